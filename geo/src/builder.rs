@@ -1,192 +1,984 @@
 use std::{
+	collections::BTreeMap,
 	fs::{File, OpenOptions},
 	io::{Seek, SeekFrom, Write},
 	path::Path,
-	sync::RwLock,
+	sync::{Mutex, RwLock},
 };
 
 use hcomp::{encode::encode, Heightmap};
-use libwebp_sys::{
-	WebPEncode,
-	WebPImageHint::WEBP_HINT_GRAPH,
-	WebPInitConfig,
-	WebPPicture,
-	WebPPictureImportRGBA,
-	WebPPictureInit,
+
+use crate::{
+	map_index_to_lat_lon,
+	map_lat_lon_to_index,
+	mapping::{self, height_bounds},
+	webp,
+	Codec,
+	Dataset,
+	Layers,
+	LoadError,
+	Provenance,
+	TileMetadata,
+	FORMAT_VERSION,
+	MAX_HEIGHT,
 };
 
-use crate::{map_lat_lon_to_index, Dataset, TileMetadata, FORMAT_VERSION};
+/// The optional layers of a tile. Every layer in the metadata of the dataset must be given.
+#[derive(Default)]
+pub struct LayerData {
+	/// See `Layers::SLOPE`.
+	pub slope: Option<Vec<u8>>,
+	/// See `Layers::ASPECT`.
+	pub aspect: Option<Vec<u8>>,
+	/// See `Layers::COVERAGE`.
+	pub coverage: Option<Vec<u8>>,
+}
+
+/// Statistics about the heights given to the height codec, collected if enabled with
+/// `DatasetBuilder::set_collect_stats`.
+///
+/// `hcomp` does not report which of its paths it took for a tile, so the `estimated_` counts are only estimates made
+/// from the input heights, and can differ from what `hcomp` actually chose.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct EncodeStats {
+	/// The number of tiles encoded.
+	pub tiles: usize,
+	/// The sum of the number of distinct height values of every tile.
+	pub distinct_values: usize,
+	/// The number of tiles with at most 256 distinct height values, which could be paletted.
+	pub estimated_paletted: usize,
+	/// The number of tiles whose height values span less than 256. This is not necessarily the criterion `hcomp` packs
+	/// heights into single bytes by.
+	pub estimated_narrow_range: usize,
+	/// The size of the compressed heights of every tile, including their mini-tile tables.
+	pub height_bytes: u64,
+}
+
+/// The tiles counted by a builder made with `DatasetBuilder::dry_run`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DryRunEstimate {
+	/// The number of tiles that would have been written.
+	pub tiles: usize,
+	/// The number of those tiles that were encoded to measure their size.
+	pub sampled_tiles: usize,
+	/// The total size of the sampled tiles.
+	pub sampled_bytes: u64,
+}
+
+impl DryRunEstimate {
+	/// The size of the dataset, including its header, if the tiles that weren't sampled compress like the ones that
+	/// were.
+	pub fn file_size(&self) -> u64 {
+		let header = Dataset::header_size(FORMAT_VERSION) as u64;
+		if self.sampled_tiles == 0 {
+			return header;
+		}
+		header + self.sampled_bytes * self.tiles as u64 / self.sampled_tiles as u64
+	}
+}
+
+struct DryRun {
+	sample_every: usize,
+	/// The height resolutions that sampled tiles are encoded with, starting with the one of the dataset.
+	height_resolutions: Vec<u16>,
+	/// The estimate for each of `height_resolutions`.
+	estimates: Mutex<Vec<DryRunEstimate>>,
+}
 
 struct Locked {
 	tile_map: Vec<u64>,
-	file: File,
+	/// The minimum and maximum height of every tile, written after the tile map.
+	bounds: Vec<(i16, i16)>,
+	/// The size and CRC-32 of every tile, written after the bounds.
+	checksums: Vec<(u32, u32)>,
+	/// `None` for a dry run.
+	file: Option<File>,
+	/// The tiles added since the last `write_ordered`, and their bounds, by index, if writes are ordered.
+	ordered: Option<BTreeMap<usize, (Vec<u8>, (i16, i16))>>,
+	/// If the tile map, bounds, or checksums have changed since they were last written.
+	dirty: bool,
 }
 
 pub struct DatasetBuilder {
 	metadata: TileMetadata,
+	provenance: Provenance,
 	locked: RwLock<Locked>,
+	stats: Option<Mutex<EncodeStats>>,
+	codec: Codec,
+	dry_run: Option<DryRun>,
+}
+
+impl Locked {
+	fn file(&mut self) -> &mut File { self.file.as_mut().expect("A dry run has no file to write to") }
 }
 
 impl DatasetBuilder {
+	/// Resume building an existing dataset. If the last tile in the file is incomplete, for example because the
+	/// previous run was killed while writing it, the file is rolled back to before that tile. Only datasets of the
+	/// current format version can be resumed.
 	pub fn from_dataset(path: &Path, dataset: Dataset) -> Result<Self, std::io::Error> {
 		let metadata = dataset.metadata;
-		let tile_map = dataset.tile_map;
-		drop(dataset.data);
+		let provenance = dataset.provenance;
+		if metadata.version != FORMAT_VERSION {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!(
+					"Can only resume datasets with version {}, not {}",
+					FORMAT_VERSION, metadata.version
+				),
+			));
+		}
+		let mut tile_map = dataset.tile_map.clone();
+		let bounds = dataset.bounds.clone().unwrap_or_else(|| vec![(0, 0); 360 * 180]);
+		let checksums = dataset.checksums.clone().unwrap_or_else(|| vec![(0, 0); 360 * 180]);
+
+		let mut truncate_to = None;
+		while let Some((index, offset)) = tile_map
+			.iter()
+			.copied()
+			.enumerate()
+			.filter(|&(_, x)| x != 0)
+			.max_by_key(|&(_, x)| x)
+		{
+			let (lat, lon) = map_index_to_lat_lon(index);
+			if offset < dataset.file_size() && Self::tile_is_intact(&dataset, lat, lon) {
+				break;
+			}
+
+			tile_map[index] = 0;
+			truncate_to = Some(offset);
+		}
+		drop(dataset);
+
+		let file = OpenOptions::new().write(true).read(true).open(path)?;
+		if let Some(len) = truncate_to {
+			file.set_len(len)?;
+		}
+
+		let builder = Self {
+			metadata,
+			provenance,
+			locked: RwLock::new(Locked {
+				tile_map,
+				bounds,
+				checksums,
+				file: Some(file),
+				ordered: None,
+				dirty: false,
+			}),
+			stats: None,
+			codec: Codec::Hcomp,
+			dry_run: None,
+		};
+		if truncate_to.is_some() {
+			builder.flush()?;
+		}
+
+		Ok(builder)
+	}
+
+	/// Whether a tile was written completely. With checksums, the frames are checked against them. Without, the
+	/// heights, water and hillshade are decoded, and the frames of the layers after them are walked, so that a tile cut
+	/// off anywhere in its frames is caught.
+	fn tile_is_intact(dataset: &Dataset, lat: i16, lon: i16) -> bool {
+		if dataset.has_checksums() {
+			matches!(dataset.verify_tile(lat, lon), Some(Ok(())))
+		} else {
+			matches!(dataset.get_full_tile(lat, lon), Some(Ok(_)))
+				&& matches!(dataset.tile_frames(lat, lon), Some(Ok(_)))
+		}
+	}
+
+	/// Open a finished dataset to add more tiles to, or replace some of its tiles. Like `from_dataset`, an incomplete
+	/// last tile is rolled back. Only datasets of the current format version can be added to.
+	pub fn open_append(path: &Path) -> Result<Self, LoadError> {
+		let dataset = Dataset::load(path)?;
+		if dataset.metadata.version != FORMAT_VERSION {
+			return Err(LoadError::UnsupportedFormatVersion);
+		}
+
+		Ok(Self::from_dataset(path, dataset)?)
+	}
+
+	pub fn new(path: &Path, metadata: TileMetadata) -> Result<Self, std::io::Error> {
+		Self::check_metadata(metadata);
+
+		let tile_map = vec![0; 360 * 180];
+		let bounds = vec![(0, 0); 360 * 180];
+		let checksums = vec![(0, 0); 360 * 180];
+
+		let mut file = File::create(path)?;
+		Self::write_to_file(
+			&mut file,
+			metadata,
+			Provenance::default(),
+			&tile_map,
+			&bounds,
+			&checksums,
+		)?;
 
 		Ok(Self {
 			metadata,
+			provenance: Provenance::default(),
 			locked: RwLock::new(Locked {
 				tile_map,
-				file: OpenOptions::new().write(true).read(true).open(path)?,
+				bounds,
+				checksums,
+				file: Some(file),
+				ordered: None,
+				dirty: false,
 			}),
+			stats: None,
+			codec: Codec::Hcomp,
+			dry_run: None,
 		})
 	}
 
-	pub fn new(path: &Path, metadata: TileMetadata) -> Result<Self, std::io::Error> {
+	/// Make a builder that writes nothing, and only counts the tiles added to it, to estimate how large a dataset
+	/// would be before building it. Only every `sample_every`th tile is encoded, to measure its size, so tiles that
+	/// would fail to encode may go unnoticed. `replace_tile` counts tiles like `add_tile`.
+	pub fn dry_run(metadata: TileMetadata, sample_every: usize) -> Self {
+		Self::check_metadata(metadata);
+
+		Self {
+			metadata,
+			provenance: Provenance::default(),
+			locked: RwLock::new(Locked {
+				tile_map: vec![0; 360 * 180],
+				bounds: vec![(0, 0); 360 * 180],
+				checksums: vec![(0, 0); 360 * 180],
+				file: None,
+				ordered: None,
+				dirty: false,
+			}),
+			stats: None,
+			codec: Codec::Hcomp,
+			dry_run: Some(DryRun {
+				sample_every: sample_every.max(1),
+				height_resolutions: vec![metadata.height_resolution],
+				estimates: Mutex::new(vec![DryRunEstimate::default()]),
+			}),
+		}
+	}
+
+	/// The tiles counted so far, if this is a dry run.
+	pub fn dry_run_estimate(&self) -> Option<DryRunEstimate> {
+		self.dry_run.as_ref().map(|x| x.estimates.lock().unwrap()[0])
+	}
+
+	/// Also estimate the size of the dataset with each of `height_resolutions`, so that one dry run can compare them.
+	/// The heights of every sampled tile are encoded again with each of them. Only the heights are, since the other
+	/// frames don't depend on the height resolution. Does nothing if this is not a dry run.
+	pub fn set_dry_run_height_resolutions(&mut self, height_resolutions: &[u16]) {
+		if let Some(dry_run) = &mut self.dry_run {
+			assert!(
+				height_resolutions.iter().all(|&x| x != 0),
+				"Height resolution must be at least 1"
+			);
+			dry_run.height_resolutions.truncate(1);
+			dry_run.height_resolutions.extend_from_slice(height_resolutions);
+			*dry_run.estimates.get_mut().unwrap() = vec![DryRunEstimate::default(); dry_run.height_resolutions.len()];
+		}
+	}
+
+	/// The tiles counted so far for the height resolution of the dataset, followed by those for each height
+	/// resolution given to `set_dry_run_height_resolutions`. Empty if this is not a dry run.
+	pub fn dry_run_estimates(&self) -> Vec<(u16, DryRunEstimate)> {
+		self.dry_run.as_ref().map_or(Vec::new(), |x| {
+			x.height_resolutions
+				.iter()
+				.copied()
+				.zip(x.estimates.lock().unwrap().iter().copied())
+				.collect()
+		})
+	}
+
+	pub fn metadata(&self) -> TileMetadata { self.metadata }
+
+	fn check_metadata(metadata: TileMetadata) {
 		assert_eq!(
 			metadata.version, FORMAT_VERSION,
 			"Can only build datasets with version {}",
 			FORMAT_VERSION
 		);
+		assert!(
+			metadata.mini_tile_size == 0 || metadata.resolution % metadata.mini_tile_size == 0,
+			"Mini-tile size must divide the resolution"
+		);
+		assert_ne!(metadata.height_resolution, 0, "Height resolution must be at least 1");
+	}
 
-		let tile_map = vec![0; 360 * 180];
+	/// Hold added tiles in memory until `write_ordered` is called, which writes them in row-major order. If tiles are
+	/// added from multiple threads, this makes the layout of the file independent of which tile finishes first, so
+	/// that the same input always produces the same file. Must be called before any tiles are added.
+	/// `replace_tile` always writes immediately.
+	pub fn set_ordered(&mut self, ordered: bool) {
+		self.locked.get_mut().unwrap().ordered = ordered.then(BTreeMap::new);
+	}
 
-		let mut file = File::create(path)?;
-		Self::write_to_file(&mut file, metadata, &tile_map)?;
+	/// Record where the heights came from in the header, which is written on the next flush. Resumed datasets keep the
+	/// provenance they were built with until this is called.
+	pub fn set_provenance(&mut self, provenance: Provenance) {
+		self.provenance = provenance;
+		self.locked.get_mut().unwrap().dirty = true;
+	}
 
-		Ok(Self {
-			metadata,
-			locked: RwLock::new(Locked { tile_map, file }),
-		})
+	/// Compress the heights of every tile encoded from now on with `codec`. Defaults to `Codec::Hcomp`.
+	pub fn set_codec(&mut self, codec: Codec) { self.codec = codec; }
+
+	/// Collect `EncodeStats` for every tile encoded from now on.
+	pub fn set_collect_stats(&mut self, collect: bool) {
+		self.stats = collect.then(|| Mutex::new(EncodeStats::default()));
+	}
+
+	/// The statistics of the tiles encoded so far, if they are being collected.
+	pub fn encode_stats(&self) -> Option<EncodeStats> { self.stats.as_ref().map(|x| *x.lock().unwrap()) }
+
+	/// Write the tiles held back since the last call, in row-major order. Does nothing if writes are not ordered.
+	pub fn write_ordered(&self) -> Result<(), std::io::Error> {
+		zone!("Write ordered");
+		let mut locked = self.locked.write().unwrap();
+		let Locked {
+			tile_map,
+			bounds,
+			checksums,
+			file,
+			ordered,
+			dirty,
+		} = &mut *locked;
+		if let Some(pending) = ordered {
+			for (index, (tile, tile_bounds)) in std::mem::take(pending) {
+				let file = file.as_mut().expect("A dry run has no file to write to");
+				tile_map[index] = file.seek(SeekFrom::End(0))?;
+				bounds[index] = tile_bounds;
+				checksums[index] = Dataset::checksum(&tile);
+				*dirty = true;
+				file.write_all(&tile)?;
+			}
+		}
+
+		Ok(())
 	}
 
 	pub fn tile_exists(&self, lat: i16, lon: i16) -> bool {
 		let index = map_lat_lon_to_index(lat, lon);
-		self.locked.read().unwrap().tile_map[index] != 0
+		let locked = self.locked.read().unwrap();
+		locked.tile_map[index] != 0 || locked.ordered.as_ref().map_or(false, |x| x.contains_key(&index))
+	}
+
+	/// The number of tiles in the dataset, including those from before it was resumed and those not written yet.
+	pub fn tile_count(&self) -> usize {
+		let locked = self.locked.read().unwrap();
+		let pending = locked.ordered.as_ref().map_or(0, |x| x.len());
+		locked.tile_map.iter().filter(|&&x| x != 0).count() + pending
 	}
 
-	/// data: `height + 500`s in meters.
+	/// data: `height + 500`s in meters, at most `MAX_HEIGHT`.
 	pub fn add_tile(
 		&self, lat: i16, lon: i16, data: Vec<u16>, water: Vec<u8>, hillshade: Vec<u8>,
 	) -> Result<(), std::io::Error> {
+		self.add_tile_with_layers(lat, lon, data, water, hillshade, LayerData::default())
+	}
+
+	/// Like `add_tile`, but also stores the optional layers of the dataset.
+	pub fn add_tile_with_layers(
+		&self, lat: i16, lon: i16, data: Vec<u16>, water: Vec<u8>, hillshade: Vec<u8>, layers: LayerData,
+	) -> Result<(), std::io::Error> {
+		if let Some(dry_run) = &self.dry_run {
+			let sample = {
+				let mut estimates = dry_run.estimates.lock().unwrap();
+				for estimate in estimates.iter_mut() {
+					estimate.tiles += 1;
+				}
+				(estimates[0].tiles - 1) % dry_run.sample_every == 0
+			};
+			if sample {
+				let frames = self.encode_frames(water, hillshade, layers)?;
+				let sizes = dry_run
+					.height_resolutions
+					.iter()
+					.enumerate()
+					.map(|(i, &height_resolution)| {
+						let (heights, _) = self.encode_heights(&data, height_resolution, i == 0)?;
+						Ok((heights.len() + frames.len()) as u64)
+					})
+					.collect::<Result<Vec<_>, std::io::Error>>()?;
+
+				let mut estimates = dry_run.estimates.lock().unwrap();
+				for (estimate, size) in estimates.iter_mut().zip(sizes) {
+					estimate.sampled_tiles += 1;
+					estimate.sampled_bytes += size;
+				}
+			}
+			return Ok(());
+		}
+
+		let (tile, bounds) = self.encode_tile(data, water, hillshade, layers)?;
+		self.add_raw_tile(lat, lon, &tile, bounds)
+	}
+
+	/// Append a tile that is already compressed, such as the frames and bounds of `Dataset::tile_frames`. The frames
+	/// aren't checked, so they must be in the layout of the current format version, and have been encoded with the
+	/// same resolution, height resolution, and layers as this dataset. `bounds` are the minimum and maximum height of
+	/// the tile in meters.
+	pub fn add_raw_tile(
+		&self, lat: i16, lon: i16, compressed_frames: &[u8], bounds: (i16, i16),
+	) -> Result<(), std::io::Error> {
+		zone!("Write");
+		if let Some(dry_run) = &self.dry_run {
+			// The size of a compressed tile is already known, so it is always sampled. It can't be encoded again with
+			// another height resolution, so it counts the same for every one.
+			for estimate in dry_run.estimates.lock().unwrap().iter_mut() {
+				estimate.tiles += 1;
+				estimate.sampled_tiles += 1;
+				estimate.sampled_bytes += compressed_frames.len() as u64;
+			}
+			return Ok(());
+		}
+
+		let index = map_lat_lon_to_index(lat, lon);
+		let mut locked = self.locked.write().unwrap();
+		if let Some(pending) = &mut locked.ordered {
+			pending.insert(index, (compressed_frames.to_vec(), bounds));
+			return Ok(());
+		}
+
+		let offset = locked.file().seek(SeekFrom::End(0))?;
+		locked.tile_map[index] = offset;
+		locked.bounds[index] = bounds;
+		locked.checksums[index] = Dataset::checksum(compressed_frames);
+		locked.dirty = true;
+		locked.file().write_all(compressed_frames)?;
+
+		Ok(())
+	}
+
+	/// Like `add_tile`, but takes signed heights in meters. Heights below -500m, the water sentinel of older formats,
+	/// are clamped to it. Water is only ever signified by the water mask.
+	pub fn add_tile_meters(
+		&self, lat: i16, lon: i16, heights: Vec<i16>, water: Vec<u8>, hillshade: Vec<u8>,
+	) -> Result<(), std::io::Error> {
+		self.add_tile_meters_with_layers(lat, lon, heights, water, hillshade, LayerData::default())
+	}
+
+	/// Like `add_tile_meters`, but also stores the optional layers of the dataset.
+	pub fn add_tile_meters_with_layers(
+		&self, lat: i16, lon: i16, heights: Vec<i16>, water: Vec<u8>, hillshade: Vec<u8>, layers: LayerData,
+	) -> Result<(), std::io::Error> {
+		let data = heights.into_iter().map(mapping::offset_height).collect();
+		self.add_tile_with_layers(lat, lon, data, water, hillshade, layers)
+	}
+
+	/// Like `add_tile`, but reuses the space of the tile already present at the position if the new tile fits in it.
+	/// Otherwise, the new tile is appended and the old one is left behind as dead space. The new tile is always written
+	/// immediately, and replaces a tile at the same position that `set_ordered` is still holding back.
+	pub fn replace_tile(
+		&self, lat: i16, lon: i16, data: Vec<u16>, water: Vec<u8>, hillshade: Vec<u8>, layers: LayerData,
+	) -> Result<(), std::io::Error> {
+		if self.dry_run.is_some() {
+			return self.add_tile_with_layers(lat, lon, data, water, hillshade, layers);
+		}
+
+		let (tile, bounds) = self.encode_tile(data, water, hillshade, layers)?;
+
+		zone!("Write");
+		let index = map_lat_lon_to_index(lat, lon);
+		let mut locked = self.locked.write().unwrap();
+		// Otherwise `write_ordered` would write the held back tile over this one later.
+		if let Some(pending) = &mut locked.ordered {
+			pending.remove(&index);
+		}
+		locked.bounds[index] = bounds;
+		locked.checksums[index] = Dataset::checksum(&tile);
+		locked.dirty = true;
+		let old = locked.tile_map[index];
+		if old != 0 {
+			let next = locked.tile_map.iter().copied().filter(|&x| x > old).min();
+			let fits = match next {
+				Some(next) => tile.len() as u64 <= next - old,
+				// The last tile in the file can always be rewritten in place.
+				None => true,
+			};
+
+			if fits {
+				locked.file().seek(SeekFrom::Start(old))?;
+				locked.file().write_all(&tile)?;
+				if next.is_none() {
+					locked.file().set_len(old + tile.len() as u64)?;
+				}
+				return Ok(());
+			}
+		}
+
+		let offset = locked.file().seek(SeekFrom::End(0))?;
+		locked.tile_map[index] = offset;
+		locked.file().write_all(&tile)?;
+
+		Ok(())
+	}
+
+	fn encode_tile(
+		&self, data: Vec<u16>, water: Vec<u8>, hillshade: Vec<u8>, layers: LayerData,
+	) -> Result<(Vec<u8>, (i16, i16)), std::io::Error> {
+		let frames = self.encode_frames(water, hillshade, layers)?;
+		let (mut out, bounds) = self.encode_heights(&data, self.metadata.height_resolution, true)?;
+		out.extend_from_slice(&frames);
+		Ok((out, bounds))
+	}
+
+	/// Compress the frames of a tile that follow its heights.
+	fn encode_frames(&self, water: Vec<u8>, hillshade: Vec<u8>, layers: LayerData) -> Result<Vec<u8>, std::io::Error> {
+		let mut layer_frames = Vec::new();
+		for (layer, data) in [
+			(Layers::SLOPE, layers.slope),
+			(Layers::ASPECT, layers.aspect),
+			(Layers::COVERAGE, layers.coverage),
+		] {
+			if !self.metadata.layers.contains(layer) {
+				continue;
+			}
+
+			zone!("Compress layer");
+			let data = data.ok_or_else(|| {
+				std::io::Error::new(std::io::ErrorKind::InvalidInput, "Missing a layer of the dataset")
+			})?;
+			layer_frames.push(Self::compress_u8_webp(
+				&data,
+				self.metadata.resolution as _,
+				self.metadata.resolution as _,
+			)?);
+		}
+
 		let water = {
-			tracy::zone!("Compress water");
+			zone!("Compress water");
 			Self::compress_u8_webp(&water, self.metadata.resolution as _, self.metadata.resolution as _)?
 		};
 
 		let hillshade = {
-			tracy::zone!("Compress hillshade");
+			zone!("Compress hillshade");
 			Self::compress_u8_webp(&hillshade, self.metadata.resolution as _, self.metadata.resolution as _)?
 		};
 
+		let mut out = water;
+		out.extend_from_slice(&hillshade);
+		for frame in layer_frames {
+			out.extend_from_slice(&frame);
+		}
+
+		Ok(out)
+	}
+
+	/// Compress the heights of a tile with `height_resolution`, along with their codec, and find their bounds. Only
+	/// counts the tile towards the encode stats if `collect_stats` is set.
+	fn encode_heights(
+		&self, data: &[u16], height_resolution: u16, collect_stats: bool,
+	) -> Result<(Vec<u8>, (i16, i16)), std::io::Error> {
 		let data: Vec<_> = {
-			tracy::zone!("Map height");
-			data.into_iter()
-				.map(|x| {
-					let positive = x as f32;
-					let mapped = positive / self.metadata.height_resolution as f32;
-					mapped.round() as u16
-				})
-				.collect()
+			zone!("Map height");
+			data.iter().map(|&x| mapping::quantize(x, height_resolution)).collect()
 		};
+		if data.iter().any(|&x| mapping::is_out_of_range(x, height_resolution)) {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				format!(
+					"Height out of range, must be at most {}m",
+					MAX_HEIGHT as i32 - mapping::HEIGHT_OFFSET
+				),
+			));
+		}
 
-		let data = {
-			tracy::zone!("Compress height");
-			let mut out = Vec::new();
+		let bounds = height_bounds(&data, height_resolution);
 
+		// The number of distinct values and the range they span, measured before the heights are moved into the codec.
+		let input_stats = self.stats.as_ref().filter(|_| collect_stats).map(|_| {
+			zone!("Collect stats");
+			let mut seen = vec![0u64; 65536 / 64];
+			for &x in data.iter() {
+				seen[x as usize / 64] |= 1 << (x % 64);
+			}
+			let distinct: usize = seen.iter().map(|x| x.count_ones() as usize).sum();
+			let min = data.iter().copied().min().unwrap_or(0);
+			let max = data.iter().copied().max().unwrap_or(0);
+			(distinct, max - min)
+		});
+
+		zone!("Compress height");
+		let mut out = vec![self.codec as u8];
+
+		let res = self.metadata.resolution as usize;
+		let mini = self.metadata.mini_tile_size as usize;
+		if self.codec == Codec::Zstd {
+			#[cfg(feature = "zstd")]
+			{
+				let bytes: Vec<_> = data.iter().flat_map(|x| x.to_le_bytes()).collect();
+				let frame = zstd::bulk::compress(&bytes, 3)?;
+				out.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+				out.extend_from_slice(&frame);
+			}
+			#[cfg(not(feature = "zstd"))]
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::Unsupported,
+				"Built without zstd support",
+			));
+		} else if mini == 0 {
 			encode(
 				Heightmap {
-					width: self.metadata.resolution as _,
-					height: self.metadata.resolution as _,
+					width: res as _,
+					height: res as _,
 					data: data.into(),
 				},
 				22,
 				&mut out,
 			)?;
+		} else {
+			// The sizes of the mini-tile frames come first, so that they can be found without decoding each other.
+			let mut frames = Vec::new();
+			for by in 0..res / mini {
+				for bx in 0..res / mini {
+					let block: Vec<_> = (0..mini)
+						.flat_map(|y| {
+							let start = (by * mini + y) * res + bx * mini;
+							data[start..start + mini].iter().copied()
+						})
+						.collect();
+
+					let start = frames.len();
+					encode(
+						Heightmap {
+							width: mini as _,
+							height: mini as _,
+							data: block.into(),
+						},
+						22,
+						&mut frames,
+					)?;
+					out.extend_from_slice(&((frames.len() - start) as u32).to_le_bytes());
+				}
+			}
+			out.extend_from_slice(&frames);
+		}
 
-			out
-		};
-
-		tracy::zone!("Write");
-		let index = map_lat_lon_to_index(lat, lon);
-		let mut locked = self.locked.write().unwrap();
-		let offset = locked.file.seek(SeekFrom::End(0))?;
-		locked.tile_map[index] = offset;
-		locked.file.write_all(&data)?;
-		locked.file.write_all(&water)?;
-		locked.file.write_all(&hillshade)?;
+		if let (Some(stats), Some((distinct, range))) = (&self.stats, input_stats) {
+			let mut stats = stats.lock().unwrap();
+			stats.tiles += 1;
+			stats.distinct_values += distinct;
+			stats.estimated_paletted += (distinct <= 256) as usize;
+			stats.estimated_narrow_range += (range < 256) as usize;
+			stats.height_bytes += out.len() as u64;
+		}
 
-		Ok(())
+		Ok((out, bounds))
 	}
 
 	pub fn flush(&self) -> Result<(), std::io::Error> {
-		tracy::zone!("Flush");
+		zone!("Flush");
+		if self.dry_run.is_some() {
+			return Ok(());
+		}
 
 		let mut locked = self.locked.write().unwrap();
-
-		locked.file.seek(SeekFrom::Start(32))?;
-		let slice = unsafe { std::slice::from_raw_parts(locked.tile_map.as_ptr() as _, locked.tile_map.len() * 8) };
-		locked.file.write_all(slice)?;
-
-		locked.file.flush()?;
+		let Locked {
+			tile_map,
+			bounds,
+			checksums,
+			file,
+			..
+		} = &mut *locked;
+		let file = file.as_mut().expect("A dry run has no file to write to");
+
+		file.seek(SeekFrom::Start(14))?;
+		file.write_all(&self.provenance.to_header())?;
+		let slice = unsafe { std::slice::from_raw_parts(tile_map.as_ptr() as _, tile_map.len() * 8) };
+		file.write_all(slice)?;
+		// The bounds and checksum tables directly follow the tile map.
+		file.write_all(&Self::bounds_bytes(bounds))?;
+		file.write_all(&Self::checksums_bytes(checksums))?;
+
+		file.flush()?;
+		locked.dirty = false;
 
 		Ok(())
 	}
 
-	pub fn finish(self) -> Result<(), std::io::Error> { self.flush() }
+	/// Write any tiles held back by `set_ordered`, and save the tile map. Dropping the builder does the same, but only
+	/// logs any error with `tracing`, so this must be called to find out if the dataset was saved.
+	pub fn finish(self) -> Result<(), std::io::Error> {
+		self.write_ordered()?;
+		self.flush()
+	}
 
-	fn write_to_file(file: &mut File, metadata: TileMetadata, tile_map: &[u64]) -> Result<(), std::io::Error> {
+	fn write_to_file(
+		file: &mut File, metadata: TileMetadata, provenance: Provenance, tile_map: &[u64], bounds: &[(i16, i16)],
+		checksums: &[(u32, u32)],
+	) -> Result<(), std::io::Error> {
 		let mut header = [0; 32];
 		header[0..5].copy_from_slice(&Dataset::MAGIC);
 		header[5..7].copy_from_slice(&metadata.version.to_le_bytes());
 		header[7..9].copy_from_slice(&metadata.resolution.to_le_bytes());
 		header[9..11].copy_from_slice(&metadata.height_resolution.to_le_bytes());
+		header[11] = metadata.layers.0;
+		header[12..14].copy_from_slice(&metadata.mini_tile_size.to_le_bytes());
+		header[14..32].copy_from_slice(&provenance.to_header());
 
 		file.write_all(&header)?;
 		file.write_all(unsafe { std::slice::from_raw_parts(tile_map.as_ptr() as _, tile_map.len() * 8) })?;
+		file.write_all(&Self::bounds_bytes(bounds))?;
+		file.write_all(&Self::checksums_bytes(checksums))?;
 
 		Ok(())
 	}
 
+	fn bounds_bytes(bounds: &[(i16, i16)]) -> Vec<u8> {
+		bounds
+			.iter()
+			.flat_map(|&(min, max)| min.to_le_bytes().into_iter().chain(max.to_le_bytes()))
+			.collect()
+	}
+
+	fn checksums_bytes(checksums: &[(u32, u32)]) -> Vec<u8> {
+		checksums
+			.iter()
+			.flat_map(|&(len, crc)| len.to_le_bytes().into_iter().chain(crc.to_le_bytes()))
+			.collect()
+	}
+
 	fn compress_u8_webp(data: &[u8], width: u32, height: u32) -> Result<Vec<u8>, std::io::Error> {
-		unsafe {
-			let mut temp = Vec::new();
-
-			let mut config = std::mem::zeroed();
-			WebPInitConfig(&mut config);
-			config.lossless = 1;
-			config.quality = 100.0;
-			config.method = 3;
-			config.image_hint = WEBP_HINT_GRAPH;
-			config.exact = 1;
-
-			let mut picture = std::mem::zeroed();
-			WebPPictureInit(&mut picture);
-			picture.use_argb = 1;
-			picture.writer = Some(write);
-			picture.custom_ptr = &mut temp as *mut _ as _;
-			picture.width = width as i32 / 2;
-			picture.height = height as i32 / 2;
-
-			WebPPictureImportRGBA(&mut picture, data.as_ptr() as _, width as i32 * 2);
-
-			WebPEncode(&config, &mut picture);
-
-			if picture.error_code as i32 != 0 {
-				return Err(std::io::Error::new(
-					std::io::ErrorKind::Other,
-					format!("WebPEncode failed: {}", picture.error_code as i32),
-				));
-			}
+		webp::encode(data, width, height)
+	}
+}
 
-			unsafe extern "C" fn write(data: *const u8, data_size: usize, picture: *const WebPPicture) -> i32 {
-				let vec = &mut *((*picture).custom_ptr as *mut Vec<u8>);
-				vec.extend_from_slice(std::slice::from_raw_parts(data, data_size));
+impl Drop for DatasetBuilder {
+	/// Save tiles added after the last flush, so that a builder dropped without `finish`, such as on an early return,
+	/// does not leave the file with a stale tile map.
+	fn drop(&mut self) {
+		// A poisoned lock means a write panicked, so the file can't be trusted to be consistent anyway.
+		let locked = match self.locked.get_mut() {
+			Ok(x) => x,
+			Err(_) => return,
+		};
+		if !locked.dirty && locked.ordered.as_ref().map_or(true, |x| x.is_empty()) {
+			return;
+		}
 
-				1
-			}
+		// There is no one to return the error to, so it is only logged. `finish` returns it instead.
+		if let Err(e) = self.write_ordered().and_then(|_| self.flush()) {
+			tracing::warn!(error = ?e, "Error saving dataset when dropping its builder");
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const RES: usize = 16;
+
+	fn temp_path(name: &str) -> std::path::PathBuf {
+		std::env::temp_dir().join(format!("geo-test-{}-{}.geo", name, std::process::id()))
+	}
+
+	fn metadata() -> TileMetadata {
+		TileMetadata {
+			version: FORMAT_VERSION,
+			resolution: RES as _,
+			height_resolution: 1,
+			layers: Layers::NONE,
+			mini_tile_size: 0,
+		}
+	}
 
-			Ok(temp)
+	/// Heights that compress to a single value.
+	fn flat(height: u16) -> Vec<u16> { vec![height; RES * RES] }
+
+	/// Heights that don't compress well, so they take more space than `flat`.
+	fn rough() -> Vec<u16> { (0..RES * RES).map(|i| (i * 7919 % 1000) as u16 + 500).collect() }
+
+	fn add(builder: &DatasetBuilder, lon: i16, heights: Vec<u16>) {
+		builder
+			.add_tile(0, lon, heights, vec![0; RES * RES], vec![0; RES * RES])
+			.unwrap();
+	}
+
+	fn replace(builder: &DatasetBuilder, lon: i16, heights: Vec<u16>) {
+		builder
+			.replace_tile(
+				0,
+				lon,
+				heights,
+				vec![0; RES * RES],
+				vec![0; RES * RES],
+				LayerData::default(),
+			)
+			.unwrap();
+	}
+
+	/// Finish the dataset at `path`, and read it back along with the size of the file.
+	fn finish(builder: DatasetBuilder, path: &Path) -> (Dataset, u64) {
+		builder.finish().unwrap();
+		let bytes = std::fs::read(path).unwrap();
+		std::fs::remove_file(path).unwrap();
+		let len = bytes.len() as u64;
+		(Dataset::from_bytes(bytes.into()).unwrap(), len)
+	}
+
+	fn heights(dataset: &Dataset, lon: i16) -> Vec<u16> { dataset.get_heights(0, lon).unwrap().unwrap() }
+
+	#[test]
+	fn a_smaller_replacement_is_written_in_place() {
+		let path = temp_path("replace-in-place");
+		let builder = DatasetBuilder::new(&path, metadata()).unwrap();
+		add(&builder, 0, rough());
+		add(&builder, 1, flat(1000));
+		builder.flush().unwrap();
+		let (first, size) = (
+			builder.locked.read().unwrap().tile_map[map_lat_lon_to_index(0, 0)],
+			file_len(&path),
+		);
+
+		replace(&builder, 0, flat(2000));
+		let (dataset, len) = finish(builder, &path);
+		assert_eq!(dataset.tile_offset(0, 0), Some(first));
+		assert_eq!(len, size);
+		assert_eq!(heights(&dataset, 0), flat(2000));
+		assert_eq!(heights(&dataset, 1), flat(1000));
+	}
+
+	#[test]
+	fn a_larger_replacement_is_appended() {
+		let path = temp_path("replace-append");
+		let builder = DatasetBuilder::new(&path, metadata()).unwrap();
+		add(&builder, 0, flat(1000));
+		add(&builder, 1, flat(1000));
+		builder.flush().unwrap();
+		let size = file_len(&path);
+
+		replace(&builder, 0, rough());
+		let (dataset, len) = finish(builder, &path);
+		assert!(dataset.tile_offset(0, 0) > dataset.tile_offset(0, 1));
+		assert!(len > size);
+		assert_eq!(heights(&dataset, 0), rough());
+		assert_eq!(heights(&dataset, 1), flat(1000));
+	}
+
+	#[test]
+	fn the_last_tile_is_replaced_in_place_and_the_file_shrinks() {
+		let path = temp_path("replace-last");
+		let builder = DatasetBuilder::new(&path, metadata()).unwrap();
+		add(&builder, 0, flat(1000));
+		add(&builder, 1, rough());
+		builder.flush().unwrap();
+		let (last, size) = (
+			builder.locked.read().unwrap().tile_map[map_lat_lon_to_index(0, 1)],
+			file_len(&path),
+		);
+
+		replace(&builder, 1, flat(2000));
+		let (dataset, len) = finish(builder, &path);
+		assert_eq!(dataset.tile_offset(0, 1), Some(last));
+		assert!(len < size);
+		assert_eq!(heights(&dataset, 0), flat(1000));
+		assert_eq!(heights(&dataset, 1), flat(2000));
+	}
+
+	#[test]
+	fn a_replacement_is_not_reverted_by_ordered_writes() {
+		let path = temp_path("replace-ordered");
+		let mut builder = DatasetBuilder::new(&path, metadata()).unwrap();
+		builder.set_ordered(true);
+		add(&builder, 0, flat(1000));
+
+		replace(&builder, 0, flat(2000));
+		assert_eq!(builder.tile_count(), 1);
+		let (dataset, _) = finish(builder, &path);
+		assert_eq!(heights(&dataset, 0), flat(2000));
+	}
+
+	#[test]
+	fn tile_sizes_leave_out_the_space_freed_by_a_smaller_replacement() {
+		let path = temp_path("replace-sizes");
+		let builder = DatasetBuilder::new(&path, metadata()).unwrap();
+		add(&builder, 0, rough());
+		add(&builder, 1, flat(1000));
+		replace(&builder, 0, flat(2000));
+		let (dataset, _) = finish(builder, &path);
+
+		let sizes = dataset.tile_sizes();
+		assert_eq!(sizes.len(), 2);
+		for (lat, lon, size) in sizes {
+			let tile = dataset.tile_frames(lat, lon).unwrap().unwrap();
+			assert_eq!(size, tile.frames().len() as u64);
+		}
+	}
+
+	#[test]
+	fn a_dry_run_estimates_every_height_resolution_from_the_same_tiles() {
+		let mut builder = DatasetBuilder::dry_run(metadata(), 2);
+		builder.set_dry_run_height_resolutions(&[4, 16]);
+		for lon in 0..3 {
+			add(&builder, lon, rough());
+		}
+
+		let estimates = builder.dry_run_estimates();
+		assert_eq!(estimates.iter().map(|x| x.0).collect::<Vec<_>>(), [1, 4, 16]);
+		for (_, estimate) in estimates.iter() {
+			assert_eq!((estimate.tiles, estimate.sampled_tiles), (3, 2));
+		}
+		assert_eq!(
+			builder.dry_run_estimate().unwrap().sampled_bytes,
+			estimates[0].1.sampled_bytes
+		);
+		assert!(estimates[1].1.sampled_bytes < estimates[0].1.sampled_bytes);
+		assert!(estimates[2].1.sampled_bytes < estimates[1].1.sampled_bytes);
+	}
+
+	fn file_len(path: &Path) -> u64 { std::fs::metadata(path).unwrap().len() }
+
+	#[test]
+	#[should_panic(expected = "Height resolution must be at least 1")]
+	fn height_resolution_of_0_is_rejected() {
+		let metadata = TileMetadata {
+			version: FORMAT_VERSION,
+			resolution: 16,
+			height_resolution: 0,
+			layers: Layers::NONE,
+			mini_tile_size: 0,
+		};
+		// The metadata is checked before the file is created, so nothing is left behind.
+		let _ = DatasetBuilder::new(&std::env::temp_dir().join("geo-test-hres-0.geo"), metadata);
+	}
+
+	#[test]
+	fn a_tile_cut_off_in_its_layers_is_rolled_back() {
+		let path = std::env::temp_dir().join(format!("geo-test-append-{}.geo", std::process::id()));
+		let metadata = TileMetadata {
+			version: FORMAT_VERSION,
+			resolution: 16,
+			height_resolution: 1,
+			layers: Layers::SLOPE,
+			mini_tile_size: 0,
+		};
+		let builder = DatasetBuilder::new(&path, metadata).unwrap();
+		let len = 16 * 16;
+		for lon in 0..2 {
+			let layers = LayerData {
+				slope: Some(vec![0; len]),
+				..Default::default()
+			};
+			builder
+				.add_tile_with_layers(0, lon, vec![1000; len], vec![0; len], vec![0; len], layers)
+				.unwrap();
 		}
+		builder.finish().unwrap();
+
+		// Cut the end off the slope of the last tile, which its heights, water and hillshade still decode without.
+		let file = OpenOptions::new().write(true).open(&path).unwrap();
+		file.set_len(file.metadata().unwrap().len() - 1).unwrap();
+		drop(file);
+
+		let builder = DatasetBuilder::open_append(&path).unwrap();
+		assert!(builder.tile_exists(0, 0));
+		assert!(!builder.tile_exists(0, 1));
+		drop(builder);
+		std::fs::remove_file(&path).unwrap();
 	}
 }
@@ -1,71 +1,656 @@
-use std::{fs::File, io::Read, path::Path};
+use std::{
+	borrow::Cow,
+	fs::File,
+	io::Read,
+	ops::Range,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
 
 use hcomp::decode::decode;
-use libwebp_sys::WebPDecodeRGBAInto;
+#[cfg(all(unix, feature = "mmap"))]
+use memmap2::Advice;
+#[cfg(feature = "mmap")]
 use memmap2::{Mmap, MmapOptions};
 
-use crate::{map_lat_lon_to_index, LoadError, TileMetadata, FORMAT_VERSION};
+#[cfg(feature = "http")]
+use crate::http::HttpSource;
+use crate::{
+	is_supported_version,
+	limit::Permit,
+	map_index_to_lat_lon,
+	map_lat_lon_to_index,
+	map_neighbour,
+	mapping::{self, height_bounds},
+	normalize_lon,
+	webp,
+	Codec,
+	Layers,
+	LoadError,
+	Provenance,
+	TileMetadata,
+};
 
+/// The expected access pattern of a dataset.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+	/// No particular pattern.
+	Normal,
+	/// Tiles are read in file order, such as when scanning the whole dataset.
+	Sequential,
+	/// Tiles are read in no particular order, such as when rendering.
+	Random,
+}
+
+impl Access {
+	#[cfg(feature = "mmap")]
+	fn apply(self, map: &Mmap) {
+		// The advice is only a hint, so failing to give it is not an error.
+		#[cfg(unix)]
+		let _ = map.advise(match self {
+			Access::Normal => Advice::Normal,
+			Access::Sequential => Advice::Sequential,
+			Access::Random => Advice::Random,
+		});
+		#[cfg(not(unix))]
+		let _ = map;
+	}
+}
+
+/// The data of a dataset, either after the header or including it.
+pub(crate) enum Backing {
+	/// Mapped from the end of the header.
+	#[cfg(feature = "mmap")]
+	Mmap(MappedFile),
+	/// The whole file.
+	Bytes(Arc<[u8]>),
+	#[cfg(feature = "http")]
+	Http(HttpSource),
+	/// The shards of each latitude band, read by `Dataset::tile_frame` instead of through the backing.
+	Shards(Vec<Option<Dataset>>),
+}
+
+/// A file mapped from the end of its header, along with what is needed to notice that it was truncated since.
+#[cfg(feature = "mmap")]
+pub(crate) struct MappedFile {
+	map: Mmap,
+	file: File,
+	/// The sorted offsets of all tiles, followed by the size of the file when it was mapped. A tile ends where the
+	/// next one begins.
+	ends: Vec<u64>,
+}
+
+#[cfg(feature = "mmap")]
+impl MappedFile {
+	fn new(map: Mmap, file: File, tile_map: &[u64], data_offset: usize) -> Self {
+		let mut ends: Vec<_> = tile_map.iter().copied().filter(|&x| x != 0).collect();
+		ends.sort_unstable();
+		ends.push((data_offset + map.len()) as u64);
+		Self { map, file, ends }
+	}
+
+	/// The frame of the tile at `offset`, which is `start` into the map, up to where the next tile begins.
+	///
+	/// Touching a page of the map past the end of the file raises `SIGBUS`, which can't be recovered from. So the size
+	/// of the file is checked first, and a tile that the file no longer covers is an error instead. The file can
+	/// still be truncated between the check and the read, so this only turns the common case of reading from a file
+	/// that was replaced in place into an error, and doesn't make modifying a mapped file safe.
+	fn frame(&self, offset: u64, start: usize) -> Result<&[u8], std::io::Error> {
+		let out_of_bounds = || std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Tile offset out of bounds");
+		let end = *self.ends[self.ends.partition_point(|&x| x <= offset)..]
+			.first()
+			.ok_or_else(out_of_bounds)?;
+		if self.file.metadata()?.len() < end {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::UnexpectedEof,
+				"The file was truncated since it was loaded",
+			));
+		}
+
+		self.map
+			.get(start..start + (end - offset) as usize)
+			.ok_or_else(out_of_bounds)
+	}
+}
+
+impl Backing {
+	/// The size of the whole file, given where the data after the header begins.
+	fn file_size(&self, data_offset: usize) -> u64 {
+		match self {
+			#[cfg(feature = "mmap")]
+			Self::Mmap(x) => (data_offset + x.map.len()) as u64,
+			Self::Bytes(x) => x.len() as u64,
+			#[cfg(feature = "http")]
+			Self::Http(x) => x.file_size(),
+			Self::Shards(x) => x.iter().flatten().map(|x| x.file_size()).sum(),
+		}
+	}
+
+	/// Get the frame of the tile at `offset` from the beginning of the file. Backings in memory return everything
+	/// until the end of the file, except for maps, which end at the next tile.
+	///
+	/// Offsets that point into the header or past the end of the file are reported as errors, since they can only come
+	/// from a corrupt tile map.
+	fn tile(&self, offset: u64, data_offset: usize) -> Result<Cow<'_, [u8]>, std::io::Error> {
+		let out_of_bounds = || std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Tile offset out of bounds");
+		if let Self::Shards(_) = self {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::Unsupported,
+				"The tiles of a sharded dataset must be read from their shard",
+			));
+		}
+		let start = usize::try_from(offset)
+			.ok()
+			.and_then(|x| x.checked_sub(data_offset))
+			.ok_or_else(out_of_bounds)?;
+		match self {
+			#[cfg(feature = "mmap")]
+			Self::Mmap(x) => x.frame(offset, start).map(Cow::Borrowed),
+			Self::Bytes(x) => x
+				.get(data_offset + start..)
+				.map(Cow::Borrowed)
+				.ok_or_else(out_of_bounds),
+			#[cfg(feature = "http")]
+			Self::Http(x) => x.read_tile(offset).map(Cow::Owned),
+			Self::Shards(_) => unreachable!(),
+		}
+	}
+}
+
+/// The compressed frames of a tile, as they are stored in the file. See `Dataset::tile_frames`.
+pub struct RawTile<'a> {
+	frame: Cow<'a, [u8]>,
+	/// Where the height, water, hillshade, and layer frames end.
+	ends: [usize; 4],
+	bounds: (i16, i16),
+}
+
+impl RawTile<'_> {
+	/// The codec tag and frames of the heights.
+	pub fn height(&self) -> &[u8] { &self.frame[..self.ends[0]] }
+
+	/// The codec that the heights are compressed with.
+	pub fn codec(&self) -> Codec { Codec::from_tag(self.frame[0]).expect("The codec is checked by `tile_frames`") }
+
+	/// The webp frame of the water mask.
+	pub fn water(&self) -> &[u8] { &self.frame[self.ends[0]..self.ends[1]] }
+
+	/// The webp frame of the hillshade.
+	pub fn hillshade(&self) -> &[u8] { &self.frame[self.ends[1]..self.ends[2]] }
+
+	/// The webp frames of the layers, one after the other in the order of their bits.
+	pub fn layers(&self) -> &[u8] { &self.frame[self.ends[2]..self.ends[3]] }
+
+	/// All the frames of the tile, as they are stored in the file.
+	pub fn frames(&self) -> &[u8] { &self.frame[..self.ends[3]] }
+
+	/// The minimum and maximum height of the tile in meters, for `DatasetBuilder::add_raw_tile`.
+	pub fn bounds(&self) -> (i16, i16) { self.bounds }
+}
+
+/// A rectangle of pixels in a tile, with rows and columns in the same order as `Dataset::get_tile`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct TileRect {
+	pub x: usize,
+	pub y: usize,
+	pub width: usize,
+	pub height: usize,
+}
+
+/// A dataset opened for reading.
+///
+/// Every getter takes `&self` and never mutates the dataset, so a `Dataset` is `Send` and `Sync`, and can be shared
+/// between threads (for example in an `Arc`) to read tiles concurrently. Each call decodes into its own buffers.
 pub struct Dataset {
 	pub(crate) metadata: TileMetadata,
+	pub(crate) provenance: Provenance,
 	pub(crate) tile_map: Vec<u64>,
-	pub(crate) data: Mmap,
+	/// The bounding heights of every tile, since format version 12.
+	pub(crate) bounds: Option<Vec<(i16, i16)>>,
+	/// The size and CRC-32 of the frames of every tile, since format version 13.
+	pub(crate) checksums: Option<Vec<(u32, u32)>>,
+	/// Check tiles against their checksums in `get_full_tile`.
+	pub(crate) verify_checksums: bool,
+	pub(crate) data: Backing,
+	/// Where the data after the header begins, which tile offsets are relative to.
+	pub(crate) data_offset: usize,
+	/// The path and access pattern that the dataset was loaded with, if it was loaded from the file system.
+	pub(crate) origin: Option<(PathBuf, Access)>,
 }
 
+// Keep the guarantee documented on `Dataset` from silently breaking if a backing stops being thread-safe.
+const _: fn() = || {
+	fn assert_send_sync<T: Send + Sync>() {}
+	assert_send_sync::<Dataset>();
+};
+
 impl Dataset {
+	/// The size of the bounds table of format version 12.
+	pub(crate) const BOUNDS_SIZE: usize = 360 * 180 * 4;
+	/// The size of the checksum table of format version 13.
+	pub(crate) const CHECKSUMS_SIZE: usize = 360 * 180 * 8;
+	/// The size of the header, including the tile map, but not the bounds table.
+	pub(crate) const HEADER_SIZE: usize = 32 + 360 * 180 * 8;
 	pub(crate) const MAGIC: [u8; 5] = [115, 117, 115, 115, 121];
+	/// The number of latitude bands that a sharded dataset is split into, each `180 / SHARD_COUNT` degrees tall.
+	pub const SHARD_COUNT: usize = 18;
 
-	pub fn load(dir: &Path) -> Result<Self, LoadError> {
+	/// Load a dataset by memory mapping the file. Without the `mmap` feature, the whole file is read into memory
+	/// instead.
+	///
+	/// Tiles are addressed with `usize` offsets into the map, so on 32-bit targets files larger than 4 GiB are
+	/// rejected with `LoadError::FileTooLarge`. See `check_addressable`.
+	pub fn load(dir: &Path) -> Result<Self, LoadError> { Self::load_with_advice(dir, Access::Normal) }
+
+	/// Load a dataset, hinting to the OS how the tiles are going to be accessed.
+	///
+	/// The file is opened and mapped read-only, so a `Dataset` can never write to it. Use `DatasetBuilder` to modify
+	/// a dataset.
+	///
+	/// Nothing else may modify the file while it is mapped either. The map keeps pointing at the file as it is on disk,
+	/// so tiles that were rewritten are read half old and half new, and reading past the end of a file that was
+	/// truncated kills the process with `SIGBUS` instead of returning an error. Write a new file and rename it over the
+	/// old one instead, and call `reload` afterwards to pick it up.
+	///
+	/// If `dir` is a directory, it is loaded as a sharded dataset. See `load_shards`.
+	pub fn load_with_advice(dir: &Path, access: Access) -> Result<Self, LoadError> {
 		let meta = std::fs::metadata(&dir)?;
 		if meta.is_dir() {
-			Err(LoadError::UnsupportedFormatVersion)
+			Self::load_shards(dir, access)
 		} else {
-			let mut file = File::open(dir)?;
-			let mut buffer = Vec::with_capacity(32 + 360 * 180 * 8);
-			buffer.resize(buffer.capacity(), 0);
+			check_addressable(meta.len(), usize::MAX as u64)?;
 
-			file.read_exact(&mut buffer).map_err(|_| LoadError::InvalidFileSize)?;
+			let mut file = File::open(dir)?;
 
-			if buffer[0..5] != Self::MAGIC {
+			// Check the magic on its own first, so unrelated files aren't reported as truncated datasets.
+			let mut magic = [0; 5];
+			file.read_exact(&mut magic).map_err(|_| LoadError::InvalidMagic)?;
+			if magic != Self::MAGIC {
 				return Err(LoadError::InvalidMagic);
 			}
-			let version = u16::from_le_bytes(buffer[5..7].try_into().unwrap());
-			if version != FORMAT_VERSION {
-				return Err(LoadError::UnsupportedFormatVersion);
-			}
-			let resolution = u16::from_le_bytes(buffer[7..9].try_into().unwrap());
-			let height_resolution = u16::from_le_bytes(buffer[9..11].try_into().unwrap());
-			let metadata = TileMetadata {
-				version: FORMAT_VERSION,
-				resolution,
-				height_resolution,
-			};
 
-			let tile_map = buffer[32..]
-				.chunks_exact(8)
-				.map(|x| u64::from_le_bytes(x.try_into().unwrap()))
-				.collect();
+			let mut buffer = vec![0; Self::HEADER_SIZE];
+			buffer[0..5].copy_from_slice(&magic);
+			file.read_exact(&mut buffer[5..])
+				.map_err(|_| LoadError::InvalidFileSize)?;
+
+			let (metadata, tile_map) = Self::parse_header(&buffer)?;
+			let provenance = Provenance::from_header(&buffer);
+			buffer.resize(Self::header_size(metadata.version), 0);
+			file.read_exact(&mut buffer[Self::HEADER_SIZE..])
+				.map_err(|_| LoadError::InvalidFileSize)?;
+			let bounds = Self::parse_bounds(metadata.version, &buffer);
+			let checksums = Self::parse_checksums(metadata.version, &buffer);
+
+			let data_offset = buffer.len();
+			#[cfg(feature = "mmap")]
+			let data = {
+				let data = unsafe { MmapOptions::new().offset(data_offset as _).map(&file)? };
+				access.apply(&data);
+				Backing::Mmap(MappedFile::new(data, file, &tile_map, data_offset))
+			};
+			#[cfg(not(feature = "mmap"))]
+			let data = {
+				let _ = access;
+				let mut bytes = buffer;
+				file.read_to_end(&mut bytes)?;
+				Backing::Bytes(bytes.into())
+			};
 
 			Ok(Dataset {
 				metadata,
+				provenance,
 				tile_map,
-				data: unsafe { MmapOptions::new().offset(buffer.len() as _).map(&file)? },
+				bounds,
+				checksums,
+				verify_checksums: false,
+				data,
+				data_offset,
+				origin: Some((dir.to_path_buf(), access)),
 			})
 		}
 	}
 
+	/// Load a dataset that is too large for one file, split by latitude into `SHARD_COUNT` files in `dir`, named
+	/// `part_00.geo` for the southernmost band to `part_17.geo` for the northernmost. Each shard is a whole dataset,
+	/// and only its tiles within its band are used. Bands without any tiles can be left out, but every shard present
+	/// has to have the same metadata.
+	///
+	/// The tile maps of the shards are stitched into one, and every tile is read from the shard of its band.
+	/// `tile_offset` gives the offset of a tile within its shard.
+	pub fn load_shards(dir: &Path, access: Access) -> Result<Self, LoadError> {
+		let mut metadata = None;
+		let mut provenance = Provenance::default();
+		let mut shards = Vec::with_capacity(Self::SHARD_COUNT);
+		for i in 0..Self::SHARD_COUNT {
+			let path = dir.join(format!("part_{:02}.geo", i));
+			if !path.is_file() {
+				shards.push(None);
+				continue;
+			}
+
+			let shard = Self::load_with_advice(&path, access)?;
+			if *metadata.get_or_insert(shard.metadata) != shard.metadata {
+				return Err(LoadError::InconsistentShards);
+			}
+			// Shards are built in separate runs, so they may have been generated at different times.
+			provenance = shard.provenance;
+			shards.push(Some(shard));
+		}
+		let metadata = metadata.ok_or_else(|| {
+			std::io::Error::new(
+				std::io::ErrorKind::NotFound,
+				"No shards named part_NN.geo in the directory",
+			)
+		})?;
+
+		let band = 360 * 180 / Self::SHARD_COUNT;
+		let mut tile_map = vec![0; 360 * 180];
+		let mut bounds = (metadata.version >= 12).then(|| vec![(0, 0); 360 * 180]);
+		let mut checksums = (metadata.version >= 13).then(|| vec![(0, 0); 360 * 180]);
+		for (i, shard) in shards.iter().enumerate() {
+			if let Some(shard) = shard {
+				let range = i * band..(i + 1) * band;
+				tile_map[range.clone()].copy_from_slice(&shard.tile_map[range.clone()]);
+				if let (Some(bounds), Some(shard)) = (&mut bounds, &shard.bounds) {
+					bounds[range.clone()].copy_from_slice(&shard[range.clone()]);
+				}
+				if let (Some(checksums), Some(shard)) = (&mut checksums, &shard.checksums) {
+					checksums[range.clone()].copy_from_slice(&shard[range]);
+				}
+			}
+		}
+
+		Ok(Dataset {
+			metadata,
+			provenance,
+			tile_map,
+			bounds,
+			checksums,
+			verify_checksums: false,
+			data: Backing::Shards(shards),
+			data_offset: 0,
+			origin: Some((dir.to_path_buf(), access)),
+		})
+	}
+
+	/// Load a dataset from a buffer holding the whole file, such as one embedded in the binary.
+	pub fn from_bytes(bytes: Arc<[u8]>) -> Result<Self, LoadError> {
+		if bytes.len() < 5 || bytes[0..5] != Self::MAGIC {
+			return Err(LoadError::InvalidMagic);
+		}
+		let header = bytes.get(..Self::HEADER_SIZE).ok_or(LoadError::InvalidFileSize)?;
+		let (metadata, tile_map) = Self::parse_header(header)?;
+		let provenance = Provenance::from_header(header);
+		let data_offset = Self::header_size(metadata.version);
+		let header = bytes.get(..data_offset).ok_or(LoadError::InvalidFileSize)?;
+		let bounds = Self::parse_bounds(metadata.version, header);
+		let checksums = Self::parse_checksums(metadata.version, header);
+
+		Ok(Dataset {
+			metadata,
+			provenance,
+			tile_map,
+			bounds,
+			checksums,
+			verify_checksums: false,
+			data: Backing::Bytes(bytes),
+			data_offset,
+			origin: None,
+		})
+	}
+
+	/// Load the dataset again from the path it was loaded from, to pick up a file that was replaced on disk. If the
+	/// file fails to load, the dataset is left as it was. Whether checksums are verified is kept.
+	///
+	/// Datasets that weren't loaded from the file system can't be reloaded, and return an `Unsupported` IO error.
+	pub fn reload(&mut self) -> Result<(), LoadError> {
+		let (path, access) = self.origin.clone().ok_or_else(|| {
+			std::io::Error::new(
+				std::io::ErrorKind::Unsupported,
+				"Only datasets loaded from a path can be reloaded",
+			)
+		})?;
+		let verify_checksums = self.verify_checksums;
+		*self = Self::load_with_advice(&path, access)?;
+		self.verify_checksums = verify_checksums;
+		Ok(())
+	}
+
+	/// The size of the header of a dataset with the format version `version`, which is where the tiles can begin.
+	pub(crate) fn header_size(version: u16) -> usize {
+		if version >= 13 {
+			Self::HEADER_SIZE + Self::BOUNDS_SIZE + Self::CHECKSUMS_SIZE
+		} else if version >= 12 {
+			Self::HEADER_SIZE + Self::BOUNDS_SIZE
+		} else {
+			Self::HEADER_SIZE
+		}
+	}
+
+	/// Parse the header and tile map, after the magic has been checked. Anything after the tile map is ignored.
+	pub(crate) fn parse_header(header: &[u8]) -> Result<(TileMetadata, Vec<u64>), LoadError> {
+		let version = u16::from_le_bytes(header[5..7].try_into().unwrap());
+		if !is_supported_version(version) {
+			return Err(LoadError::UnsupportedFormatVersion);
+		}
+		let resolution = u16::from_le_bytes(header[7..9].try_into().unwrap());
+		let height_resolution = u16::from_le_bytes(header[9..11].try_into().unwrap());
+		if height_resolution == 0 {
+			return Err(LoadError::InvalidHeightResolution);
+		}
+		// Version 8 has no layers, and the byte is always 0. The same goes for mini-tiles before version 10.
+		let layers = Layers(header[11]);
+		let mini_tile_size = u16::from_le_bytes(header[12..14].try_into().unwrap());
+		if mini_tile_size != 0 && resolution % mini_tile_size != 0 {
+			return Err(LoadError::InvalidMiniTileSize);
+		}
+		let metadata = TileMetadata {
+			version,
+			resolution,
+			height_resolution,
+			layers,
+			mini_tile_size,
+		};
+
+		let tile_map = header[32..Self::HEADER_SIZE]
+			.chunks_exact(8)
+			.map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+			.collect();
+
+		Ok((metadata, tile_map))
+	}
+
+	/// Parse the bounds table after the tile map of a header that is `header_size(version)` long, if the version has
+	/// one.
+	pub(crate) fn parse_bounds(version: u16, header: &[u8]) -> Option<Vec<(i16, i16)>> {
+		(version >= 12).then(|| {
+			header[Self::HEADER_SIZE..Self::HEADER_SIZE + Self::BOUNDS_SIZE]
+				.chunks_exact(4)
+				.map(|x| (i16::from_le_bytes([x[0], x[1]]), i16::from_le_bytes([x[2], x[3]])))
+				.collect()
+		})
+	}
+
+	/// Parse the checksum table after the bounds table of a header that is `header_size(version)` long, if the version
+	/// has one.
+	pub(crate) fn parse_checksums(version: u16, header: &[u8]) -> Option<Vec<(u32, u32)>> {
+		let start = Self::HEADER_SIZE + Self::BOUNDS_SIZE;
+		(version >= 13).then(|| {
+			header[start..start + Self::CHECKSUMS_SIZE]
+				.chunks_exact(8)
+				.map(|x| {
+					(
+						u32::from_le_bytes(x[0..4].try_into().unwrap()),
+						u32::from_le_bytes(x[4..8].try_into().unwrap()),
+					)
+				})
+				.collect()
+		})
+	}
+
+	/// The size and CRC-32 of the frames of a tile, as they are stored in the checksum table.
+	pub(crate) fn checksum(frames: &[u8]) -> (u32, u32) { (frames.len() as u32, crc32fast::hash(frames)) }
+
 	pub fn metadata(&self) -> TileMetadata { self.metadata }
 
+	/// The format version of the file, as it was read from the header.
+	pub fn format_version(&self) -> u16 { self.metadata.version }
+
+	/// Where the heights came from, if the builder recorded it. Datasets from before format version 13 never have a
+	/// provenance. The shards of a sharded dataset are assumed to share one, and the last shard's is returned.
+	pub fn provenance(&self) -> Option<Provenance> { Some(self.provenance).filter(|&x| x != Provenance::default()) }
+
 	pub fn tile_exists(&self, lat: i16, lon: i16) -> bool {
 		let index = map_lat_lon_to_index(lat, lon);
 		self.tile_map[index] != 0
 	}
 
+	/// Whether the tile containing a point, in degrees, is present.
+	pub fn contains(&self, lat: f64, lon: f64) -> bool {
+		let (lat, lon) = Self::tile_containing(lat, lon);
+		self.tile_exists(lat, lon)
+	}
+
+	/// Find the present tile closest to a point, in degrees. Tiles are searched in rings of growing size around the
+	/// tile containing the point, and the closest tile of the first ring with any present tiles is returned, so the
+	/// result is the nearest in tiles, not necessarily in distance. Only the tile map is read.
+	pub fn nearest_present_tile(&self, lat: f64, lon: f64) -> Option<(i16, i16)> {
+		let (tile_lat, tile_lon) = Self::tile_containing(lat, lon);
+		// Compare the distances to the centers of tiles on a plane, scaling longitude by the latitude of the point.
+		let scale = lat.to_radians().cos();
+		let distance = |(tile_lat, tile_lon): (i16, i16)| {
+			let dlat = tile_lat as f64 + 0.5 - lat;
+			let dlon = ((tile_lon as f64 + 0.5 - lon + 180.0).rem_euclid(360.0) - 180.0) * scale;
+			dlat * dlat + dlon * dlon
+		};
+
+		for radius in 0..=180 {
+			let ring = (-radius..=radius)
+				.flat_map(|dlat| (-radius..=radius).map(move |dlon| (dlat, dlon)))
+				.filter(|&(dlat, dlon): &(i16, i16)| dlat.abs() == radius || dlon.abs() == radius)
+				.filter_map(|(dlat, dlon)| map_neighbour(tile_lat, tile_lon, dlat, dlon))
+				.filter(|&(lat, lon)| self.tile_exists(lat, lon));
+			if let Some(tile) = ring.min_by(|&a, &b| distance(a).total_cmp(&distance(b))) {
+				return Some(tile);
+			}
+		}
+
+		None
+	}
+
+	fn tile_containing(lat: f64, lon: f64) -> (i16, i16) {
+		let lat = lat.floor().clamp(-90.0, 89.0) as i16;
+		let lon = normalize_lon(lon).floor() as i16;
+		(lat, lon.min(179))
+	}
+
+	/// The minimum and maximum height of a tile in meters, without decoding it. Returns `None` if the tile is not
+	/// present, or if the dataset is older than format version 12 and has no bounds stored.
+	pub fn tile_bounds(&self, lat: i16, lon: i16) -> Option<(i16, i16)> {
+		let index = map_lat_lon_to_index(lat, lon);
+		if self.tile_map[index] == 0 {
+			return None;
+		}
+		self.bounds.as_ref().map(|x| x[index])
+	}
+
 	pub fn tile_count(&self) -> usize { self.tile_map.iter().filter(|&&x| x != 0).count() }
 
+	/// The offset of the tile from the beginning of the file, if it is present.
+	pub fn tile_offset(&self, lat: i16, lon: i16) -> Option<u64> {
+		let index = map_lat_lon_to_index(lat, lon);
+		Some(self.tile_map[index]).filter(|&x| x != 0)
+	}
+
+	/// The size of the dataset file in bytes.
+	pub fn file_size(&self) -> u64 { self.data.file_size(self.data_offset) }
+
+	/// The compressed size in bytes of every present tile, in the order they are stored in their file. Datasets with
+	/// checksums record the size of each tile. Without them, a tile extends until the next one in its file, or until
+	/// the end of it.
+	pub fn tile_sizes(&self) -> Vec<(i16, i16, u64)> {
+		if let Backing::Shards(shards) = &self.data {
+			// Offsets are local to each shard, so they can only be compared within one.
+			return shards.iter().flatten().flat_map(|x| x.tile_sizes()).collect();
+		}
+
+		let mut tiles: Vec<_> = self
+			.tile_map
+			.iter()
+			.enumerate()
+			.filter(|&(_, &offset)| offset != 0)
+			.map(|(index, &offset)| (offset, index))
+			.collect();
+		tiles.sort_unstable();
+
+		let file_size = self.file_size();
+		tiles
+			.iter()
+			.enumerate()
+			.map(|(i, &(offset, index))| {
+				let size = match &self.checksums {
+					Some(checksums) => checksums[index].0 as u64,
+					None => tiles.get(i + 1).map_or(file_size, |x| x.0) - offset,
+				};
+				let (lat, lon) = map_index_to_lat_lon(index);
+				(lat, lon, size)
+			})
+			.collect()
+	}
+
+	/// Whether the dataset stores checksums of its tiles, which it does since format version 13.
+	pub fn has_checksums(&self) -> bool { self.checksums.is_some() }
+
+	/// Check every tile read by the getters that decode or copy its frames (`get_tile`, `get_full_tile`,
+	/// `get_full_tile_into`, `get_heights`, `get_tile_raw`, `get_region`, the layer getters, and `tile_frames`) against
+	/// its checksum, returning an `InvalidData` error for tiles that don't match instead of decoding them. This costs a
+	/// pass over the compressed frames of each tile. Datasets without checksums have nothing to check against, and
+	/// are read as usual.
+	pub fn set_verify_checksums(&mut self, verify: bool) { self.verify_checksums = verify; }
+
+	/// Check a tile against its checksum without decoding it. Returns `None` if the tile is not present, and an
+	/// `Unsupported` error if the dataset has no checksums.
+	pub fn verify_tile(&self, lat: i16, lon: i16) -> Option<Result<(), std::io::Error>> {
+		zone!("Verify Tile");
+
+		let frame = match self.tile_frame(lat, lon)? {
+			Ok(x) => x,
+			Err(e) => return Some(Err(e)),
+		};
+		Some(self.check_frame(map_lat_lon_to_index(lat, lon), &frame))
+	}
+
+	fn check_frame(&self, index: usize, frame: &[u8]) -> Result<(), std::io::Error> {
+		let (len, crc) = match &self.checksums {
+			Some(x) => x[index],
+			None => {
+				return Err(std::io::Error::new(
+					std::io::ErrorKind::Unsupported,
+					"The dataset has no checksums",
+				))
+			},
+		};
+		let frames = frame
+			.get(..len as usize)
+			.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Truncated tile"))?;
+		if Self::checksum(frames).1 != crc {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				"Tile does not match its checksum",
+			));
+		}
+
+		Ok(())
+	}
+
+	/// Get the `height + 500`s of a tile in meters, with bit 15 set for water, along with the hillshade. The builder
+	/// guarantees that heights never exceed `MAX_HEIGHT`, so the water flag never collides with a height. Use
+	/// `get_full_tile` to get the water mask separately.
 	pub fn get_tile(&self, lat: i16, lon: i16) -> Option<Result<(Vec<u16>, Vec<u8>), std::io::Error>> {
 		Some(match self.get_full_tile(lat, lon)? {
-			Ok((mut data, water, hillshade)) => {
+			Ok((data, water, hillshade)) => {
+				let mut data = data.into_owned();
 				for (h, w) in data.iter_mut().zip(water) {
 					*h |= (w as u16) << 15;
 				}
@@ -76,73 +661,622 @@ impl Dataset {
 		})
 	}
 
-	pub fn get_full_tile(&self, lat: i16, lon: i16) -> Option<Result<(Vec<u16>, Vec<u8>, Vec<u8>), std::io::Error>> {
-		tracy::zone!("Get Tile");
+	/// Like `get_tile`, but without decoding the hillshade.
+	pub fn get_heights(&self, lat: i16, lon: i16) -> Option<Result<Vec<u16>, std::io::Error>> {
+		zone!("Get Heights");
+		let _permit = Permit::acquire();
 
-		let index = map_lat_lon_to_index(lat, lon);
-		let offset = self.tile_map[index] as usize;
-		if offset == 0 {
-			return None;
+		let frame = match self.checked_tile_frame(lat, lon)? {
+			Ok(x) => x,
+			Err(e) => return Some(Err(e)),
+		};
+		Some(self.decode_tile(&frame, false).map(|(data, water, _)| {
+			let mut data = data.into_owned();
+			for (h, w) in data.iter_mut().zip(water) {
+				*h |= (w as u16) << 15;
+			}
+			data
+		}))
+	}
+
+	/// Get the heights of a tile as they are stored, without multiplying them by the height resolution, along with the
+	/// height resolution to multiply them with. See `mapping` for how to convert them to meters. Only the heights are
+	/// decoded, without the water mask or the hillshade.
+	pub fn get_tile_raw(&self, lat: i16, lon: i16) -> Option<Result<(Vec<u16>, u16), std::io::Error>> {
+		zone!("Get Raw Tile");
+		let _permit = Permit::acquire();
+
+		let frame = match self.checked_tile_frame(lat, lon)? {
+			Ok(x) => x,
+			Err(e) => return Some(Err(e)),
+		};
+		Some(
+			self.decode_heights(&frame)
+				.map(|(data, _)| (data.into_owned(), self.metadata.height_resolution)),
+		)
+	}
+
+	/// Get the `height + 500`s of a tile in meters, the water mask, and the hillshade.
+	///
+	/// If the height resolution is 1 and the dataset is in memory, the heights are returned as the decoder produced
+	/// them, without another pass.
+	pub fn get_full_tile(
+		&self, lat: i16, lon: i16,
+	) -> Option<Result<(Cow<'_, [u16]>, Vec<u8>, Vec<u8>), std::io::Error>> {
+		zone!("Get Tile");
+		let _permit = Permit::acquire();
+
+		let frame = match self.checked_tile_frame(lat, lon)? {
+			Ok(x) => x,
+			Err(e) => return Some(Err(e)),
+		};
+
+		Some(match frame {
+			Cow::Borrowed(frame) => self.decode_tile(frame, true),
+			Cow::Owned(frame) => self
+				.decode_tile(&frame, true)
+				.map(|(data, water, hillshade)| (Cow::Owned(data.into_owned()), water, hillshade)),
+		})
+	}
+
+	/// Like `get_full_tile`, but decodes into the given buffers instead of allocating new ones. Their contents are
+	/// replaced, and they only allocate if they are too small, so decoding many tiles with the same buffers doesn't
+	/// allocate for them once they have grown to fit a tile. The heights are written into `heights` as they are
+	/// decoded, but the height codec still allocates for its own output, and so does webp with the `pure-rust-webp`
+	/// feature.
+	///
+	/// If the tile fails to decode, the contents of the buffers are unspecified.
+	pub fn get_full_tile_into(
+		&self, lat: i16, lon: i16, heights: &mut Vec<u16>, water: &mut Vec<u8>, hillshade: &mut Vec<u8>,
+	) -> Option<Result<(), std::io::Error>> {
+		zone!("Get Tile");
+		let _permit = Permit::acquire();
+
+		let frame = match self.checked_tile_frame(lat, lon)? {
+			Ok(x) => x,
+			Err(e) => return Some(Err(e)),
+		};
+
+		Some(self.decode_tile_into(&frame, heights, water, hillshade))
+	}
+
+	fn decode_tile_into(
+		&self, frame: &[u8], heights: &mut Vec<u16>, water: &mut Vec<u8>, hillshade: &mut Vec<u8>,
+	) -> Result<(), std::io::Error> {
+		let res = self.metadata.resolution as u32;
+		let hres = self.metadata.height_resolution;
+
+		let len = {
+			zone!("Decompress height");
+			self.decode_heights_into(frame, heights, |x| mapping::dequantize(x, hres))?
+		};
+		let rest = {
+			zone!("Decompress water");
+			Self::decompress_u8_webp_into(&frame[len..], res, res, water)?
+		};
+		{
+			zone!("Decompress hillshade");
+			Self::decompress_u8_webp_into(rest, res, res, hillshade)?;
 		}
 
-		let frame = &self.data[offset - (32 + 360 * 180 * 8)..];
+		Ok(())
+	}
+
+	/// The hillshade is left empty if `hillshade` is false.
+	fn decode_tile<'a>(
+		&self, frame: &'a [u8], hillshade: bool,
+	) -> Result<(Cow<'a, [u16]>, Vec<u8>, Vec<u8>), std::io::Error> {
 		let res = self.metadata.resolution as u32;
 
 		let (data, len) = {
-			tracy::zone!("Decompress height");
-			match decode(frame, res, res) {
-				Ok(x) => x,
+			zone!("Decompress height");
+			self.decode_heights(frame)?
+		};
+		let data = self.unmap_heights(data);
+		let (water, rest) = {
+			zone!("Decompress water");
+			Self::decompress_u8_webp(&frame[len..], res, res)?
+		};
+		let hillshade = if hillshade {
+			zone!("Decompress hillshade");
+			Self::decompress_u8_webp(rest, res, res)?.0
+		} else {
+			Vec::new()
+		};
+
+		Ok((data, water, hillshade))
+	}
+
+	/// Get the slope layer of a tile, if the dataset has one. See `Layers::SLOPE`.
+	pub fn get_slope(&self, lat: i16, lon: i16) -> Option<Result<Vec<u8>, std::io::Error>> {
+		self.get_layer(lat, lon, Layers::SLOPE)
+	}
+
+	/// Get the aspect layer of a tile, if the dataset has one. See `Layers::ASPECT`.
+	pub fn get_aspect(&self, lat: i16, lon: i16) -> Option<Result<Vec<u8>, std::io::Error>> {
+		self.get_layer(lat, lon, Layers::ASPECT)
+	}
+
+	/// Get the coverage layer of a tile, if the dataset has one. See `Layers::COVERAGE`.
+	pub fn get_coverage(&self, lat: i16, lon: i16) -> Option<Result<Vec<u8>, std::io::Error>> {
+		self.get_layer(lat, lon, Layers::COVERAGE)
+	}
+
+	fn get_layer(&self, lat: i16, lon: i16, layer: Layers) -> Option<Result<Vec<u8>, std::io::Error>> {
+		zone!("Get Layer");
+
+		if !self.metadata.layers.contains(layer) {
+			return None;
+		}
+		let _permit = Permit::acquire();
+
+		let frame = match self.checked_tile_frame(lat, lon)? {
+			Ok(x) => x,
+			Err(e) => return Some(Err(e)),
+		};
+		let res = self.metadata.resolution as u32;
+
+		let len = match self.heights_len(&frame) {
+			Ok(x) => x,
+			Err(e) => return Some(Err(e)),
+		};
+		let mut rest = &frame[len..];
+		for _ in 0..2 + self.metadata.layers.index_of(layer) {
+			match Self::webp_frame_size(rest) {
+				Ok(size) => rest = &rest[size..],
 				Err(e) => return Some(Err(e)),
 			}
+		}
+
+		Some(Self::decompress_u8_webp(rest, res, res).map(|x| x.0))
+	}
+
+	/// The codec that the heights of a tile are compressed with, read from its tag without decoding anything. Tiles
+	/// from before format version 11 are always hcomp.
+	pub fn tile_codec(&self, lat: i16, lon: i16) -> Option<Result<Codec, std::io::Error>> {
+		let frame = match self.tile_frame(lat, lon)? {
+			Ok(x) => x,
+			Err(e) => return Some(Err(e)),
 		};
-		let data: Vec<_> = {
-			tracy::zone!("Unmap height");
-			data.data
-				.into_owned()
-				.into_iter()
-				.map(|x| x * self.metadata.height_resolution)
-				.collect()
+		Some(self.heights_codec(&frame).map(|x| x.0))
+	}
+
+	/// Get the compressed frames of a tile without decompressing them, for copying tiles between datasets without
+	/// re-encoding them. Unless the dataset is mini-tiled, hcomp heights are still decoded, since the end of a hcomp
+	/// frame is only known after decoding it, and so are the heights of datasets without stored bounds. Tiles from
+	/// before format version 11 are given the codec tag of the current format.
+	pub fn tile_frames(&self, lat: i16, lon: i16) -> Option<Result<RawTile<'_>, std::io::Error>> {
+		zone!("Get Tile Frames");
+		// The heights may be decoded to find their end, or their bounds.
+		let _permit = Permit::acquire();
+
+		let frame = match self.checked_tile_frame(lat, lon)? {
+			Ok(x) => x,
+			Err(e) => return Some(Err(e)),
 		};
-		let (water, rest) = {
-			tracy::zone!("Decompress water");
 
-			match Self::decompress_u8_webp(&frame[len..], res, res) {
-				Ok(x) => x,
-				Err(e) => return Some(Err(e)),
+		let height = match self.heights_len(&frame) {
+			Ok(x) => x,
+			Err(e) => return Some(Err(e)),
+		};
+		let mut ends = [height; 4];
+		let mut end = height;
+		for (i, count) in [1, 1, self.metadata.layers.0.count_ones()].into_iter().enumerate() {
+			for _ in 0..count {
+				match Self::webp_frame_size(&frame[end..]) {
+					Ok(size) => end += size,
+					Err(e) => return Some(Err(e)),
+				}
 			}
+			ends[i + 1] = end;
+		}
+
+		let index = map_lat_lon_to_index(lat, lon);
+		let bounds = match &self.bounds {
+			Some(x) => x[index],
+			// Older datasets don't store the bounds, so the heights have to be decoded for them.
+			None => match self.decode_heights(&frame) {
+				Ok((heights, _)) => height_bounds(&heights, self.metadata.height_resolution),
+				Err(e) => return Some(Err(e)),
+			},
+		};
+
+		if self.metadata.version < 11 {
+			// Add the codec tag of the current format, so the frames can be given to `DatasetBuilder::add_raw_tile`.
+			let mut tagged = Vec::with_capacity(end + 1);
+			tagged.push(Codec::Hcomp as u8);
+			tagged.extend_from_slice(&frame[..end]);
+			return Some(Ok(RawTile {
+				frame: Cow::Owned(tagged),
+				ends: ends.map(|x| x + 1),
+				bounds,
+			}));
+		}
+
+		Some(Ok(RawTile { frame, ends, bounds }))
+	}
+
+	/// Get the `height + 500`s in meters of a rectangle of a tile, in row-major order. Only the mini-tiles that
+	/// overlap the rectangle are decoded, so this is cheaper than `get_full_tile` for small rectangles of mini-tiled
+	/// datasets. Without mini-tiles, or if the tile is not compressed with hcomp, the whole tile is decoded.
+	pub fn get_region(&self, lat: i16, lon: i16, rect: TileRect) -> Option<Result<Vec<u16>, std::io::Error>> {
+		zone!("Get Region");
+
+		let res = self.metadata.resolution as usize;
+		if rect.x + rect.width > res || rect.y + rect.height > res {
+			return Some(Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				"Region is outside of the tile",
+			)));
+		}
+		let _permit = Permit::acquire();
+
+		let frame = match self.checked_tile_frame(lat, lon)? {
+			Ok(x) => x,
+			Err(e) => return Some(Err(e)),
 		};
-		let (hillshade, _) = {
-			tracy::zone!("Decompress hillshade");
-			match Self::decompress_u8_webp(rest, res, res) {
+
+		let (codec, start) = match self.heights_codec(&frame) {
+			Ok(x) => x,
+			Err(e) => return Some(Err(e)),
+		};
+		let mini = self.metadata.mini_tile_size as usize;
+		let mut out = vec![0; rect.width * rect.height];
+		if mini == 0 || codec != Codec::Hcomp || rect.width == 0 || rect.height == 0 {
+			let data = match self.decode_heights(&frame) {
+				Ok(x) => x.0,
+				Err(e) => return Some(Err(e)),
+			};
+			for (y, row) in out.chunks_exact_mut(rect.width.max(1)).enumerate() {
+				let start = (rect.y + y) * res + rect.x;
+				row.copy_from_slice(&data[start..start + rect.width]);
+			}
+		} else {
+			let ranges = match self.mini_tile_ranges(&frame, start) {
 				Ok(x) => x,
 				Err(e) => return Some(Err(e)),
+			};
+			let per_row = res / mini;
+			for by in rect.y / mini..=(rect.y + rect.height - 1) / mini {
+				for bx in rect.x / mini..=(rect.x + rect.width - 1) / mini {
+					let block = match decode(&frame[ranges[by * per_row + bx].clone()], mini as _, mini as _) {
+						Ok(x) => x.0.data,
+						Err(e) => return Some(Err(e)),
+					};
+
+					// The part of the rectangle covered by this mini-tile, in tile pixels.
+					let (x0, x1) = (rect.x.max(bx * mini), (rect.x + rect.width).min((bx + 1) * mini));
+					let (y0, y1) = (rect.y.max(by * mini), (rect.y + rect.height).min((by + 1) * mini));
+					for y in y0..y1 {
+						let src = (y - by * mini) * mini + x0 - bx * mini;
+						let dst = (y - rect.y) * rect.width + x0 - rect.x;
+						out[dst..dst + x1 - x0].copy_from_slice(&block[src..src + x1 - x0]);
+					}
+				}
+			}
+		}
+
+		Some(Ok(self.unmap_heights(out.into()).into_owned()))
+	}
+
+	/// Decode the stored heights of a tile, returning them along with the size of their frames.
+	fn decode_heights<'a>(&self, frame: &'a [u8]) -> Result<(Cow<'a, [u16]>, usize), std::io::Error> {
+		let (codec, start) = self.heights_codec(frame)?;
+		if codec == Codec::Hcomp && self.metadata.mini_tile_size == 0 {
+			// The output of the decoder is returned as is, without copying it.
+			let res = self.metadata.resolution as u32;
+			let (data, len) = decode(&frame[start..], res, res)?;
+			return Ok((data.data, start + len));
+		}
+
+		let mut data = Vec::new();
+		let len = self.decode_heights_into(frame, &mut data, |x| x)?;
+		Ok((data.into(), len))
+	}
+
+	/// Decode the heights at the start of a tile, replacing the contents of `out` with them after passing each through
+	/// `map`. Returns the size of the heights in the frame.
+	fn decode_heights_into(
+		&self, frame: &[u8], out: &mut Vec<u16>, map: impl Fn(u16) -> u16,
+	) -> Result<usize, std::io::Error> {
+		let res = self.metadata.resolution as usize;
+		let mini = self.metadata.mini_tile_size as usize;
+		let (codec, start) = self.heights_codec(frame)?;
+		out.clear();
+		match codec {
+			Codec::Zstd => {
+				let (bytes, len) = Self::decompress_zstd(&frame[start..], res)?;
+				out.extend(bytes.chunks_exact(2).map(|x| map(u16::from_le_bytes([x[0], x[1]]))));
+				return Ok(start + len);
+			},
+			Codec::Hcomp if mini == 0 => {
+				let (data, len) = decode(&frame[start..], res as _, res as _)?;
+				out.extend(data.data.iter().map(|&x| map(x)));
+				return Ok(start + len);
+			},
+			Codec::Hcomp => {},
+		}
+
+		let ranges = self.mini_tile_ranges(frame, start)?;
+		let len = ranges.last().map_or(start, |x| x.end);
+		let per_row = res / mini;
+		out.resize(res * res, 0);
+		for (i, range) in ranges.into_iter().enumerate() {
+			let block = decode(&frame[range], mini as _, mini as _)?.0.data;
+			let (bx, by) = (i % per_row, i / per_row);
+			for (y, row) in block.chunks_exact(mini).enumerate() {
+				let start = (by * mini + y) * res + bx * mini;
+				for (out, &x) in out[start..start + mini].iter_mut().zip(row) {
+					*out = map(x);
+				}
+			}
+		}
+
+		Ok(len)
+	}
+
+	/// Multiply stored heights by the height resolution.
+	fn unmap_heights<'a>(&self, data: Cow<'a, [u16]>) -> Cow<'a, [u16]> {
+		if self.metadata.height_resolution == 1 {
+			data
+		} else {
+			zone!("Unmap height");
+			data.iter()
+				.map(|&x| mapping::dequantize(x, self.metadata.height_resolution))
+				.collect::<Vec<_>>()
+				.into()
+		}
+	}
+
+	/// The size of the heights at the start of a tile, including their codec.
+	fn heights_len(&self, frame: &[u8]) -> Result<usize, std::io::Error> {
+		let (codec, start) = self.heights_codec(frame)?;
+		match codec {
+			Codec::Zstd => Ok(start + 4 + Self::zstd_frame(&frame[start..])?.len()),
+			Codec::Hcomp if self.metadata.mini_tile_size == 0 => {
+				// The length of a hcomp frame is only known after decoding it.
+				let res = self.metadata.resolution as u32;
+				Ok(start + decode(&frame[start..], res, res)?.1)
+			},
+			Codec::Hcomp => Ok(self.mini_tile_ranges(frame, start)?.last().map_or(start, |x| x.end)),
+		}
+	}
+
+	/// The codec of the heights of a tile, and the size of the tag that stores it. Tiles from before format version 11
+	/// have no tag, and are always hcomp.
+	fn heights_codec(&self, frame: &[u8]) -> Result<(Codec, usize), std::io::Error> {
+		if self.metadata.version < 11 {
+			return Ok((Codec::Hcomp, 0));
+		}
+
+		let tag = *frame
+			.first()
+			.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Truncated tile"))?;
+		let codec = Codec::from_tag(tag)
+			.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Unknown height codec"))?;
+		Ok((codec, 1))
+	}
+
+	/// Where the frame of each mini-tile is, from the start of the tile, if the mini-tile table starts at `start`.
+	fn mini_tile_ranges(&self, frame: &[u8], start: usize) -> Result<Vec<Range<usize>>, std::io::Error> {
+		let per_row = (self.metadata.resolution / self.metadata.mini_tile_size) as usize;
+		let count = per_row * per_row;
+		let truncated = || std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Truncated mini-tile table");
+
+		let table = frame.get(start..start + count * 4).ok_or_else(truncated)?;
+		let mut start = start + table.len();
+		let mut ranges = Vec::with_capacity(count);
+		for size in table.chunks_exact(4) {
+			let end = start + u32::from_le_bytes(size.try_into().unwrap()) as usize;
+			if end > frame.len() {
+				return Err(truncated());
 			}
+			ranges.push(start..end);
+			start = end;
+		}
+
+		Ok(ranges)
+	}
+
+	/// Like `tile_frame`, but checks the frames against their checksum first if `set_verify_checksums` is enabled.
+	fn checked_tile_frame(&self, lat: i16, lon: i16) -> Option<Result<Cow<'_, [u8]>, std::io::Error>> {
+		let frame = match self.tile_frame(lat, lon)? {
+			Ok(x) => x,
+			Err(e) => return Some(Err(e)),
 		};
+		if self.verify_checksums && self.checksums.is_some() {
+			zone!("Verify checksum");
+			if let Err(e) = self.check_frame(map_lat_lon_to_index(lat, lon), &frame) {
+				return Some(Err(e));
+			}
+		}
+
+		Some(Ok(frame))
+	}
+
+	fn tile_frame(&self, lat: i16, lon: i16) -> Option<Result<Cow<'_, [u8]>, std::io::Error>> {
+		if let Backing::Shards(shards) = &self.data {
+			let band = (lat + 90) as usize * Self::SHARD_COUNT / 180;
+			return shards[band].as_ref()?.tile_frame(lat, lon);
+		}
+
+		let offset = self.tile_offset(lat, lon)?;
+		Some(self.data.tile(offset, self.data_offset))
+	}
+
+	/// The zstd frame at the start of `data`, after its `u32` size.
+	fn zstd_frame(data: &[u8]) -> Result<&[u8], std::io::Error> {
+		let truncated = || std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Truncated zstd frame");
+		let size = data.get(..4).ok_or_else(truncated)?;
+		let size = u32::from_le_bytes(size.try_into().unwrap()) as usize;
+		data.get(4..4 + size).ok_or_else(truncated)
+	}
+
+	/// Decompress the zstd heights at the start of `data`, returning their little endian bytes along with their size.
+	#[cfg(feature = "zstd")]
+	fn decompress_zstd(data: &[u8], res: usize) -> Result<(Vec<u8>, usize), std::io::Error> {
+		let frame = Self::zstd_frame(data)?;
+		let bytes = zstd::bulk::decompress(frame, res * res * 2)?;
+		if bytes.len() != res * res * 2 {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				"Wrong number of heights in zstd frame",
+			));
+		}
 
-		Some(Ok((data, water, hillshade)))
+		Ok((bytes, 4 + frame.len()))
+	}
+
+	#[cfg(not(feature = "zstd"))]
+	fn decompress_zstd(_: &[u8], _: usize) -> Result<(Vec<u8>, usize), std::io::Error> {
+		Err(std::io::Error::new(
+			std::io::ErrorKind::Unsupported,
+			"Built without zstd support",
+		))
+	}
+
+	/// The size of the webp frame at the start of `data`, including the RIFF header.
+	fn webp_frame_size(data: &[u8]) -> Result<usize, std::io::Error> {
+		let truncated = || std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Truncated webp frame");
+		if data.len() < 8 {
+			return Err(truncated());
+		}
+		let frame_size = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize + 8;
+		if frame_size > data.len() {
+			return Err(truncated());
+		}
+
+		Ok(frame_size)
 	}
 
 	fn decompress_u8_webp(data: &[u8], width: u32, height: u32) -> Result<(Vec<u8>, &[u8]), std::io::Error> {
-		unsafe {
-			let frame_size = u32::from_le_bytes(data[4..8].try_into().unwrap()) + 8;
-			let frame = &data[..frame_size as usize];
-			let mut decompressed = vec![0; width as usize * height as usize];
-			if WebPDecodeRGBAInto(
-				frame.as_ptr(),
-				frame.len(),
-				decompressed.as_mut_ptr(),
-				decompressed.len(),
-				width as i32 * 2,
-			)
-			.is_null()
-			{
-				return Err(std::io::Error::new(
-					std::io::ErrorKind::Other,
-					"WebPDecodeRGBAInto failed",
-				));
-			}
+		let frame_size = Self::webp_frame_size(data)?;
+		let decompressed = webp::decode(&data[..frame_size], width, height)?;
+		Ok((decompressed, &data[frame_size..]))
+	}
+
+	/// Like `decompress_u8_webp`, but replaces the contents of `out`, returning the rest of `data`.
+	fn decompress_u8_webp_into<'a>(
+		data: &'a [u8], width: u32, height: u32, out: &mut Vec<u8>,
+	) -> Result<&'a [u8], std::io::Error> {
+		let frame_size = Self::webp_frame_size(data)?;
+		webp::decode_into(&data[..frame_size], width, height, out)?;
+		Ok(&data[frame_size..])
+	}
+}
+
+/// Tiles are addressed with `usize` offsets, so a file longer than `max`, the largest `usize` of the target, can't be
+/// loaded. This only fails on 32-bit targets, but takes the limit as a parameter so that it can be checked anywhere.
+fn check_addressable(len: u64, max: u64) -> Result<(), LoadError> {
+	if len > max {
+		Err(LoadError::FileTooLarge)
+	} else {
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const GIB: u64 = 1024 * 1024 * 1024;
 
-			Ok((decompressed, &data[frame_size as usize..]))
+	#[test]
+	fn files_over_4_gib_are_too_large_for_32_bit() {
+		let max = u32::MAX as u64;
+		assert!(check_addressable(GIB, max).is_ok());
+		assert!(check_addressable(max, max).is_ok());
+		assert!(matches!(check_addressable(4 * GIB, max), Err(LoadError::FileTooLarge)));
+		assert!(matches!(check_addressable(5 * GIB, max), Err(LoadError::FileTooLarge)));
+	}
+
+	fn header(height_resolution: u16) -> Vec<u8> {
+		let mut header = vec![0; Dataset::HEADER_SIZE];
+		header[5..7].copy_from_slice(&crate::FORMAT_VERSION.to_le_bytes());
+		header[7..9].copy_from_slice(&1200u16.to_le_bytes());
+		header[9..11].copy_from_slice(&height_resolution.to_le_bytes());
+		header
+	}
+
+	/// Two tiles of different heights, in a dataset built with `codec`, `mini_tile_size`, and a height resolution of 2.
+	fn two_tiles(codec: Codec, mini_tile_size: u16) -> Dataset {
+		let res = 16;
+		let path = std::env::temp_dir().join(format!(
+			"geo-test-into-{:?}-{}-{}.geo",
+			codec,
+			mini_tile_size,
+			std::process::id()
+		));
+		let metadata = crate::TileMetadata {
+			version: crate::FORMAT_VERSION,
+			resolution: res as _,
+			height_resolution: 2,
+			layers: Layers::NONE,
+			mini_tile_size,
+		};
+		let mut builder = crate::DatasetBuilder::new(&path, metadata).unwrap();
+		builder.set_codec(codec);
+		for lon in 0..2 {
+			let heights = (0..res * res)
+				.map(|i| (i * 37 + lon * 1000) as u16 % 3000 + 500)
+				.collect();
+			let water = (0..res * res).map(|i| (i % 2 * 255) as u8).collect();
+			builder
+				.add_tile(0, lon as _, heights, water, vec![lon as u8 * 100; res * res])
+				.unwrap();
+		}
+		builder.finish().unwrap();
+
+		let bytes = std::fs::read(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		Dataset::from_bytes(bytes.into()).unwrap()
+	}
+
+	#[test]
+	fn decoding_into_buffers_matches_allocating() {
+		let mut cases = vec![(Codec::Hcomp, 0), (Codec::Hcomp, 8)];
+		if cfg!(feature = "zstd") {
+			cases.push((Codec::Zstd, 0));
 		}
+
+		for (codec, mini_tile_size) in cases {
+			let dataset = two_tiles(codec, mini_tile_size);
+			let (mut heights, mut water, mut hillshade) = (Vec::new(), Vec::new(), Vec::new());
+			for lon in 0..2 {
+				dataset
+					.get_full_tile_into(0, lon, &mut heights, &mut water, &mut hillshade)
+					.unwrap()
+					.unwrap();
+				let (expected_heights, expected_water, expected_hillshade) =
+					dataset.get_full_tile(0, lon).unwrap().unwrap();
+				assert_eq!(heights, &*expected_heights, "{:?} {}", codec, mini_tile_size);
+				assert_eq!(water, expected_water);
+				assert_eq!(hillshade, expected_hillshade);
+			}
+		}
+	}
+
+	#[test]
+	fn headers_with_a_height_resolution_of_0_are_rejected() {
+		assert!(matches!(
+			Dataset::parse_header(&header(0)),
+			Err(LoadError::InvalidHeightResolution)
+		));
+		assert!(Dataset::parse_header(&header(1)).is_ok());
+	}
+
+	#[cfg(target_pointer_width = "32")]
+	#[test]
+	fn the_target_limit_is_4_gib() {
+		assert!(matches!(
+			check_addressable(4 * GIB, usize::MAX as u64),
+			Err(LoadError::FileTooLarge)
+		));
+	}
+
+	#[cfg(target_pointer_width = "64")]
+	#[test]
+	fn the_target_has_no_practical_limit() {
+		assert!(check_addressable(5 * GIB, usize::MAX as u64).is_ok());
 	}
 }
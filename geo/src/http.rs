@@ -0,0 +1,97 @@
+//! Reading datasets over HTTP, fetching only the header up front and every tile with a range request.
+
+use std::io::{Error, ErrorKind, Read};
+
+use crate::{dataset::Backing, Dataset, LoadError, Provenance};
+
+pub(crate) struct HttpSource {
+	url: String,
+	/// The sorted offsets of all tiles, followed by the size of the file. A tile ends where the next one begins.
+	ends: Vec<u64>,
+}
+
+impl HttpSource {
+	pub(crate) fn file_size(&self) -> u64 { *self.ends.last().unwrap() }
+
+	pub(crate) fn read_tile(&self, offset: u64) -> Result<Vec<u8>, Error> {
+		zone!("Fetch Tile");
+
+		let end = self.ends[self.ends.partition_point(|&x| x <= offset).min(self.ends.len() - 1)];
+		if end <= offset {
+			return Err(Error::new(
+				ErrorKind::UnexpectedEof,
+				"Tile offset past the end of the file",
+			));
+		}
+
+		Self::get_range(&self.url, offset, end).map(|x| x.0)
+	}
+
+	/// Fetch the bytes in `start..end`, returning them along with the size of the whole file.
+	fn get_range(url: &str, start: u64, end: u64) -> Result<(Vec<u8>, u64), Error> {
+		let response = ureq::get(url)
+			.set("Range", &format!("bytes={}-{}", start, end - 1))
+			.call()
+			.map_err(|e| Error::new(ErrorKind::Other, e))?;
+		if response.status() != 206 {
+			return Err(Error::new(ErrorKind::Other, "Server does not support range requests"));
+		}
+
+		// `Content-Range: bytes <start>-<end>/<size>`
+		let size = response
+			.header("Content-Range")
+			.and_then(|x| x.rsplit('/').next())
+			.and_then(|x| x.trim().parse().ok())
+			.ok_or_else(|| Error::new(ErrorKind::InvalidData, "Missing or unknown Content-Range"))?;
+
+		let mut data = Vec::with_capacity((end - start) as usize);
+		response.into_reader().read_to_end(&mut data)?;
+		if data.len() as u64 != end - start {
+			return Err(Error::new(ErrorKind::UnexpectedEof, "Short range response"));
+		}
+
+		Ok((data, size))
+	}
+}
+
+impl Dataset {
+	/// Open a dataset served over HTTP. Only the header is fetched up front, and every tile is fetched with a range
+	/// request when it is read, so the server must support range requests.
+	pub fn open_url(url: &str) -> Result<Self, LoadError> {
+		let fetch = |start: usize, end: usize| match HttpSource::get_range(url, start as _, end as _) {
+			Ok(x) => Ok(x),
+			Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(LoadError::InvalidFileSize),
+			Err(e) => Err(LoadError::from(e)),
+		};
+
+		let (mut header, size) = fetch(0, Self::HEADER_SIZE)?;
+		if header[0..5] != Self::MAGIC {
+			return Err(LoadError::InvalidMagic);
+		}
+		let (metadata, tile_map) = Self::parse_header(&header)?;
+		let provenance = Provenance::from_header(&header);
+		// The version is only known once the header is in, so the bounds table needs a request of its own.
+		let header_size = Self::header_size(metadata.version);
+		if header_size > header.len() {
+			header.extend(fetch(header.len(), header_size)?.0);
+		}
+		let bounds = Self::parse_bounds(metadata.version, &header);
+		let checksums = Self::parse_checksums(metadata.version, &header);
+
+		let mut ends: Vec<_> = tile_map.iter().copied().filter(|&x| x != 0).collect();
+		ends.sort_unstable();
+		ends.push(size);
+
+		Ok(Dataset {
+			metadata,
+			provenance,
+			tile_map,
+			bounds,
+			checksums,
+			verify_checksums: false,
+			data: Backing::Http(HttpSource { url: url.into(), ends }),
+			data_offset: header.len(),
+			origin: None,
+		})
+	}
+}
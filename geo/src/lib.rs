@@ -5,12 +5,28 @@ extern crate core;
 use std::{
 	error::Error,
 	fmt::{Debug, Display},
+	ops::BitOr,
 };
 
+/// `tracy::zone!`, if the `profiling` feature is enabled.
+macro_rules! zone {
+	($name:literal) => {
+		#[cfg(feature = "profiling")]
+		tracy::zone!($name);
+	};
+}
+
 mod dataset;
 pub use dataset::*;
 mod builder;
 pub use builder::*;
+#[cfg(feature = "http")]
+mod http;
+mod limit;
+pub use limit::set_max_decodes_in_flight;
+pub mod mapping;
+mod peaks;
+mod webp;
 
 /// ## Format version 1
 /// Metadata file (_meta):
@@ -139,12 +155,99 @@ pub use builder::*;
 ///   beginning of the file). If zero, the tile is not present.
 /// * [offset..]: A hcomp frame containing the compressed data of the tile, until the next tile, followed by a webp
 ///   image of the water mask, further followed by a webp image of the hillshade.
-pub const FORMAT_VERSION: u16 = 8;
+///
+/// # Format version 9
+/// Adds optional layers. Version 8 datasets are still supported, and have no layers.
+/// * [0..5]: Magic number: `[115, 117, 115, 115, 121]`.
+/// * [5..7]: The format version, little endian.
+/// * [7..9]: The resolution of the square tile (one side).
+/// * [9..11]: The resolution of height values (round each raw value to the nearest multiple).
+/// * [11]: The layers present in every tile, as the bits of `Layers`.
+/// * [12..32]: Empty space, for future use. Must be 0.
+/// * [32..32 + 360 * 180 * 8] @ offsets: 360 * 180 `u64`s that store the offsets of the tile in question (from the
+///   beginning of the file). If zero, the tile is not present.
+/// * [offset..]: A hcomp frame containing the compressed data of the tile, until the next tile, followed by a webp
+///   image of the water mask, a webp image of the hillshade, and a webp image for each layer present, in the order of
+///   their bits.
+///
+/// # Format version 10
+/// Brings back the mini-tiles of version 4, optionally. Version 8 and 9 datasets are still supported, and are not
+/// mini-tiled.
+/// * [0..5]: Magic number: `[115, 117, 115, 115, 121]`.
+/// * [5..7]: The format version, little endian.
+/// * [7..9]: The resolution of the square tile (one side).
+/// * [9..11]: The resolution of height values (round each raw value to the nearest multiple).
+/// * [11]: The layers present in every tile, as the bits of `Layers`.
+/// * [12..14]: The size of each mini-tile (one side), which divides the resolution. If zero, tiles are not mini-tiled.
+/// * [14..32]: Empty space, for future use. Must be 0.
+/// * [32..32 + 360 * 180 * 8] @ offsets: 360 * 180 `u64`s that store the offsets of the tile in question (from the
+///   beginning of the file). If zero, the tile is not present.
+/// * [offset..]: The heights of the tile, until the next tile, followed by a webp image of the water mask, a webp image
+///   of the hillshade, and a webp image for each layer present, in the order of their bits.
+///
+/// Without mini-tiles, the heights are a single hcomp frame. With them, the tile is split into `(res / mini)²` square
+/// mini-tiles in row-major order, and the heights are a `u32` for the size of each mini-tile's frame, followed by a
+/// hcomp frame for each mini-tile. Each frame can be found and decoded on its own, so a part of a tile can be read
+/// without decoding all of it.
+///
+/// # Format version 11
+/// Tiles choose the codec of their heights. The header is the same as version 10, and version 8 to 10 datasets are
+/// still supported, with every tile using hcomp.
+/// * [offset]: The codec of the heights, as a `Codec`.
+/// * [offset + 1..]: The heights of the tile, followed by the webp images of version 10.
+///
+/// With `Codec::Hcomp`, the heights are stored like version 10. With `Codec::Zstd`, they are a `u32` for the size of a
+/// zstd frame, followed by the frame, which holds the mapped heights of the whole tile as little endian `u16`s in
+/// row-major order. Zstd tiles are never mini-tiled, even in a mini-tiled dataset.
+///
+/// # Format version 12
+/// Stores the bounding heights of every tile, so they can be known without decoding it. Tiles are the same as version
+/// 11, and version 8 to 11 datasets are still supported, without bounds.
+/// * [0..32 + 360 * 180 * 8]: The header and offsets of version 10.
+/// * [32 + 360 * 180 * 8..32 + 360 * 180 * 12]: 360 * 180 pairs of `i16`s, in the same order as the offsets, that store
+///   the minimum and maximum height of the tile in meters (without the `+ 500`). Zero for tiles that are not present.
+/// * [32 + 360 * 180 * 12..]: The tiles, at their offsets.
+///
+/// # Format version 13
+/// Stores a checksum of every tile, so that corrupted tiles can be detected, and where the heights came from. Tiles are
+/// the same as version 11, and version 8 to 12 datasets are still supported, without checksums.
+/// * [0..14]: The header of version 10.
+/// * [14]: The vertical datum of the heights, as a `VerticalDatum`.
+/// * [15..23]: When the dataset was generated, as a `u64` of seconds since the Unix epoch. 0 if unknown.
+/// * [23..32]: Empty space, for future use. Must be 0.
+/// * [32..32 + 360 * 180 * 12]: The offsets and bounds of version 12.
+/// * [32 + 360 * 180 * 12..32 + 360 * 180 * 20]: 360 * 180 pairs of `u32`s, in the same order as the offsets, that
+///   store the size of the frames of the tile, and the CRC-32 of those frames. Zero for tiles that are not present.
+/// * [32 + 360 * 180 * 20..]: The tiles, at their offsets.
+pub const FORMAT_VERSION: u16 = 13;
+
+/// The oldest format version that can still be loaded. Datasets are only ever built with `FORMAT_VERSION`.
+pub const MIN_FORMAT_VERSION: u16 = 8;
+
+/// Whether a dataset with the format version `version` can be loaded.
+pub fn is_supported_version(version: u16) -> bool { (MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) }
+
+/// The largest `height + 500` value (in meters, after applying the height resolution) that can be stored in a tile.
+/// Bit 15 is reserved for the water flag in `Dataset::get_tile`. See `mapping`.
+pub const MAX_HEIGHT: u16 = 0x7fff;
 
 pub enum LoadError {
 	InvalidFileSize,
 	InvalidMagic,
 	UnsupportedFormatVersion,
+	/// The datasets used as levels of detail are missing, not ordered by strictly decreasing resolution, a level does
+	/// not fit in the tile atlas, or the level the renderer is forced to use does not exist.
+	InconsistentLod,
+	/// The file cannot be addressed on this target, which happens for files larger than 4 GiB on 32-bit targets.
+	FileTooLarge,
+	/// The mini-tile size does not divide the resolution.
+	InvalidMiniTileSize,
+	/// The height resolution is 0, so no height could be stored.
+	InvalidHeightResolution,
+	/// The shards of a sharded dataset don't all have the same metadata.
+	InconsistentShards,
+	/// The renderer can't draw to the texture format with this name.
+	UnsupportedOutputFormat(String),
 	Io(std::io::Error),
 }
 
@@ -154,6 +257,12 @@ impl Display for LoadError {
 			Self::InvalidFileSize => write!(f, "Invalid file size"),
 			Self::InvalidMagic => write!(f, "Invalid magic number"),
 			Self::UnsupportedFormatVersion => write!(f, "Unknown format version"),
+			Self::InconsistentLod => write!(f, "Inconsistent level of detail datasets"),
+			Self::FileTooLarge => write!(f, "File too large to map on this target"),
+			Self::InvalidMiniTileSize => write!(f, "Mini-tile size does not divide the resolution"),
+			Self::InvalidHeightResolution => write!(f, "Height resolution is 0"),
+			Self::InconsistentShards => write!(f, "Shards with different metadata"),
+			Self::UnsupportedOutputFormat(x) => write!(f, "Unsupported output format: {}", x),
 			Self::Io(x) => write!(f, "IO error: {}", x),
 		}
 	}
@@ -163,7 +272,14 @@ impl Debug for LoadError {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { Display::fmt(self, f) }
 }
 
-impl Error for LoadError {}
+impl Error for LoadError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			Self::Io(x) => Some(x),
+			_ => None,
+		}
+	}
+}
 
 impl From<std::io::Error> for LoadError {
 	fn from(x: std::io::Error) -> Self { Self::Io(x) }
@@ -178,6 +294,152 @@ pub struct TileMetadata {
 	pub resolution: u16,
 	/// The multiplier for the raw stored values.
 	pub height_resolution: u16,
+	/// The optional layers stored in every tile.
+	pub layers: Layers,
+	/// The length of the side of each square mini-tile, or 0 if tiles are not split into mini-tiles.
+	pub mini_tile_size: u16,
+}
+
+/// A set of optional layers, stored after the hillshade of each tile in the order of their bits.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct Layers(pub u8);
+
+impl Layers {
+	/// The compass direction that the terrain faces, mapping 0° to 360° onto 0 to 256 (wrapping to 0). Flat terrain
+	/// is 0.
+	pub const ASPECT: Self = Self(1 << 1);
+	/// How much of each pixel is covered by source data, mapping 0% to 100% onto 0 to 255. Lets the edges of tiles
+	/// that are only partly covered, such as at coastlines or the edges of the source, fade out instead of being cut
+	/// off.
+	pub const COVERAGE: Self = Self(1 << 2);
+	pub const NONE: Self = Self(0);
+	/// The slope angle of the terrain, mapping 0° to 90° onto 0 to 255.
+	pub const SLOPE: Self = Self(1 << 0);
+
+	pub fn contains(self, other: Self) -> bool { self.0 & other.0 == other.0 }
+
+	/// The position of the frame of `layer` among the layers present in a tile.
+	pub(crate) fn index_of(self, layer: Self) -> usize { (self.0 & (layer.0 - 1)).count_ones() as usize }
+}
+
+impl BitOr for Layers {
+	type Output = Self;
+
+	fn bitor(self, rhs: Self) -> Self { Self(self.0 | rhs.0) }
+}
+
+/// The codec that the heights of a tile are compressed with, stored before them since format version 11.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Codec {
+	/// Spatial prediction, paletting, and byte-packing. Slow to encode, but compresses well.
+	Hcomp = 0,
+	/// The mapped heights in a plain zstd frame. Much faster to encode, for previews, but larger.
+	Zstd = 1,
+}
+
+impl Codec {
+	pub(crate) fn from_tag(tag: u8) -> Option<Self> {
+		match tag {
+			0 => Some(Self::Hcomp),
+			1 => Some(Self::Zstd),
+			_ => None,
+		}
+	}
+}
+
+/// Where the heights of a dataset came from, stored in the header since format version 13. See
+/// `Dataset::provenance`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Provenance {
+	pub vertical_datum: VerticalDatum,
+	/// When the dataset was generated, in seconds since the Unix epoch, or 0 if unknown.
+	pub generated_at: u64,
+}
+
+impl Provenance {
+	pub(crate) fn from_header(header: &[u8]) -> Self {
+		Self {
+			vertical_datum: VerticalDatum::from_tag(header[14]),
+			generated_at: u64::from_le_bytes(header[15..23].try_into().unwrap()),
+		}
+	}
+
+	/// The bytes of the header from 14 to 32.
+	pub(crate) fn to_header(self) -> [u8; 18] {
+		let mut out = [0; 18];
+		out[0] = self.vertical_datum as u8;
+		out[1..9].copy_from_slice(&self.generated_at.to_le_bytes());
+		out
+	}
+}
+
+/// The surface that heights are measured from. Heights measured from different datums can differ by up to 100m, so
+/// datasets should only be compared or combined if their datums match.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(u8)]
+pub enum VerticalDatum {
+	#[default]
+	Unknown = 0,
+	/// Above the EGM96 geoid, roughly mean sea level. Used by SRTM.
+	Egm96 = 1,
+	/// Above the EGM2008 geoid, roughly mean sea level.
+	Egm2008 = 2,
+	/// Above the WGS84 ellipsoid, like GPS altitudes.
+	Wgs84Ellipsoid = 3,
+}
+
+impl VerticalDatum {
+	/// Datums added by later versions of the library are read as `Unknown`.
+	fn from_tag(tag: u8) -> Self {
+		match tag {
+			1 => Self::Egm96,
+			2 => Self::Egm2008,
+			3 => Self::Wgs84Ellipsoid,
+			_ => Self::Unknown,
+		}
+	}
+}
+
+/// The mean radius of the Earth in meters, which distances on the globe are measured with.
+pub const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// A position on the globe, in degrees.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LatLon {
+	pub lat: f64,
+	pub lon: f64,
+}
+
+impl LatLon {
+	/// The great-circle distance to `other` in meters, on a sphere of `EARTH_RADIUS`.
+	///
+	/// This uses the haversine formula, so it stays accurate for points that are close together.
+	pub fn distance_m(&self, other: &LatLon) -> f64 {
+		let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+		let dlat = lat2 - lat1;
+		let dlon = (other.lon - self.lon).to_radians();
+
+		let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+		2.0 * EARTH_RADIUS * a.sqrt().clamp(0.0, 1.0).asin()
+	}
+
+	/// The initial bearing of the great circle to `other`, in degrees clockwise from true north, in `[0, 360)`.
+	pub fn bearing_deg(&self, other: &LatLon) -> f64 {
+		let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+		let dlon = (other.lon - self.lon).to_radians();
+
+		let y = dlon.sin() * lat2.cos();
+		let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+		// `rem_euclid` rounds tiny negative angles up to exactly 360.
+		let bearing = y.atan2(x).to_degrees().rem_euclid(360.0);
+		if bearing >= 360.0 {
+			0.0
+		} else {
+			bearing
+		}
+	}
 }
 
 pub fn map_lat_lon_to_index(lat: i16, lon: i16) -> usize {
@@ -196,3 +458,117 @@ pub fn map_index_to_lat_lon(index: usize) -> (i16, i16) {
 	let lon = (index % 360) as i16 - 180;
 	(lat, lon)
 }
+
+/// Wrap a longitude in degrees to `[-180, 180)`, so that longitudes in `[0, 360)` or past the antimeridian can be used
+/// to find a tile.
+pub fn normalize_lon(lon: f64) -> f64 {
+	let lon = (lon + 180.0).rem_euclid(360.0) - 180.0;
+	// `rem_euclid` can round up to 360 for tiny negative values.
+	if lon >= 180.0 {
+		-180.0
+	} else {
+		lon
+	}
+}
+
+/// The tile map indices of the tiles that intersect a box in degrees, from south to north and west to east.
+///
+/// The box crosses the antimeridian if `min_lon` is east of `max_lon`, like a box from 170 to -170. Longitudes outside
+/// of `[-180, 180)` are wrapped, latitudes are clamped to the poles, and a box with no area still yields the tile it
+/// is in.
+pub fn indices_in_bounds(min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> impl Iterator<Item = usize> {
+	let lat_start = min_lat.floor().clamp(-90.0, 89.0) as i16;
+	let lat_end = (max_lat.ceil().clamp(-90.0, 90.0) as i16).max(lat_start + 1);
+
+	let (lon_start, lon_count) = if max_lon - min_lon >= 360.0 {
+		(-180, 360)
+	} else {
+		let (min, max) = (normalize_lon(min_lon), normalize_lon(max_lon));
+		let width = if max >= min { max - min } else { max + 360.0 - min };
+		let start = min.floor();
+		let count = ((min + width).ceil() - start).clamp(1.0, 360.0);
+		(start as i16, count as i16)
+	};
+
+	(lat_start..lat_end).flat_map(move |lat| {
+		(0..lon_count).map(move |dlon| {
+			let lon = (lon_start + 180 + dlon).rem_euclid(360) - 180;
+			map_lat_lon_to_index(lat, lon)
+		})
+	})
+}
+
+/// Get the tile `dlat` and `dlon` tiles away from a tile, wrapping around the antimeridian. Returns `None` if the
+/// tile would be past a pole.
+pub fn map_neighbour(lat: i16, lon: i16, dlat: i16, dlon: i16) -> Option<(i16, i16)> {
+	let lat = lat + dlat;
+	if !(-90..90).contains(&lat) {
+		return None;
+	}
+
+	let lon = (lon + 180 + dlon).rem_euclid(360) - 180;
+	Some((lat, lon))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const HEATHROW: LatLon = LatLon {
+		lat: 51.4700,
+		lon: -0.4543,
+	};
+	const KENNEDY: LatLon = LatLon {
+		lat: 40.6413,
+		lon: -73.7781,
+	};
+
+	#[test]
+	fn distance_and_bearing_between_cities() {
+		// About 5555 km on the WGS84 ellipsoid, which the sphere puts about 15 km shorter.
+		let distance = HEATHROW.distance_m(&KENNEDY);
+		assert!((distance - 5_540_000.0).abs() < 5_000.0, "{}", distance);
+		assert_eq!(KENNEDY.distance_m(&HEATHROW), distance);
+
+		let bearing = HEATHROW.bearing_deg(&KENNEDY);
+		assert!((bearing - 288.0).abs() < 0.5, "{}", bearing);
+		let bearing = KENNEDY.bearing_deg(&HEATHROW);
+		assert!((bearing - 51.4).abs() < 0.5, "{}", bearing);
+	}
+
+	#[test]
+	fn distance_and_bearing_across_the_antimeridian() {
+		let sydney = LatLon {
+			lat: -33.9399,
+			lon: 151.1753,
+		};
+		let san_francisco = LatLon {
+			lat: 37.6213,
+			lon: -122.3790,
+		};
+		let distance = sydney.distance_m(&san_francisco);
+		assert!((distance - 11_949_000.0).abs() < 5_000.0, "{}", distance);
+		let bearing = sydney.bearing_deg(&san_francisco);
+		assert!((bearing - 56.0).abs() < 0.5, "{}", bearing);
+
+		// Two degrees of the equator, the short way across the antimeridian.
+		let (west, east) = (LatLon { lat: 0.0, lon: 179.0 }, LatLon { lat: 0.0, lon: -179.0 });
+		let distance = west.distance_m(&east);
+		assert!(
+			(distance - 2.0f64.to_radians() * EARTH_RADIUS).abs() < 1.0,
+			"{}",
+			distance
+		);
+		assert!((west.bearing_deg(&east) - 90.0).abs() < 1e-9);
+		assert!((east.bearing_deg(&west) - 270.0).abs() < 1e-9);
+	}
+
+	#[test]
+	fn bearing_is_below_360() {
+		// Just west of north, which is a tiny negative angle before it is wrapped.
+		let origin = LatLon { lat: 0.0, lon: 0.0 };
+		let bearing = origin.bearing_deg(&LatLon { lat: 1.0, lon: -1e-17 });
+		assert!((0.0..360.0).contains(&bearing), "{}", bearing);
+		assert_eq!(origin.bearing_deg(&origin), 0.0);
+	}
+}
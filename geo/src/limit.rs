@@ -0,0 +1,52 @@
+use std::{
+	num::NonZeroUsize,
+	sync::{Condvar, Mutex},
+};
+
+struct Limit {
+	max: Option<NonZeroUsize>,
+	in_flight: usize,
+}
+
+static LIMIT: Mutex<Limit> = Mutex::new(Limit {
+	max: None,
+	in_flight: 0,
+});
+static RELEASED: Condvar = Condvar::new();
+
+/// Limit how many tiles are decoded at once across every dataset in the process, so that decoding many tiles from
+/// many threads can't run out of memory. Decodes over the limit block until another one finishes. `None`, the
+/// default, doesn't limit them.
+///
+/// A decode holds about `6 * res * res` bytes while it runs, for the heights, the heights multiplied by the height
+/// resolution if it isn't 1, the water mask, and the hillshade, plus the compressed tile if the dataset isn't memory
+/// mapped. The peak memory of decoding is then about `max * 6 * res * res` bytes, or 52 MB for 6 decodes at a
+/// resolution of 1200, regardless of how many tiles are requested. The tiles returned to the caller are not counted.
+///
+/// Blocking isn't supported on `wasm32-unknown-unknown`, so a limit should not be set there.
+pub fn set_max_decodes_in_flight(max: Option<NonZeroUsize>) {
+	LIMIT.lock().unwrap().max = max;
+	// A higher limit may let several waiting decodes through.
+	RELEASED.notify_all();
+}
+
+/// A decode counted against the limit, until it is dropped.
+pub(crate) struct Permit(());
+
+impl Permit {
+	pub(crate) fn acquire() -> Self {
+		let mut limit = LIMIT.lock().unwrap();
+		while limit.max.map_or(false, |max| limit.in_flight >= max.get()) {
+			limit = RELEASED.wait(limit).unwrap();
+		}
+		limit.in_flight += 1;
+		Self(())
+	}
+}
+
+impl Drop for Permit {
+	fn drop(&mut self) {
+		LIMIT.lock().unwrap().in_flight -= 1;
+		RELEASED.notify_one();
+	}
+}
@@ -0,0 +1,82 @@
+//! Conversions between heights in meters and the raw values stored in tiles.
+//!
+//! Tiles store `height + 500` in meters, divided by the height resolution of the dataset and rounded, so that every
+//! height on Earth is a small positive integer. Up to format version 7, a height of -500m marked water. Since then,
+//! water is only signified by the water mask, which `Dataset::get_tile` merges into bit 15 of each height, and -500m
+//! is just the lowest height that can be stored.
+//!
+//! `unmap_height(map_height(h, hres), hres)` is `h` to within `hres / 2` meters, for any `h` from -500m to
+//! `MAX_HEIGHT - 500`m whose raw value `is_out_of_range` accepts. Unless `hres` is 1, the highest of those heights can
+//! round up past `MAX_HEIGHT`, and are rejected by `DatasetBuilder` instead.
+
+use crate::MAX_HEIGHT;
+
+/// Added to heights in meters before they are stored, so that they are positive.
+pub const HEIGHT_OFFSET: i32 = 500;
+
+/// Map a height in meters to the raw value stored in a tile with the height resolution `hres`. Heights below -500m
+/// are clamped to it.
+pub fn map_height(meters: i16, hres: u16) -> u16 { quantize(offset_height(meters), hres) }
+
+/// Map a raw value stored in a tile with the height resolution `hres` back to a height in meters.
+pub fn unmap_height(raw: u16, hres: u16) -> i16 { (dequantize(raw, hres) as i32 - HEIGHT_OFFSET) as i16 }
+
+/// Convert a height in meters to the `height + 500` that `DatasetBuilder::add_tile` takes, clamping heights below
+/// -500m to it.
+pub fn offset_height(meters: i16) -> u16 { (meters as i32 + HEIGHT_OFFSET).max(0) as u16 }
+
+/// Divide a `height + 500` by the height resolution, rounding to the nearest raw value.
+pub fn quantize(offset: u16, hres: u16) -> u16 { (offset as f32 / hres as f32).round() as u16 }
+
+/// Multiply a raw value by the height resolution, giving the `height + 500` that `Dataset::get_tile` returns. Raw
+/// values too large for any height, which only corrupt tiles have, saturate instead of overflowing.
+pub fn dequantize(raw: u16, hres: u16) -> u16 { raw.saturating_mul(hres) }
+
+/// Whether a raw value is too high to be stored with the height resolution `hres`.
+pub fn is_out_of_range(raw: u16, hres: u16) -> bool { raw as u32 * hres as u32 > MAX_HEIGHT as u32 }
+
+/// The minimum and maximum height in meters of raw values.
+pub(crate) fn height_bounds(raw: &[u16], hres: u16) -> (i16, i16) {
+	let min = raw.iter().copied().min().unwrap_or(0);
+	let max = raw.iter().copied().max().unwrap_or(0);
+	(unmap_height(min, hres), unmap_height(max, hres))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn heights_round_trip_within_half_the_resolution() {
+		for hres in [1, 2, 3, 5, 7, 10, 16, 33] {
+			for meters in -500..=MAX_HEIGHT as i16 - 500 {
+				let raw = map_height(meters, hres);
+				if is_out_of_range(raw, hres) {
+					// Only heights that round up past the highest multiple of `hres` are rejected.
+					assert!(
+						offset_height(meters) as u32 + hres as u32 / 2 > MAX_HEIGHT as u32,
+						"{} {}",
+						meters,
+						hres
+					);
+					continue;
+				}
+
+				let error = (unmap_height(raw, hres) as i32 - meters as i32).abs();
+				assert!(
+					error <= hres as i32 / 2,
+					"{}m with hres {} is off by {}",
+					meters,
+					hres,
+					error
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn corrupt_raw_values_saturate() {
+		assert_eq!(dequantize(u16::MAX, 2), u16::MAX);
+		assert_eq!(dequantize(MAX_HEIGHT / 2, 2), MAX_HEIGHT - 1);
+	}
+}
@@ -0,0 +1,250 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::{map_index_to_lat_lon, map_neighbour, mapping::HEIGHT_OFFSET, Dataset, LatLon};
+
+const WATER: u16 = 1 << 15;
+/// The mapped height of the sea, which missing tiles are assumed to be.
+const SEA_LEVEL: u16 = HEIGHT_OFFSET as u16;
+/// The number of decoded tiles to keep around. Floods rarely leave the tiles around the peak.
+const CACHE_SIZE: usize = 64;
+/// Floods that cover more pixels than this without finding higher ground are assumed to be prominent enough.
+const MAX_FLOOD: usize = 1 << 22;
+const NEIGHBOURS: [(i32, i32); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+
+impl Dataset {
+	/// Find the peaks with a prominence of at least `min_prominence_m` meters, returning their position and height in
+	/// meters.
+	///
+	/// A peak is a dry pixel that is higher than all 8 of its neighbours, including those in neighbouring tiles. Its
+	/// prominence is estimated by flooding outwards from it, highest pixels first, until either higher ground or a
+	/// deep enough col is found. Missing tiles and tiles that fail to decode are treated as sea.
+	///
+	/// This decodes every tile in the dataset, so it is slow.
+	pub fn peaks(&self, min_prominence_m: f32) -> Vec<(LatLon, f32)> {
+		zone!("Find peaks");
+
+		let mut tiles = Tiles {
+			dataset: self,
+			res: self.metadata.resolution,
+			cache: HashMap::new(),
+		};
+		let res = tiles.res as usize;
+
+		let mut peaks = Vec::new();
+		for index in 0..360 * 180 {
+			let (lat, lon) = map_index_to_lat_lon(index);
+			let tile = match tiles.tile(lat, lon) {
+				Some(x) => x.to_vec(),
+				None => continue,
+			};
+
+			for y in 0..res {
+				for x in 0..res {
+					let height = tile[y * res + x];
+					if height & WATER != 0 {
+						continue;
+					}
+
+					let pixel = Pixel {
+						lat,
+						lon,
+						x: x as u16,
+						y: y as u16,
+					};
+					let interior = x > 0 && y > 0 && x < res - 1 && y < res - 1;
+					let is_peak = NEIGHBOURS.iter().all(|&(dx, dy)| {
+						let neighbour = if interior {
+							tile[(y as i32 + dy) as usize * res + (x as i32 + dx) as usize] & !WATER
+						} else if let Some(neighbour) = tiles.step(pixel, dx, dy) {
+							tiles.height(neighbour)
+						} else {
+							return true;
+						};
+						neighbour < height
+					});
+
+					if is_peak && tiles.is_prominent(pixel, height, min_prominence_m) {
+						let position = LatLon {
+							lat: lat as f64 + 1.0 - (y as f64 + 0.5) / res as f64,
+							lon: lon as f64 + (x as f64 + 0.5) / res as f64,
+						};
+						peaks.push((position, (height as i32 - HEIGHT_OFFSET) as f32));
+					}
+				}
+			}
+		}
+
+		peaks
+	}
+}
+
+/// A pixel in a tile. Rows go from north to south.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Pixel {
+	lat: i16,
+	lon: i16,
+	x: u16,
+	y: u16,
+}
+
+struct Tiles<'a> {
+	dataset: &'a Dataset,
+	res: u16,
+	cache: HashMap<(i16, i16), Option<Vec<u16>>>,
+}
+
+impl Tiles<'_> {
+	fn tile(&mut self, lat: i16, lon: i16) -> Option<&[u16]> {
+		if !self.cache.contains_key(&(lat, lon)) {
+			if self.cache.len() >= CACHE_SIZE {
+				self.cache.clear();
+			}
+
+			let tile = self.dataset.get_tile(lat, lon).and_then(|x| x.ok()).map(|x| x.0);
+			self.cache.insert((lat, lon), tile);
+		}
+
+		self.cache[&(lat, lon)].as_deref()
+	}
+
+	/// The mapped height of a pixel, without the water flag.
+	fn height(&mut self, pixel: Pixel) -> u16 {
+		let res = self.res as usize;
+		self.tile(pixel.lat, pixel.lon)
+			.map_or(SEA_LEVEL, |x| x[pixel.y as usize * res + pixel.x as usize] & !WATER)
+	}
+
+	/// Step from a pixel, crossing into neighbouring tiles if required.
+	fn step(&self, pixel: Pixel, dx: i32, dy: i32) -> Option<Pixel> {
+		let res = self.res as i32;
+		let x = pixel.x as i32 + dx;
+		let y = pixel.y as i32 + dy;
+
+		// Rows go south, so moving past the last row moves to the tile below.
+		let dlat = -y.div_euclid(res) as i16;
+		let dlon = x.div_euclid(res) as i16;
+		let (lat, lon) = map_neighbour(pixel.lat, pixel.lon, dlat, dlon)?;
+		Some(Pixel {
+			lat,
+			lon,
+			x: x.rem_euclid(res) as u16,
+			y: y.rem_euclid(res) as u16,
+		})
+	}
+
+	fn is_prominent(&mut self, peak: Pixel, height: u16, min_prominence_m: f32) -> bool {
+		let mut queue = BinaryHeap::new();
+		let mut visited = HashSet::new();
+		queue.push((height, peak));
+		visited.insert(peak);
+
+		let mut col = height;
+		while let Some((h, pixel)) = queue.pop() {
+			if h > height {
+				break;
+			}
+
+			col = col.min(h);
+			if (height - col) as f32 >= min_prominence_m || visited.len() > MAX_FLOOD {
+				return true;
+			}
+
+			for &(dx, dy) in NEIGHBOURS.iter() {
+				if let Some(neighbour) = self.step(pixel, dx, dy) {
+					if visited.insert(neighbour) {
+						queue.push((self.height(neighbour), neighbour));
+					}
+				}
+			}
+		}
+
+		(height - col) as f32 >= min_prominence_m
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{DatasetBuilder, Layers, TileMetadata, FORMAT_VERSION};
+
+	const RES: usize = 16;
+
+	/// A tile of land 100m high, with the given pixels raised to their height in meters.
+	fn tile(lat: i16, lon: i16, raised: &[(usize, usize, i32)]) -> (i16, i16, Vec<u16>) {
+		let mut heights = vec![(HEIGHT_OFFSET + 100) as u16; RES * RES];
+		for &(x, y, height) in raised {
+			heights[y * RES + x] = (HEIGHT_OFFSET + height) as u16;
+		}
+		(lat, lon, heights)
+	}
+
+	/// Build a dataset of the given tiles and read it back into memory.
+	fn dataset(name: &str, tiles: Vec<(i16, i16, Vec<u16>)>) -> Dataset {
+		let path = std::env::temp_dir().join(format!("geo-test-peaks-{}-{}.geo", name, std::process::id()));
+		let metadata = TileMetadata {
+			version: FORMAT_VERSION,
+			resolution: RES as _,
+			height_resolution: 1,
+			layers: Layers::NONE,
+			mini_tile_size: 0,
+		};
+		let builder = DatasetBuilder::new(&path, metadata).unwrap();
+		for (lat, lon, heights) in tiles {
+			builder
+				.add_tile(lat, lon, heights, vec![0; RES * RES], vec![0; RES * RES])
+				.unwrap();
+		}
+		builder.finish().unwrap();
+
+		let bytes = std::fs::read(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		Dataset::from_bytes(bytes.into()).unwrap()
+	}
+
+	/// The tile and pixel of each peak, along with its height.
+	fn pixels(peaks: Vec<(LatLon, f32)>) -> Vec<(i16, i16, usize, usize, f32)> {
+		let mut pixels: Vec<_> = peaks
+			.into_iter()
+			.map(|(position, height)| {
+				let (lat, lon) = (position.lat.floor(), position.lon.floor());
+				let x = ((position.lon - lon) * RES as f64) as usize;
+				let y = ((lat + 1.0 - position.lat) * RES as f64) as usize;
+				(lat as i16, lon as i16, x, y, height)
+			})
+			.collect();
+		pixels.sort_by(|a, b| a.partial_cmp(b).unwrap());
+		pixels
+	}
+
+	#[test]
+	fn a_peak_is_found_only_if_it_is_prominent_enough() {
+		// The lower peak is 100m above the plateau that connects it to the higher one.
+		let dataset = dataset("threshold", vec![tile(0, 0, &[(3, 3, 200), (12, 12, 300)])]);
+
+		assert_eq!(
+			pixels(dataset.peaks(99.0)),
+			[(0, 0, 3, 3, 200.0), (0, 0, 12, 12, 300.0)]
+		);
+		assert_eq!(pixels(dataset.peaks(101.0)), [(0, 0, 12, 12, 300.0)]);
+	}
+
+	#[test]
+	fn a_higher_pixel_in_the_next_tile_is_not_a_peak() {
+		let dataset = dataset(
+			"neighbour",
+			vec![tile(0, 0, &[(RES - 1, 8, 200)]), tile(0, 1, &[(0, 8, 300)])],
+		);
+
+		assert_eq!(pixels(dataset.peaks(50.0)), [(0, 1, 0, 8, 300.0)]);
+	}
+
+	#[test]
+	fn neighbours_are_found_across_the_antimeridian() {
+		let dataset = dataset(
+			"antimeridian",
+			vec![tile(0, 179, &[(RES - 1, 8, 200)]), tile(0, -180, &[(0, 8, 300)])],
+		);
+
+		assert_eq!(pixels(dataset.peaks(50.0)), [(0, -180, 0, 8, 300.0)]);
+	}
+}
@@ -0,0 +1,185 @@
+//! The webp frames of tiles, encoded and decoded with libwebp or, with the `pure-rust-webp` feature, the `image` crate.
+
+#[cfg(not(any(feature = "libwebp", feature = "pure-rust-webp")))]
+compile_error!("Either the `libwebp` or the `pure-rust-webp` feature must be enabled");
+
+/// Decode a lossless webp frame of `width * height` `u8`s, which are stored as RGBA pixels of half the width and
+/// height.
+pub(crate) fn decode(frame: &[u8], width: u32, height: u32) -> Result<Vec<u8>, std::io::Error> {
+	let mut decompressed = Vec::new();
+	decode_into(frame, width, height, &mut decompressed)?;
+	Ok(decompressed)
+}
+
+#[cfg(not(feature = "pure-rust-webp"))]
+pub(crate) use libwebp::{decode_into, encode};
+#[cfg(feature = "pure-rust-webp")]
+pub(crate) use pure::{decode_into, encode};
+
+// Both are built if both features are enabled, so that they can be tested against each other, but only the `image`
+// crate is used then.
+#[cfg(feature = "libwebp")]
+#[cfg_attr(feature = "pure-rust-webp", allow(dead_code))]
+mod libwebp {
+	/// Like `decode`, but replaces the contents of `out`, which only allocates if it is too small.
+	pub(crate) fn decode_into(frame: &[u8], width: u32, height: u32, out: &mut Vec<u8>) -> Result<(), std::io::Error> {
+		use libwebp_sys::WebPDecodeRGBAInto;
+
+		out.clear();
+		out.resize(width as usize * height as usize, 0);
+		unsafe {
+			if WebPDecodeRGBAInto(
+				frame.as_ptr(),
+				frame.len(),
+				out.as_mut_ptr(),
+				out.len(),
+				width as i32 * 2,
+			)
+			.is_null()
+			{
+				return Err(std::io::Error::new(
+					std::io::ErrorKind::Other,
+					"WebPDecodeRGBAInto failed",
+				));
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Encode `width * height` `u8`s as a lossless webp frame of RGBA pixels of half the width and height.
+	pub(crate) fn encode(data: &[u8], width: u32, height: u32) -> Result<Vec<u8>, std::io::Error> {
+		use libwebp_sys::{
+			WebPEncode,
+			WebPImageHint::WEBP_HINT_GRAPH,
+			WebPInitConfig,
+			WebPPicture,
+			WebPPictureImportRGBA,
+			WebPPictureInit,
+		};
+
+		unsafe {
+			let mut temp = Vec::new();
+
+			let mut config = std::mem::zeroed();
+			WebPInitConfig(&mut config);
+			config.lossless = 1;
+			config.quality = 100.0;
+			config.method = 3;
+			config.image_hint = WEBP_HINT_GRAPH;
+			config.exact = 1;
+
+			let mut picture = std::mem::zeroed();
+			WebPPictureInit(&mut picture);
+			picture.use_argb = 1;
+			picture.writer = Some(write);
+			picture.custom_ptr = &mut temp as *mut _ as _;
+			picture.width = width as i32 / 2;
+			picture.height = height as i32 / 2;
+
+			WebPPictureImportRGBA(&mut picture, data.as_ptr() as _, width as i32 * 2);
+
+			WebPEncode(&config, &mut picture);
+
+			if picture.error_code as i32 != 0 {
+				return Err(std::io::Error::new(
+					std::io::ErrorKind::Other,
+					format!("WebPEncode failed: {}", picture.error_code as i32),
+				));
+			}
+
+			unsafe extern "C" fn write(data: *const u8, data_size: usize, picture: *const WebPPicture) -> i32 {
+				let vec = &mut *((*picture).custom_ptr as *mut Vec<u8>);
+				vec.extend_from_slice(std::slice::from_raw_parts(data, data_size));
+
+				1
+			}
+
+			Ok(temp)
+		}
+	}
+}
+
+#[cfg(feature = "pure-rust-webp")]
+mod pure {
+	/// Like `decode`, but replaces the contents of `out`. The `image` crate always decodes into a new buffer, so this
+	/// still allocates.
+	pub(crate) fn decode_into(frame: &[u8], width: u32, height: u32, out: &mut Vec<u8>) -> Result<(), std::io::Error> {
+		use image::{codecs::webp::WebPDecoder, DynamicImage, ImageError};
+
+		let invalid = |e: ImageError| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+		let decoder = WebPDecoder::new(std::io::Cursor::new(frame)).map_err(invalid)?;
+		// Lossless frames without alpha can decode to RGB, so always convert to RGBA.
+		let decompressed = DynamicImage::from_decoder(decoder)
+			.map_err(invalid)?
+			.into_rgba8()
+			.into_raw();
+		if decompressed.len() != width as usize * height as usize {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				"Webp frame has the wrong size",
+			));
+		}
+
+		*out = decompressed;
+		Ok(())
+	}
+
+	/// Encode `width * height` `u8`s as a lossless webp frame of RGBA pixels of half the width and height.
+	///
+	/// This usually compresses worse than libwebp, but both are lossless, so the frames decode the same with either.
+	pub(crate) fn encode(data: &[u8], width: u32, height: u32) -> Result<Vec<u8>, std::io::Error> {
+		use image::{codecs::webp::WebPEncoder, ColorType};
+
+		let mut out = Vec::new();
+		WebPEncoder::new_lossless(&mut out)
+			.encode(data, width / 2, height / 2, ColorType::Rgba8)
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+		Ok(out)
+	}
+}
+
+/// Run with `--features pure-rust-webp`, so that both implementations are built.
+#[cfg(all(test, feature = "libwebp", feature = "pure-rust-webp"))]
+mod tests {
+	use super::*;
+
+	/// A tile of terrain-like bytes, that varies smoothly with some noise, so it doesn't compress to nothing.
+	fn tile(res: u32) -> Vec<u8> {
+		let mut state = 0x2545_f491u32;
+		(0..res * res)
+			.map(|i| {
+				state ^= state << 13;
+				state ^= state >> 17;
+				state ^= state << 5;
+				let (x, y) = (i % res, i / res);
+				((x * 3 + y * 5) / 4 + (state >> 29)) as u8
+			})
+			.collect()
+	}
+
+	/// Tiles are stored as RGBA pixels of half their size, so 34 gives frames of an odd 17 by 17 pixels.
+	const SIZES: [u32; 2] = [34, 1200];
+
+	#[test]
+	fn libwebp_frames_decode_with_image() {
+		for res in SIZES {
+			let data = tile(res);
+			let frame = libwebp::encode(&data, res, res).unwrap();
+			let mut out = Vec::new();
+			pure::decode_into(&frame, res, res, &mut out).unwrap();
+			assert!(out == data, "{} by {} tile decoded differently", res, res);
+		}
+	}
+
+	#[test]
+	fn image_frames_decode_with_libwebp() {
+		for res in SIZES {
+			let data = tile(res);
+			let frame = pure::encode(&data, res, res).unwrap();
+			let mut out = Vec::new();
+			libwebp::decode_into(&frame, res, res, &mut out).unwrap();
+			assert!(out == data, "{} by {} tile decoded differently", res, res);
+		}
+	}
+}
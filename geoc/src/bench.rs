@@ -0,0 +1,78 @@
+use std::{
+	io::Write,
+	path::PathBuf,
+	time::{Duration, Instant},
+};
+
+use clap::Args;
+use geo::{Access, Dataset};
+
+use crate::common::Size;
+
+#[derive(Args)]
+/// Measure how fast the tiles of a dataset decode, on a single thread.
+pub struct Bench {
+	input: PathBuf,
+	/// Decode the dataset this many times, reporting the latencies over all of them.
+	#[clap(short = 'n', long = "iterations", default_value_t = 1)]
+	iterations: usize,
+}
+
+pub fn bench(bench: Bench) {
+	let dataset = match Dataset::load_with_advice(&bench.input, Access::Sequential) {
+		Ok(x) => x,
+		Err(err) => {
+			eprintln!("dataset could not be loaded: {}", err);
+			return;
+		},
+	};
+
+	// Reading in the order the tiles are stored keeps the reads sequential.
+	let tiles = dataset.tile_sizes();
+	if tiles.is_empty() {
+		println!("No tiles to decode");
+		return;
+	}
+	let compressed: usize = tiles.iter().map(|&(.., size)| size as usize).sum();
+
+	let iterations = bench.iterations.max(1);
+	let mut latencies = Vec::with_capacity(tiles.len() * iterations);
+	for iteration in 0..iterations {
+		for (i, &(lat, lon, _)) in tiles.iter().enumerate() {
+			let tile_start = Instant::now();
+			if let Some(Err(e)) = dataset.get_full_tile(lat, lon) {
+				println!("\nError in tile {}, {}: {}", lat, lon, e);
+				return;
+			}
+			latencies.push(tile_start.elapsed());
+
+			print!("\r{}/{}", iteration * tiles.len() + i + 1, tiles.len() * iterations);
+			let _ = std::io::stdout().flush();
+		}
+	}
+	// Only the time spent decoding counts, not printing the progress between tiles.
+	let elapsed: Duration = latencies.iter().sum();
+	latencies.sort_unstable();
+
+	let decoded = latencies.len();
+	let seconds = elapsed.as_secs_f64();
+	let percentile = |p: usize| latencies[(decoded - 1) * p / 100];
+	let mean = elapsed / decoded as u32;
+
+	println!("\n");
+	println!("Throughput");
+	println!("  Tiles decoded: {} in {:.1?}", decoded, elapsed);
+	println!("  Tiles/s: {:.2}", decoded as f64 / seconds);
+	println!(
+		"  Compressed input: {}/s",
+		Size((compressed as f64 * iterations as f64 / seconds) as usize)
+	);
+
+	println!();
+
+	println!("Latency");
+	println!("  Mean: {:.2?}", mean);
+	println!("  p50: {:.2?}", percentile(50));
+	println!("  p99: {:.2?}", percentile(99));
+	println!("  Max: {:.2?}", latencies.last().copied().unwrap_or(Duration::ZERO));
+}
@@ -0,0 +1,75 @@
+use std::{
+	io::{BufWriter, Write},
+	path::PathBuf,
+};
+
+use clap::{ArgEnum, Args};
+use geo::{mapping::HEIGHT_OFFSET, Dataset};
+
+#[derive(ArgEnum, Copy, Clone)]
+pub enum CatFormat {
+	/// Little-endian `i16`s, row by row.
+	Raw,
+	/// One line of comma-separated heights per row.
+	Csv,
+}
+
+#[derive(Args)]
+/// Write the heights of a tile to stdout, in meters, north row first.
+pub struct Cat {
+	input: PathBuf,
+	/// The latitude and longitude of the south-west corner of the tile, like `-34,151`.
+	#[clap(long = "tile", parse(try_from_str = parse_tile), allow_hyphen_values = true)]
+	tile: (i16, i16),
+	#[clap(long = "format", arg_enum, default_value = "raw")]
+	format: CatFormat,
+}
+
+fn parse_tile(s: &str) -> Result<(i16, i16), String> {
+	let (lat, lon) = s.split_once(',').ok_or("expected `lat,lon`")?;
+	let lat = lat.trim().parse().map_err(|e| format!("invalid latitude: {}", e))?;
+	let lon = lon.trim().parse().map_err(|e| format!("invalid longitude: {}", e))?;
+	Ok((lat, lon))
+}
+
+/// Exits with an error if the tile can't be written, so that a pipeline doesn't carry on with nothing.
+pub fn cat(cat: Cat) {
+	let fail = |message: String| -> ! {
+		eprintln!("{}", message);
+		std::process::exit(1);
+	};
+
+	let dataset = Dataset::load(&cat.input).unwrap_or_else(|e| fail(format!("dataset could not be loaded: {}", e)));
+	let (lat, lon) = cat.tile;
+	if !(-90..90).contains(&lat) || !(-180..180).contains(&lon) {
+		fail(format!("{}, {} is not a tile", lat, lon));
+	}
+
+	let heights = match dataset.get_full_tile(lat, lon) {
+		Some(Ok((heights, ..))) => heights,
+		Some(Err(e)) => fail(format!("Error decoding tile {}, {}: {}", lat, lon, e)),
+		None => fail(format!("Tile {}, {} is not in the dataset", lat, lon)),
+	};
+	let res = dataset.metadata().resolution as usize;
+	let meters = heights.iter().map(|&x| (x as i32 - HEIGHT_OFFSET) as i16);
+
+	let stdout = std::io::stdout();
+	let mut out = BufWriter::new(stdout.lock());
+	let written = match cat.format {
+		CatFormat::Raw => meters
+			.flat_map(i16::to_le_bytes)
+			.collect::<Vec<_>>()
+			.chunks(res * 2)
+			.try_for_each(|row| out.write_all(row)),
+		CatFormat::Csv => meters.collect::<Vec<_>>().chunks(res).try_for_each(|row| {
+			let row: Vec<_> = row.iter().map(|x| x.to_string()).collect();
+			writeln!(out, "{}", row.join(","))
+		}),
+	};
+
+	// A closed pipe means the reader has all it wanted.
+	match written.and_then(|_| out.flush()) {
+		Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => fail(format!("Error writing tile: {}", e)),
+		_ => {},
+	}
+}
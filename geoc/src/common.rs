@@ -1,70 +1,293 @@
 use std::{
 	error::Error,
+	fmt::Display,
 	io::Write,
 	path::Path,
 	sync::{
 		atomic::{AtomicBool, AtomicUsize, Ordering},
 		Arc,
+		Mutex,
+		Once,
 	},
-	time::Duration,
+	time::{Duration, Instant, SystemTime},
 };
 
-use geo::{map_index_to_lat_lon, Dataset, DatasetBuilder, TileMetadata};
+use clap::{ArgEnum, Args};
+use geo::{map_index_to_lat_lon, Codec, DatasetBuilder, DryRunEstimate, Provenance, TileMetadata, VerticalDatum};
 use rayon::prelude::*;
 
+#[derive(ArgEnum, Copy, Clone)]
+pub enum Resample {
+	Nearest,
+	Triangle,
+	CatmullRom,
+	Lanczos3,
+}
+
+impl Resample {
+	pub fn filter(self) -> resize::Type {
+		match self {
+			Self::Nearest => resize::Type::Point,
+			Self::Triangle => resize::Type::Triangle,
+			Self::CatmullRom => resize::Type::Catrom,
+			Self::Lanczos3 => resize::Type::Lanczos3,
+		}
+	}
+
+	#[cfg(feature = "generate")]
+	pub fn gdal(self) -> gdal::raster::ResampleAlg {
+		match self {
+			Self::Nearest => gdal::raster::ResampleAlg::NearestNeighbour,
+			Self::Triangle => gdal::raster::ResampleAlg::Bilinear,
+			Self::CatmullRom => gdal::raster::ResampleAlg::Cubic,
+			Self::Lanczos3 => gdal::raster::ResampleAlg::Lanczos,
+		}
+	}
+}
+
+#[derive(ArgEnum, Copy, Clone, PartialEq, Eq)]
+pub enum HeightCodec {
+	Hcomp,
+	Zstd,
+}
+
+impl HeightCodec {
+	pub fn codec(self) -> Codec {
+		match self {
+			Self::Hcomp => Codec::Hcomp,
+			Self::Zstd => Codec::Zstd,
+		}
+	}
+}
+
+#[derive(ArgEnum, Copy, Clone, PartialEq, Eq)]
+pub enum Datum {
+	Unknown,
+	Egm96,
+	Egm2008,
+	Wgs84,
+}
+
+impl Datum {
+	pub fn vertical_datum(self) -> VerticalDatum {
+		match self {
+			Self::Unknown => VerticalDatum::Unknown,
+			Self::Egm96 => VerticalDatum::Egm96,
+			Self::Egm2008 => VerticalDatum::Egm2008,
+			Self::Wgs84 => VerticalDatum::Wgs84Ellipsoid,
+		}
+	}
+}
+
+/// The provenance of a dataset generated now, with heights above `vertical_datum`.
+pub fn provenance_now(vertical_datum: VerticalDatum) -> Provenance {
+	Provenance {
+		vertical_datum,
+		generated_at: SystemTime::now()
+			.duration_since(SystemTime::UNIX_EPOCH)
+			.map_or(0, |x| x.as_secs()),
+	}
+}
+
+/// Parse `--hres`, which must be at least 1, since stored heights are multiplied by it.
+pub fn parse_height_resolution(s: &str) -> Result<u16, String> {
+	match s.parse() {
+		Ok(0) => Err("the height resolution must be at least 1".into()),
+		Ok(x) => Ok(x),
+		Err(e) => Err(format!("invalid height resolution: {}", e)),
+	}
+}
+
+pub struct Size(pub usize);
+
+impl Display for Size {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		let size = self.0;
+		if size < 1000 {
+			write!(f, "{} B", size)
+		} else if size < 1000 * 1000 {
+			write!(f, "{:.2} KB", size as f64 / 1000.0)
+		} else if size < 1000 * 1000 * 1000 {
+			write!(f, "{:.2} MiB", size as f64 / 1000.0 / 1000.0)
+		} else {
+			write!(f, "{:.2} GiB", size as f64 / 1000.0 / 1000.0 / 1000.0)
+		}
+	}
+}
+
+#[derive(Args, Clone)]
+pub struct OutputOptions {
+	/// The number of tiles to process in parallel. Defaults to the number of cores. Each thread opens its own handle
+	/// to every source dataset, so memory usage grows with the number of jobs.
+	#[clap(short = 'j', long = "jobs")]
+	jobs: Option<usize>,
+	/// How often progress is saved to disk, in seconds.
+	#[clap(long = "flush-interval", default_value_t = 10)]
+	flush_interval: u64,
+	/// Also save progress every time this many tiles have been processed.
+	#[clap(long = "flush-every")]
+	flush_every: Option<usize>,
+	/// Write tiles in row-major order, so that the same input always produces the same file. Tiles are processed in
+	/// small batches instead of all at once, which leaves threads idle at the end of each batch. Resuming an earlier
+	/// run still appends the remaining tiles after the ones already written.
+	#[clap(long = "deterministic")]
+	deterministic: bool,
+	/// Print statistics about the heights of the encoded tiles, such as how many had few enough distinct values for a
+	/// palette. The codec does not report how it encoded each tile, so these are estimates made from the heights.
+	#[clap(long = "encode-stats")]
+	encode_stats: bool,
+	/// The codec that heights are compressed with. `zstd` is much faster to encode but compresses worse, for quick
+	/// previews.
+	#[clap(long = "codec", arg_enum, default_value = "hcomp")]
+	codec: HeightCodec,
+	/// Process every tile without writing the output, and print how many tiles it would have and roughly how large it
+	/// would be. Only some of the tiles are compressed to estimate the size. The estimate is for the whole output,
+	/// even if an earlier run was interrupted.
+	#[clap(long = "dry-run")]
+	dry_run: bool,
+	/// Other height resolutions that a dry run also estimates the size with.
+	#[clap(skip)]
+	compare_height_resolutions: Vec<u16>,
+}
+
+impl OutputOptions {
+	pub fn codec(&self) -> Codec { self.codec.codec() }
+
+	/// The same options, but for a dry run that also estimates the size with each of `compare_height_resolutions`.
+	pub fn as_dry_run(&self, compare_height_resolutions: &[u16]) -> Self {
+		Self {
+			deterministic: false,
+			dry_run: true,
+			compare_height_resolutions: compare_height_resolutions.to_vec(),
+			..self.clone()
+		}
+	}
+}
+
+/// Every this many tiles is compressed in a dry run, to estimate the size of the rest.
+const DRY_RUN_SAMPLE_EVERY: usize = 16;
+
+/// A flag that stops a `for_tile_in_output` run once it is set.
+pub type CancellationToken = Arc<AtomicBool>;
+
+/// Ways for an embedder to observe and control a `for_tile_in_output` run.
+#[derive(Default)]
+pub struct Hooks<'a> {
+	/// Called with the number of processed tiles and the total number of tiles. If not given, progress is printed to
+	/// stdout.
+	pub progress: Option<&'a (dyn Fn(usize, usize) + Sync)>,
+	/// Stop processing tiles once this is set, like the first Ctrl + C. Tiles that are already being processed are
+	/// finished, and the output is saved so that the run can be resumed.
+	pub cancel: Option<CancellationToken>,
+}
+
+/// The quit flag of the current `for_tile_in_output` run. A Ctrl + C handler can only be set once per process, so it
+/// finds the run to stop here.
+static CURRENT_RUN: Mutex<Option<Arc<AtomicBool>>> = Mutex::new(None);
+
+/// Returns the estimates of a dry run that finished, for the height resolution in `metadata` followed by the ones the
+/// dry run compares it with, and `None` otherwise.
 pub fn for_tile_in_output(
-	output: &Path, metadata: TileMetadata,
+	output: &Path, metadata: TileMetadata, provenance: Provenance, options: &OutputOptions, hooks: Hooks,
 	exec: impl Fn(i16, i16, &DatasetBuilder) -> Result<(), Box<dyn Error>> + Sync,
-) {
+) -> Option<Vec<(u16, DryRunEstimate)>> {
 	let was_quit = Arc::new(AtomicBool::new(false));
-	let handler_used = was_quit.clone();
+	*CURRENT_RUN.lock().unwrap() = Some(was_quit.clone());
 	let was_quit = &was_quit;
 
-	let _ = ctrlc::set_handler(move || {
-		if handler_used.load(Ordering::Acquire) {
-			std::process::exit(1);
-		}
+	static HANDLER: Once = Once::new();
+	HANDLER.call_once(|| {
+		let _ = ctrlc::set_handler(|| {
+			let handler_used = match CURRENT_RUN.lock().unwrap().clone() {
+				Some(x) => x,
+				None => std::process::exit(1),
+			};
+			if handler_used.load(Ordering::Acquire) {
+				std::process::exit(1);
+			}
 
-		println!("\nFinishing up, press Ctrl + C again to exit immediately (will result in some data loss)");
-		handler_used.store(true, Ordering::Release);
+			println!("\nFinishing up, press Ctrl + C again to exit immediately (will result in some data loss)");
+			handler_used.store(true, Ordering::Release);
+		});
 	});
 
 	fn make_builder(path: &Path, metadata: TileMetadata) -> Result<DatasetBuilder, std::io::Error> {
-		if let Ok(x) = Dataset::load(path) {
-			if metadata == x.metadata() {
+		match DatasetBuilder::open_append(path) {
+			Ok(x) if x.metadata() == metadata => {
 				println!("Continuing from last execution");
-				return DatasetBuilder::from_dataset(&path, x);
-			}
+				Ok(x)
+			},
+			_ => DatasetBuilder::new(path, metadata),
 		}
-		DatasetBuilder::new(&path, metadata)
 	}
 
-	let builder = match make_builder(&output, metadata) {
+	let pool = match rayon::ThreadPoolBuilder::new()
+		.num_threads(options.jobs.unwrap_or(0))
+		.build()
+	{
+		Ok(x) => x,
+		Err(e) => {
+			eprintln!("{}", e);
+			return None;
+		},
+	};
+
+	let builder = if options.dry_run {
+		Ok(DatasetBuilder::dry_run(metadata, DRY_RUN_SAMPLE_EVERY))
+	} else {
+		make_builder(&output, metadata)
+	};
+	let mut builder = match builder {
 		Ok(x) => x,
 		Err(e) => {
 			eprintln!("{}", e);
-			return;
+			return None;
 		},
 	};
+	builder.set_ordered(options.deterministic);
+	builder.set_collect_stats(options.encode_stats);
+	builder.set_codec(options.codec());
+	builder.set_provenance(provenance);
+	builder.set_dry_run_height_resolutions(&options.compare_height_resolutions);
 	let rbuilder = &builder;
 
+	let start = Instant::now();
+	let start_tiles = builder.tile_count();
+	let start_size = file_size(output);
+
+	let print_progress = |done: usize, total: usize| {
+		print!("\r{}/{}", done, total);
+		let _ = std::io::stdout().flush();
+	};
+	let progress: &(dyn Fn(usize, usize) + Sync) = match hooks.progress {
+		Some(x) => x,
+		None => &print_progress,
+	};
+
+	let cancel = hooks.cancel;
+	let cancelled = || cancel.as_ref().map_or(false, |x| x.load(Ordering::Acquire));
+
 	let tiles = 360 * 180;
-	let counter = AtomicUsize::new(1);
+	let counter = AtomicUsize::new(0);
 	let had_error = AtomicBool::new(false);
 	let had_error = &had_error;
 
-	let _ = crossbeam::scope(move |scope| {
+	let interrupted = crossbeam::scope(move |scope| {
 		scope.spawn(move |_| {
+			let interval = Duration::from_secs(options.flush_interval);
+			let mut last_flush = Instant::now();
 			while !was_quit.load(Ordering::Acquire) {
-				std::thread::sleep(Duration::from_secs(10));
-				let _ = rbuilder.flush();
+				std::thread::sleep(Duration::from_millis(100));
+				if last_flush.elapsed() >= interval {
+					let _ = rbuilder.flush();
+					last_flush = Instant::now();
+				}
 			}
 		});
 
-		print!("\r{}/{}", counter.load(Ordering::Relaxed), tiles);
-		(0..tiles).into_par_iter().for_each(|index| {
-			tracy::zone!("Process tile");
-			if was_quit.load(Ordering::Acquire) {
+		let process = |index: usize| {
+			zone!("Process tile");
+			if was_quit.load(Ordering::Acquire) || cancelled() {
 				return;
 			}
 
@@ -79,17 +302,125 @@ pub fn for_tile_in_output(
 				}
 			}
 
-			print!("\r{}/{}", counter.fetch_add(1, Ordering::Relaxed), tiles);
-			let _ = std::io::stdout().flush();
-		});
+			let done = counter.fetch_add(1, Ordering::Relaxed) + 1;
+			if options.flush_every.map_or(false, |n| n != 0 && done % n == 0) {
+				let _ = rbuilder.flush();
+			}
+			progress(done, tiles);
+		};
 
-		was_quit.store(true, Ordering::Release);
-	});
+		progress(0, tiles);
+		pool.install(|| {
+			if !options.deterministic {
+				(0..tiles).into_par_iter().for_each(&process);
+				return;
+			}
+
+			// Only a few tiles per thread are held in memory before they are written in order.
+			let batch = rayon::current_num_threads() * 4;
+			for start in (0..tiles).step_by(batch) {
+				if was_quit.load(Ordering::Acquire) || cancelled() {
+					break;
+				}
 
-	(!had_error.load(Ordering::Relaxed))
-		.then(|| builder.finish())
-		.map(|x| match x {
-			Ok(_) => {},
-			Err(e) => println!("Error saving output: {}", e),
+				(start..(start + batch).min(tiles)).into_par_iter().for_each(&process);
+				if let Err(e) = rbuilder.write_ordered() {
+					println!("\nError writing tiles: {}", e);
+					had_error.store(true, Ordering::Release);
+					break;
+				}
+			}
 		});
+
+		// Ctrl + C sets the flag before all the tiles are done.
+		was_quit.swap(true, Ordering::AcqRel) || cancelled()
+	})
+	.unwrap_or(true);
+
+	if had_error.load(Ordering::Relaxed) {
+		return None;
+	}
+
+	if let Some(estimate) = builder.dry_run_estimate() {
+		println!();
+		println!("Dry run{}", if interrupted { " (interrupted)" } else { "" });
+		println!("  Tiles: {}", estimate.tiles);
+		println!("  Tiles compressed for the estimate: {}", estimate.sampled_tiles);
+		println!("  Estimated size: {}", Size(estimate.file_size() as usize));
+		let estimates = builder.dry_run_estimates();
+		for &(height_resolution, estimate) in estimates.iter().skip(1) {
+			println!(
+				"  Estimated size with a height resolution of {}: {}",
+				height_resolution,
+				Size(estimate.file_size() as usize)
+			);
+		}
+		return (!interrupted).then(|| estimates);
+	}
+
+	let tiles_written = builder.tile_count() - start_tiles;
+	let stats = builder.encode_stats();
+	if let Err(e) = builder.finish() {
+		println!("Error saving output: {}", e);
+		return None;
+	}
+
+	if !interrupted {
+		let elapsed = start.elapsed();
+		let bytes_written = file_size(output).saturating_sub(start_size) as usize;
+
+		println!();
+		println!("Summary");
+		println!(
+			"  Tiles written: {} in {:.1?} ({:.2} tiles/s)",
+			tiles_written,
+			elapsed,
+			tiles_written as f64 / elapsed.as_secs_f64()
+		);
+		println!("  Bytes written: {}", Size(bytes_written));
+		if tiles_written != 0 {
+			println!("  Average tile size: {}", Size(bytes_written / tiles_written));
+		}
+
+		if let Some(stats) = stats.filter(|x| x.tiles != 0) {
+			let percent = |x: usize| x as f64 / stats.tiles as f64 * 100.0;
+			println!();
+			println!("Encoding");
+			println!("  Tiles encoded: {}", stats.tiles);
+			println!(
+				"  Average distinct heights: {:.1}",
+				stats.distinct_values as f64 / stats.tiles as f64
+			);
+			println!("  Estimated from the input heights, not reported by the codec:");
+			println!(
+				"    At most 256 distinct heights: {:.1}%",
+				percent(stats.estimated_paletted)
+			);
+			println!(
+				"    Heights spanning less than 256: {:.1}%",
+				percent(stats.estimated_narrow_range)
+			);
+			println!(
+				"  Average compressed heights: {}",
+				Size((stats.height_bytes / stats.tiles as u64) as usize)
+			);
+		}
+	}
+
+	None
+}
+
+fn file_size(path: &Path) -> u64 { std::fs::metadata(path).map(|x| x.len()).unwrap_or(0) }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn height_resolution_must_be_positive() {
+		assert!(parse_height_resolution("0").is_err());
+		assert!(parse_height_resolution("-1").is_err());
+		assert_eq!(parse_height_resolution("1"), Ok(1));
+		assert_eq!(parse_height_resolution("16"), Ok(16));
+	}
 }
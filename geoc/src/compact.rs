@@ -0,0 +1,153 @@
+use std::{
+	error::Error,
+	io::Write,
+	path::{Path, PathBuf},
+};
+
+use clap::Args;
+use geo::{map_index_to_lat_lon, Access, Dataset, DatasetBuilder, TileMetadata, FORMAT_VERSION};
+
+use crate::common::Size;
+
+#[derive(Args)]
+/// Rewrite a dataset without the space left behind by replaced tiles.
+pub struct Compact {
+	input: PathBuf,
+	/// Where to write the compacted dataset. Compacts in place if not given, or if it is the input.
+	#[clap(short = 'o', long = "out")]
+	output: Option<PathBuf>,
+}
+
+pub fn compact(compact: Compact) {
+	let source = match Dataset::load_with_advice(&compact.input, Access::Sequential) {
+		Ok(source) => source,
+		Err(err) => {
+			eprintln!("Error loading data source: {:?}", err);
+			return;
+		},
+	};
+
+	// Writing straight over the input would truncate it while it is still being read.
+	let in_place = compact
+		.output
+		.as_ref()
+		.map_or(true, |output| same_file(output, &compact.input));
+	let output = match &compact.output {
+		Some(output) if !in_place => output.clone(),
+		_ => {
+			let mut temp = compact.input.clone().into_os_string();
+			temp.push(".compact");
+			temp.into()
+		},
+	};
+
+	// Older datasets are upgraded to the current version, since their tiles are still valid.
+	let metadata = TileMetadata {
+		version: FORMAT_VERSION,
+		..source.metadata()
+	};
+	let before = source.file_size();
+	let result = write(&source, &output, metadata);
+	drop(source);
+	let after = match result {
+		Ok(x) => x,
+		Err(e) => {
+			println!("\n{}", e);
+			// A partial temporary file is of no use to anyone.
+			if in_place {
+				let _ = std::fs::remove_file(&output);
+			}
+			return;
+		},
+	};
+
+	if in_place {
+		if let Err(e) = std::fs::rename(&output, &compact.input) {
+			println!("\nError replacing input: {}", e);
+			let _ = std::fs::remove_file(&output);
+			return;
+		}
+	}
+
+	println!("\nReclaimed {}", Size(before.saturating_sub(after) as usize));
+}
+
+/// Whether `a` and `b` are the same existing file, even if they are spelled differently.
+fn same_file(a: &Path, b: &Path) -> bool {
+	match (a.canonicalize(), b.canonicalize()) {
+		(Ok(a), Ok(b)) => a == b,
+		_ => false,
+	}
+}
+
+/// Copy every tile of `source` to `output`, returning the size of the output.
+fn write(source: &Dataset, output: &Path, metadata: TileMetadata) -> Result<u64, String> {
+	let mut builder = DatasetBuilder::new(output, metadata).map_err(|e| e.to_string())?;
+	if let Some(provenance) = source.provenance() {
+		builder.set_provenance(provenance);
+	}
+
+	// Reading in offset order keeps the reads sequential.
+	let mut tiles: Vec<_> = (0..360 * 180)
+		.map(map_index_to_lat_lon)
+		.filter_map(|(lat, lon)| source.tile_offset(lat, lon).map(|offset| (offset, lat, lon)))
+		.collect();
+	tiles.sort_unstable();
+
+	for (i, &(_, lat, lon)) in tiles.iter().enumerate() {
+		// The metadata is the same, so the frames can be copied as they are.
+		let copy = || -> Result<(), Box<dyn Error>> {
+			if let Some(tile) = source.tile_frames(lat, lon).transpose()? {
+				builder.add_raw_tile(lat, lon, tile.frames(), tile.bounds())?;
+			}
+			Ok(())
+		};
+		copy().map_err(|e| format!("Error in tile {}, {}: {}", lat, lon, e))?;
+
+		print!("\r{}/{}", i + 1, tiles.len());
+		let _ = std::io::stdout().flush();
+	}
+
+	builder.finish().map_err(|e| format!("Error saving output: {}", e))?;
+	std::fs::metadata(output)
+		.map(|x| x.len())
+		.map_err(|e| format!("Error reading output: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+	use geo::Layers;
+
+	use super::*;
+
+	#[test]
+	fn compacting_onto_the_input_compacts_in_place() {
+		let dir = std::env::temp_dir().join(format!("geoc-test-compact-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("data.geo");
+
+		let metadata = TileMetadata {
+			version: FORMAT_VERSION,
+			resolution: 16,
+			height_resolution: 1,
+			layers: Layers::NONE,
+			mini_tile_size: 0,
+		};
+		let builder = DatasetBuilder::new(&path, metadata).unwrap();
+		let len = 16 * 16;
+		builder
+			.add_tile(0, 0, vec![1000; len], vec![0; len], vec![0; len])
+			.unwrap();
+		builder.finish().unwrap();
+
+		compact(Compact {
+			input: path.clone(),
+			output: Some(dir.join(".").join("data.geo")),
+		});
+
+		let dataset = Dataset::load(&path).unwrap();
+		assert!(matches!(dataset.get_full_tile(0, 0), Some(Ok(_))));
+		drop(dataset);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,173 @@
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use clap::Args;
+use geo::{map_index_to_lat_lon, map_lat_lon_to_index, Dataset, VerticalDatum};
+use png::{BitDepth, ColorType, Encoder};
+use rayon::prelude::*;
+
+#[derive(Args)]
+/// Compare the tiles of two datasets.
+pub struct Diff {
+	a: PathBuf,
+	b: PathBuf,
+	/// Write a 360x180 PNG of the mean height difference of each tile, north up. Tiles only present in one of the
+	/// datasets are red.
+	#[clap(long = "heatmap")]
+	heatmap: Option<PathBuf>,
+}
+
+enum TileDiff {
+	Missing,
+	OnlyA,
+	OnlyB,
+	Common { max: u32, mean: f64 },
+	Error(String),
+}
+
+pub fn diff(diff: Diff) {
+	let load = |path: &PathBuf| match Dataset::load(path) {
+		Ok(x) => Some(x),
+		Err(err) => {
+			eprintln!("{} could not be loaded: {}", path.display(), err);
+			None
+		},
+	};
+	let (a, b) = match (load(&diff.a), load(&diff.b)) {
+		(Some(a), Some(b)) => (a, b),
+		_ => return,
+	};
+
+	let datum = |x: &Dataset| x.provenance().unwrap_or_default().vertical_datum;
+	let (datum_a, datum_b) = (datum(&a), datum(&b));
+	if datum_a != datum_b && datum_a != VerticalDatum::Unknown && datum_b != VerticalDatum::Unknown {
+		println!(
+			"Warning: the heights are measured from different vertical datums ({:?} and {:?})",
+			datum_a, datum_b
+		);
+	}
+
+	// Heights can only be compared pixel by pixel if the tiles are the same size.
+	let compare_heights = a.metadata().resolution == b.metadata().resolution;
+	if !compare_heights {
+		println!(
+			"Resolutions differ ({} and {}), only comparing which tiles are present",
+			a.metadata().resolution,
+			b.metadata().resolution
+		);
+	}
+
+	let diffs: Vec<_> = (0..360 * 180)
+		.into_par_iter()
+		.map(map_index_to_lat_lon)
+		.map(|(lat, lon)| match (a.tile_exists(lat, lon), b.tile_exists(lat, lon)) {
+			(false, false) => TileDiff::Missing,
+			(true, false) => TileDiff::OnlyA,
+			(false, true) => TileDiff::OnlyB,
+			(true, true) if !compare_heights => TileDiff::Common { max: 0, mean: 0.0 },
+			(true, true) => compare(&a, &b, lat, lon),
+		})
+		.collect();
+
+	let (mut only_a, mut only_b, mut common, mut differing) = (0, 0, 0, 0);
+	println!("Differences");
+	let (mut max, mut mean_sum) = (0, 0.0);
+	for (i, tile) in diffs.iter().enumerate() {
+		let (lat, lon) = map_index_to_lat_lon(i);
+		match *tile {
+			TileDiff::Missing => {},
+			TileDiff::OnlyA => {
+				only_a += 1;
+				println!("  {}, {}: only in {}", lat, lon, diff.a.display());
+			},
+			TileDiff::OnlyB => {
+				only_b += 1;
+				println!("  {}, {}: only in {}", lat, lon, diff.b.display());
+			},
+			TileDiff::Common { max: tile_max, mean } => {
+				common += 1;
+				if tile_max != 0 {
+					differing += 1;
+				}
+				max = max.max(tile_max);
+				mean_sum += mean;
+			},
+			TileDiff::Error(ref e) => println!("  {}, {}: {}", lat, lon, e),
+		}
+	}
+
+	println!();
+	println!("Summary");
+	println!("  Only in {}: {}", diff.a.display(), only_a);
+	println!("  Only in {}: {}", diff.b.display(), only_b);
+	println!("  In both: {}", common);
+	if compare_heights {
+		println!("  Tiles with different heights: {}", differing);
+		println!("  Max height difference: {}m", max);
+		if common != 0 {
+			println!("  Mean height difference: {:.2}m", mean_sum / common as f64);
+		}
+	}
+
+	if let Some(path) = diff.heatmap {
+		if let Err(e) = write_heatmap(&path, &diffs) {
+			eprintln!("Error writing heatmap: {}", e);
+		}
+	}
+}
+
+/// The absolute differences of the heights of a tile present in both datasets, in meters.
+fn compare(a: &Dataset, b: &Dataset, lat: i16, lon: i16) -> TileDiff {
+	let (a, b) = match (a.get_full_tile(lat, lon), b.get_full_tile(lat, lon)) {
+		(Some(Ok(a)), Some(Ok(b))) => (a.0, b.0),
+		(Some(Err(e)), _) | (_, Some(Err(e))) => return TileDiff::Error(e.to_string()),
+		_ => unreachable!("Tile exists but could not be found"),
+	};
+
+	let (max, sum) = a
+		.iter()
+		.zip(b.iter())
+		.map(|(&a, &b)| (a as i32 - b as i32).unsigned_abs())
+		.fold((0, 0u64), |(max, sum), x| (max.max(x), sum + x as u64));
+
+	TileDiff::Common {
+		max,
+		mean: sum as f64 / a.len() as f64,
+	}
+}
+
+fn write_heatmap(path: &PathBuf, diffs: &[TileDiff]) -> Result<(), Box<dyn std::error::Error>> {
+	let scale = diffs
+		.iter()
+		.filter_map(|x| match *x {
+			TileDiff::Common { mean, .. } => Some(mean),
+			_ => None,
+		})
+		.fold(0.0, f64::max);
+
+	let mut pixels = Vec::with_capacity(360 * 180 * 3);
+	for lat in (-90..90).rev() {
+		for lon in -180..180 {
+			let pixel = match diffs[map_lat_lon_to_index(lat, lon)] {
+				TileDiff::Missing => [0, 0, 0],
+				TileDiff::OnlyA | TileDiff::OnlyB | TileDiff::Error(_) => [255, 0, 0],
+				TileDiff::Common { mean, .. } => {
+					let x = if scale == 0.0 {
+						0
+					} else {
+						(mean / scale * 255.0).round() as u8
+					};
+					[x, x, x]
+				},
+			};
+			pixels.extend_from_slice(&pixel);
+		}
+	}
+
+	let mut encoder = Encoder::new(BufWriter::new(File::create(path)?), 360, 180);
+	encoder.set_color(ColorType::Rgb);
+	encoder.set_depth(BitDepth::Eight);
+	let mut writer = encoder.write_header()?;
+	writer.write_image_data(&pixels)?;
+
+	Ok(())
+}
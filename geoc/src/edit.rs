@@ -1,7 +1,7 @@
 use std::{cell::RefCell, path::PathBuf};
 
 use clap::Args;
-use geo::{Dataset, TileMetadata, FORMAT_VERSION};
+use geo::{Codec, Dataset, Layers, TileMetadata, FORMAT_VERSION};
 use resize::{
 	Pixel::{Gray16, Gray8},
 	Resizer,
@@ -10,21 +10,36 @@ use resize::{
 use rgb::FromSlice;
 use thread_local::ThreadLocal;
 
-use crate::common::for_tile_in_output;
+use crate::common::{for_tile_in_output, parse_height_resolution, provenance_now, Hooks, OutputOptions, Resample};
 
 #[derive(Args)]
-/// Create a new dataset derived from another.
+/// Create a new dataset derived from another. Optional layers are not carried over.
 pub struct Edit {
 	input: PathBuf,
 	#[clap(short = 'o', long = "output")]
 	output: PathBuf,
 	#[clap(short = 'r', long = "res", default_value_t = 1024)]
 	resolution: u16,
-	#[clap(short = 's', long = "hres", default_value_t = 50)]
+	#[clap(short = 's', long = "hres", default_value_t = 50, parse(try_from_str = parse_height_resolution))]
 	height_resolution: u16,
+	/// Split each tile into square mini-tiles of this size, compressed on their own, so that parts of a tile can be
+	/// decoded without the rest. Must divide the resolution. 0 stores each tile whole.
+	#[clap(long = "mini-tile", default_value_t = 0)]
+	mini_tile_size: u16,
+	/// The filter used to resample heights and hillshade. The water mask always uses nearest neighbour, so that it
+	/// stays binary.
+	#[clap(long = "resample", arg_enum, default_value = "lanczos3")]
+	resample: Resample,
+	#[clap(flatten)]
+	options: OutputOptions,
 }
 
 pub fn edit(edit: Edit) {
+	if edit.mini_tile_size != 0 && edit.resolution % edit.mini_tile_size != 0 {
+		eprintln!("The mini-tile size must divide the resolution");
+		return;
+	}
+
 	let source = match Dataset::load(&edit.input) {
 		Ok(source) => source,
 		Err(err) => {
@@ -38,15 +53,72 @@ pub fn edit(edit: Edit) {
 		version: FORMAT_VERSION,
 		resolution: edit.resolution,
 		height_resolution: edit.height_resolution,
+		// Layers are derived from the heights, so they would have to be generated again.
+		layers: Layers::NONE,
+		mini_tile_size: edit.mini_tile_size,
 	};
 
 	let needs_resize = metadata.resolution != source_metadata.resolution;
+	// Tiles of a dataset with the same metadata can be copied without encoding them again, unless they are to be
+	// compressed with another codec.
+	let raw_copy = !needs_resize
+		&& edit.options.codec() == Codec::Hcomp
+		&& metadata.height_resolution == source_metadata.height_resolution
+		&& metadata.mini_tile_size == source_metadata.mini_tile_size
+		&& source_metadata.layers == Layers::NONE;
 
 	let u16_resize = ThreadLocal::new();
 	let u8_resize = ThreadLocal::new();
+	let water_resize = ThreadLocal::new();
+
+	// A source tile that can't be read is skipped like a corrupt one, so that the rest of the output is still finished.
+	let skip_unreadable = |lat: i16, lon: i16, e: std::io::Error| {
+		println!(
+			"\nSkipping tile {}, {}: error reading it from {}: {}",
+			lat,
+			lon,
+			edit.input.display(),
+			e
+		);
+	};
+
+	let (output, options, hooks) = (&edit.output, &edit.options, Hooks::default());
+	// The heights keep the datum of the source, but the output is a new dataset.
+	let provenance = provenance_now(source.provenance().unwrap_or_default().vertical_datum);
+	for_tile_in_output(output, metadata, provenance, options, hooks, |lat, lon, builder| {
+		if raw_copy {
+			match source.tile_frames(lat, lon) {
+				Some(Ok(tile)) => builder.add_raw_tile(lat, lon, tile.frames(), tile.bounds())?,
+				Some(Err(e)) => skip_unreadable(lat, lon, e),
+				None => {},
+			}
+			return Ok(());
+		}
+
+		let tile = match source.get_full_tile(lat, lon) {
+			Some(Ok(x)) => Some(x),
+			Some(Err(e)) => {
+				skip_unreadable(lat, lon, e);
+				return Ok(());
+			},
+			None => None,
+		};
+		if let Some((data, water, hillshade)) = tile {
+			// A corrupt tile can decode to the wrong size, which would otherwise be resized or encoded as garbage.
+			let len = source_metadata.resolution as usize * source_metadata.resolution as usize;
+			if data.len() != len || water.len() != len || hillshade.len() != len {
+				println!(
+					"\nSkipping tile {}, {}: decoded to {} heights, {} water and {} hillshade values instead of {}",
+					lat,
+					lon,
+					data.len(),
+					water.len(),
+					hillshade.len(),
+					len
+				);
+				return Ok(());
+			}
 
-	for_tile_in_output(&edit.output, metadata, |lat, lon, builder| {
-		if let Some((data, water, hillshade)) = source.get_full_tile(lat, lon).transpose()? {
 			let data = if needs_resize {
 				let mut u16_resize = u16_resize
 					.get_or(|| {
@@ -57,7 +129,7 @@ pub fn edit(edit: Edit) {
 								metadata.resolution as _,
 								metadata.resolution as _,
 								Gray16,
-								Type::Lanczos3,
+								edit.resample.filter(),
 							)
 							.unwrap(),
 						)
@@ -72,7 +144,22 @@ pub fn edit(edit: Edit) {
 								metadata.resolution as _,
 								metadata.resolution as _,
 								Gray8,
-								Type::Lanczos3,
+								edit.resample.filter(),
+							)
+							.unwrap(),
+						)
+					})
+					.borrow_mut();
+				let mut water_resize = water_resize
+					.get_or(|| {
+						RefCell::new(
+							Resizer::new(
+								source_metadata.resolution as _,
+								source_metadata.resolution as _,
+								metadata.resolution as _,
+								metadata.resolution as _,
+								Gray8,
+								Type::Point,
 							)
 							.unwrap(),
 						)
@@ -85,7 +172,7 @@ pub fn edit(edit: Edit) {
 				let mut hillshade_out = vec![0; res * res];
 
 				let _ = u16_resize.resize(data.as_gray(), data_out.as_gray_mut());
-				let _ = u8_resize.resize(water.as_gray(), water_out.as_gray_mut());
+				let _ = water_resize.resize(water.as_gray(), water_out.as_gray_mut());
 				let _ = u8_resize.resize(hillshade.as_gray(), hillshade_out.as_gray_mut());
 
 				if water_out.iter().all(|&x| x == 1) {
@@ -94,7 +181,7 @@ pub fn edit(edit: Edit) {
 					Some((data_out, water_out, hillshade_out))
 				}
 			} else {
-				Some((data, water, hillshade))
+				Some((data.into_owned(), water, hillshade))
 			};
 
 			if let Some(data) = data {
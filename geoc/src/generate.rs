@@ -1,16 +1,29 @@
-use std::path::PathBuf;
+use std::{
+	error::Error,
+	path::{Path, PathBuf},
+};
 
 use clap::Args;
-use geo::{TileMetadata, FORMAT_VERSION};
+use geo::{DatasetBuilder, DryRunEstimate, LatLon, LayerData, Layers, TileMetadata, FORMAT_VERSION};
 
 use crate::{
-	common::for_tile_in_output,
-	source::{LatLon, Raster},
+	common::{
+		for_tile_in_output,
+		parse_height_resolution,
+		provenance_now,
+		Datum,
+		Hooks,
+		OutputOptions,
+		Resample,
+		Size,
+	},
+	source::Raster,
 };
 
 #[derive(Args)]
 /// Generate a dataset from a raw source.
 pub struct Generate {
+	/// A raster, or a directory of 1° by 1° rasters named like SRTM tiles.
 	input: PathBuf,
 	#[clap(short = 'w', long = "water")]
 	water: PathBuf,
@@ -18,19 +31,69 @@ pub struct Generate {
 	output: PathBuf,
 	#[clap(short = 'r', long = "res", default_value_t = 1200)]
 	resolution: u16,
-	#[clap(short = 's', long = "hres", default_value_t = 1)]
+	#[clap(short = 's', long = "hres", default_value_t = 1, parse(try_from_str = parse_height_resolution))]
 	height_resolution: u16,
+	/// The filter used to resample heights.
+	#[clap(long = "resample", arg_enum, default_value = "lanczos3")]
+	resample: Resample,
+	/// The filter used to resample the water mask.
+	#[clap(long = "water-resample", arg_enum, default_value = "nearest")]
+	water_resample: Resample,
+	/// Multiply the gradient by this before shading, to exaggerate the relief of flat terrain. Only affects the
+	/// hillshade, not the slope and aspect layers.
+	#[clap(long = "z-factor", default_value_t = 1.0)]
+	z_factor: f32,
+	/// Also store the slope angle of the terrain.
+	#[clap(long = "slope")]
+	slope: bool,
+	/// Also store the compass direction that the terrain faces.
+	#[clap(long = "aspect")]
+	aspect: bool,
+	/// Also store which pixels have data in the input, from the no data value of the input, so that the renderer fades
+	/// the edges of partly covered tiles instead of drawing the missing pixels at sea level. Tiles with no data at all
+	/// are left out.
+	#[clap(long = "coverage")]
+	coverage: bool,
+	/// Split each tile into square mini-tiles of this size, compressed on their own, so that parts of a tile can be
+	/// decoded without the rest. Must divide the resolution. 0 stores each tile whole.
+	#[clap(long = "mini-tile", default_value_t = 0)]
+	mini_tile_size: u16,
+	/// The surface that the heights of the input are measured from, recorded in the dataset so that tools can tell
+	/// datasets with different height references apart.
+	#[clap(long = "vertical-datum", arg_enum, default_value = "unknown")]
+	vertical_datum: Datum,
+	/// Coarsen the height resolution until the output is estimated to fit in this many bytes. The height resolution
+	/// is the same for every tile, so it can't be adapted to each tile. Instead, a single dry run reads the input
+	/// once, and encodes the heights of its sampled tiles with `--hres` and every doubling of it, and the finest of
+	/// those that fits is used.
+	#[clap(long = "target-size", conflicts_with = "dry_run")]
+	target_size: Option<u64>,
+	#[clap(flatten)]
+	options: OutputOptions,
+}
+
+fn load_raster(path: &Path) -> Result<Raster, Box<dyn Error>> {
+	if path.is_dir() {
+		Ok(Raster::load_tiled(path)?)
+	} else {
+		Ok(Raster::load(path)?)
+	}
 }
 
 pub fn generate(generate: Generate) {
-	let source = match Raster::load(&generate.input) {
+	if generate.mini_tile_size != 0 && generate.resolution % generate.mini_tile_size != 0 {
+		eprintln!("The mini-tile size must divide the resolution");
+		return;
+	}
+
+	let source = match load_raster(&generate.input) {
 		Ok(source) => source,
 		Err(err) => {
 			eprintln!("Error loading data source: {:?}", err);
 			return;
 		},
 	};
-	let water = match Raster::load(&generate.water) {
+	let water = match load_raster(&generate.water) {
 		Ok(source) => source,
 		Err(err) => {
 			eprintln!("Error loading water source: {:?}", err);
@@ -41,9 +104,21 @@ pub fn generate(generate: Generate) {
 		version: FORMAT_VERSION,
 		resolution: generate.resolution,
 		height_resolution: generate.height_resolution,
+		layers: [
+			(generate.slope, Layers::SLOPE),
+			(generate.aspect, Layers::ASPECT),
+			(generate.coverage, Layers::COVERAGE),
+		]
+		.into_iter()
+		.filter(|x| x.0)
+		.fold(Layers::NONE, |acc, x| acc | x.1),
+		mini_tile_size: generate.mini_tile_size,
 	};
+	let layers = metadata.layers;
 
-	for_tile_in_output(&generate.output, metadata, |lat, lon, builder| {
+	let (resample, water_resample) = (generate.resample.gdal(), generate.water_resample.gdal());
+
+	let exec = |lat: i16, lon: i16, builder: &DatasetBuilder| -> Result<(), Box<dyn Error>> {
 		let bottom_left = LatLon {
 			lat: lat as f64,
 			lon: lon as f64,
@@ -54,142 +129,43 @@ pub fn generate(generate: Generate) {
 		};
 
 		source
-			.get_data_for_hillshade(bottom_left, top_right, metadata.resolution as _)
+			.get_data_for_hillshade(bottom_left, top_right, metadata.resolution as _, resample)
 			.and_then(|(data, has_extra): (Vec<i16>, _)| {
-				tracy::zone!("Load water");
+				zone!("Load water");
 				water
-					.get_data(bottom_left, top_right, metadata.resolution as _)
+					.get_data(bottom_left, top_right, metadata.resolution as _, water_resample)
 					.map(|water: Vec<u8>| (data, has_extra, water))
 			})
-			.and_then(|(data, has_extra, water)| {
+			.and_then(|(mut data, has_extra, water)| {
 				let res = metadata.resolution as usize;
 				assert!(res * res <= data.len());
 
-				let (data, hillshade) = if has_extra {
-					let ores = res;
-					let res = res + 2;
-
-					let hillshade = {
-						tracy::zone!("Generate hillshade");
-
-						let zenith = 45.0f32.to_radians();
-						let azimuth = 135.0f32.to_radians();
-
-						let mut out = vec![0; ores * ores];
-						for x in 1..res - 1 {
-							for y in 1..res - 1 {
-								let a = data[(y - 1) * res + x - 1] as f32;
-								let b = data[(y - 1) * res + x] as f32;
-								let c = data[(y - 1) * res + x + 1] as f32;
-								let d = data[y * res + x - 1] as f32;
-								let f = data[y * res + x + 1] as f32;
-								let g = data[(y + 1) * res + x - 1] as f32;
-								let h = data[(y + 1) * res + x] as f32;
-								let i = data[(y + 1) * res + x + 1] as f32;
-
-								let dzdx = ((c + 2.0 * f + i) - (a + 2.0 * d + g)) / 8.0;
-								let dzdy = ((g + 2.0 * h + i) - (a + 2.0 * b + c)) / 8.0;
-
-								let slope = (dzdx * dzdx + dzdy * dzdy).sqrt().atan();
-								let aspect = if dzdx != 0.0 {
-									let aspect = dzdy.atan2(-dzdx);
-									if aspect < 0.0 {
-										aspect + 2.0 * std::f32::consts::PI
-									} else {
-										aspect
-									}
-								} else {
-									if dzdy > 0.0 {
-										0.5 * std::f32::consts::PI
-									} else {
-										1.5 * std::f32::consts::PI
-									}
-								};
-
-								let hillshade = (zenith.cos() * slope.cos()
-									+ zenith.sin() * slope.sin() * (azimuth - aspect).cos())
-								.clamp(0.0, 1.0);
-
-								out[(y - 1) * ores + x - 1] = (hillshade * 255.0).round() as u8;
-							}
-						}
-
-						out
-					};
+				let coverage = layers.contains(Layers::COVERAGE).then(|| {
+					zone!("Generate coverage");
+					coverage(&mut data, source.no_data(bottom_left))
+				});
 
-					let mut out = vec![0; ores * ores];
-					for x in 1..res - 1 {
-						for y in 1..res - 1 {
-							out[(y - 1) * ores + x - 1] = data[y * res + x];
-						}
-					}
+				let shading = {
+					zone!("Generate hillshade");
+					// The spacing of pixels in meters, for layers that need the true gradient.
+					let spacing_y = 111_320.0 / res as f32;
+					let spacing_x = spacing_y * (lat as f32 + 0.5).to_radians().cos();
+					shade(&data, res, has_extra, layers, (spacing_x, spacing_y), generate.z_factor)
+				};
 
-					(out, hillshade)
-				} else {
-					let hillshade = {
-						tracy::zone!("Generate hillshade");
-
-						let zenith = 45.0f32.to_radians();
-						let azimuth = 135.0f32.to_radians();
-
-						let mut out = vec![0; res * res];
-						for x in 1..res - 1 {
-							for y in 1..res - 1 {
-								let a = data[(y - 1) * res + x - 1] as f32;
-								let b = data[(y - 1) * res + x] as f32;
-								let c = data[(y - 1) * res + x + 1] as f32;
-								let d = data[y * res + x - 1] as f32;
-								let f = data[y * res + x + 1] as f32;
-								let g = data[(y + 1) * res + x - 1] as f32;
-								let h = data[(y + 1) * res + x] as f32;
-								let i = data[(y + 1) * res + x + 1] as f32;
-
-								let dzdx = ((c + 2.0 * f + i) - (a + 2.0 * d + g)) / 8.0;
-								let dzdy = ((g + 2.0 * h + i) - (a + 2.0 * b + c)) / 8.0;
-
-								let slope = (dzdx * dzdx + dzdy * dzdy).sqrt().atan();
-								let aspect = if dzdx != 0.0 {
-									let aspect = dzdy.atan2(-dzdx);
-									if aspect < 0.0 {
-										aspect + 2.0 * std::f32::consts::PI
-									} else {
-										aspect
-									}
-								} else {
-									if dzdy > 0.0 {
-										0.5 * std::f32::consts::PI
-									} else {
-										1.5 * std::f32::consts::PI
-									}
-								};
-
-								let hillshade = (zenith.cos() * slope.cos()
-									+ zenith.sin() * slope.sin() * (azimuth - aspect).cos())
-								.clamp(0.0, 1.0);
-
-								out[y * res + x] = (hillshade * 255.0).round() as u8;
-							}
-						}
-
-						out
-					};
+				let data = crop(data, res, has_extra);
+				let coverage = coverage.map(|x| crop(x, res, has_extra));
 
-					(data, hillshade)
-				};
+				let water_count: u32 = water.iter().map(|&w| w as u32).sum();
+				let covered = coverage.as_ref().map_or(true, |x| x.iter().any(|&x| x != 0));
 
-				let mut water_count = 0;
-				let data = data
-					.into_iter()
-					.zip(water.iter())
-					.map(|(h, &w)| {
-						let positive = (h + 500) as u16;
-						water_count += w as u32;
-						positive
-					})
-					.collect();
-
-				if water_count != metadata.resolution as u32 * metadata.resolution as u32 {
-					Some(builder.add_tile(lat, lon, data, water, hillshade))
+				if water_count != metadata.resolution as u32 * metadata.resolution as u32 && covered {
+					let layers = LayerData {
+						slope: shading.slope,
+						aspect: shading.aspect,
+						coverage,
+					};
+					Some(builder.add_tile_meters_with_layers(lat, lon, data, water, shading.hillshade, layers))
 				} else {
 					None
 				}
@@ -197,5 +173,259 @@ pub fn generate(generate: Generate) {
 			.transpose()?;
 
 		Ok(())
-	});
+	};
+
+	let (output, options) = (&generate.output, &generate.options);
+	let provenance = provenance_now(generate.vertical_datum.vertical_datum());
+	let dry_run = |metadata: TileMetadata, compare: &[u16]| {
+		let options = options.as_dry_run(compare);
+		for_tile_in_output(output, metadata, provenance, &options, Hooks::default(), &exec)
+	};
+	let height_resolution = match generate.target_size {
+		Some(target) => match fit_height_resolution(target, metadata, dry_run) {
+			Some(x) => x,
+			None => return,
+		},
+		None => metadata.height_resolution,
+	};
+
+	let metadata = TileMetadata {
+		height_resolution,
+		..metadata
+	};
+	for_tile_in_output(output, metadata, provenance, options, Hooks::default(), &exec);
+}
+
+/// The coarsest height resolution that `--target-size` tries before giving up.
+const MAX_TARGET_HEIGHT_RESOLUTION: u16 = 128;
+
+/// Find the finest height resolution, out of the one in `metadata` and its doublings, that a dry run estimates fits in
+/// `target` bytes. A single dry run estimates all of them from the same sampled tiles. Returns `None` if the dry run
+/// fails, or if no height resolution fits.
+fn fit_height_resolution(
+	target: u64, metadata: TileMetadata,
+	dry_run: impl FnOnce(TileMetadata, &[u16]) -> Option<Vec<(u16, DryRunEstimate)>>,
+) -> Option<u16> {
+	let first = metadata.height_resolution.max(1);
+	let candidates: Vec<_> = std::iter::successors(Some(first), |&x| {
+		Some(x.saturating_mul(2).min(MAX_TARGET_HEIGHT_RESOLUTION)).filter(|&y| y > x)
+	})
+	.collect();
+
+	println!("Estimating the size with height resolutions of {:?}", candidates);
+	let estimates = dry_run(
+		TileMetadata {
+			height_resolution: first,
+			..metadata
+		},
+		&candidates[1..],
+	)?;
+
+	let fits = estimates
+		.iter()
+		.map(|&(height_resolution, estimate)| (height_resolution, estimate.file_size()))
+		.find(|&(_, size)| size <= target);
+	if let Some((height_resolution, size)) = fits {
+		println!(
+			"\nEstimated {} with a height resolution of {}",
+			Size(size as usize),
+			height_resolution
+		);
+		return Some(height_resolution);
+	}
+
+	let (height_resolution, estimate) = *estimates.last()?;
+	eprintln!(
+		"\nEven a height resolution of {} is estimated at {}, more than the target of {}",
+		height_resolution,
+		Size(estimate.file_size() as usize),
+		Size(target as usize)
+	);
+	None
+}
+
+/// Drop the extra pixel on every side of `data` that `has_extra` marks, leaving the `res` by `res` tile.
+fn crop<T: Copy + Default>(data: Vec<T>, res: usize, has_extra: bool) -> Vec<T> {
+	if !has_extra {
+		return data;
+	}
+
+	let ores = res;
+	let res = res + 2;
+
+	let mut out = vec![T::default(); ores * ores];
+	for x in 1..res - 1 {
+		for y in 1..res - 1 {
+			out[(y - 1) * ores + x - 1] = data[y * res + x];
+		}
+	}
+
+	out
+}
+
+/// The coverage layer of a tile: 255 where `data` has data, and 0 where it is `no_data`. The pixels without data are
+/// set to sea level, so that they don't shade the pixels next to them as a cliff down to the no data value.
+fn coverage(data: &mut [i16], no_data: Option<f64>) -> Vec<u8> {
+	data.iter_mut()
+		.map(|x| {
+			if no_data == Some(*x as f64) {
+				*x = 0;
+				0
+			} else {
+				255
+			}
+		})
+		.collect()
+}
+
+/// The layers derived from the gradient of the terrain.
+struct Shading {
+	hillshade: Vec<u8>,
+	slope: Option<Vec<u8>>,
+	aspect: Option<Vec<u8>>,
+}
+
+/// Compute the hillshade and the requested layers of a tile. If `has_extra` is set, `data` has an extra pixel on
+/// every side which is only used for the gradient of the pixels next to it. Otherwise, the pixels on the edges of the
+/// tile have no gradient, and are left at 0. `spacing` is the distance between pixels in meters, along x and y.
+/// `z_factor` exaggerates the relief of the hillshade.
+fn shade(data: &[i16], res: usize, has_extra: bool, layers: Layers, spacing: (f32, f32), z_factor: f32) -> Shading {
+	let (ores, res, offset) = if has_extra { (res, res + 2, 1) } else { (res, res, 0) };
+
+	let zenith = 45.0f32.to_radians();
+	let azimuth = 135.0f32.to_radians();
+
+	let mut hillshade = vec![0; ores * ores];
+	let mut slope_layer = layers.contains(Layers::SLOPE).then(|| vec![0; ores * ores]);
+	let mut aspect_layer = layers.contains(Layers::ASPECT).then(|| vec![0; ores * ores]);
+	for x in 1..res - 1 {
+		for y in 1..res - 1 {
+			let a = data[(y - 1) * res + x - 1] as f32;
+			let b = data[(y - 1) * res + x] as f32;
+			let c = data[(y - 1) * res + x + 1] as f32;
+			let d = data[y * res + x - 1] as f32;
+			let f = data[y * res + x + 1] as f32;
+			let g = data[(y + 1) * res + x - 1] as f32;
+			let h = data[(y + 1) * res + x] as f32;
+			let i = data[(y + 1) * res + x + 1] as f32;
+
+			let dzdx = ((c + 2.0 * f + i) - (a + 2.0 * d + g)) / 8.0;
+			let dzdy = ((g + 2.0 * h + i) - (a + 2.0 * b + c)) / 8.0;
+
+			let (sx, sy) = (dzdx * z_factor, dzdy * z_factor);
+			let slope = (sx * sx + sy * sy).sqrt().atan();
+			let aspect = aspect(sx, sy);
+
+			let shade =
+				(zenith.cos() * slope.cos() + zenith.sin() * slope.sin() * (azimuth - aspect).cos()).clamp(0.0, 1.0);
+
+			let index = (y - offset) * ores + x - offset;
+			hillshade[index] = (shade * 255.0).round() as u8;
+
+			// The hillshade uses the gradient per pixel, but the layers need the true gradient.
+			let (dzdx, dzdy) = (dzdx / spacing.0, dzdy / spacing.1);
+			if let Some(layer) = slope_layer.as_mut() {
+				let angle = (dzdx * dzdx + dzdy * dzdy).sqrt().atan();
+				layer[index] = (angle / std::f32::consts::FRAC_PI_2 * 255.0).round() as u8;
+			}
+			if let Some(layer) = aspect_layer.as_mut() {
+				layer[index] = if dzdx == 0.0 && dzdy == 0.0 {
+					0
+				} else {
+					// `aspect` is counter-clockwise from east, but the layer is a compass direction.
+					let compass = (90.0 - aspect(dzdx, dzdy).to_degrees()).rem_euclid(360.0);
+					((compass / 360.0 * 256.0).round() as u32 % 256) as u8
+				};
+			}
+		}
+	}
+
+	Shading {
+		hillshade,
+		slope: slope_layer,
+		aspect: aspect_layer,
+	}
+}
+
+/// The direction that the terrain faces, in radians counter-clockwise from east.
+fn aspect(dzdx: f32, dzdy: f32) -> f32 {
+	if dzdx != 0.0 {
+		let aspect = dzdy.atan2(-dzdx);
+		if aspect < 0.0 {
+			aspect + 2.0 * std::f32::consts::PI
+		} else {
+			aspect
+		}
+	} else {
+		if dzdy > 0.0 {
+			0.5 * std::f32::consts::PI
+		} else {
+			1.5 * std::f32::consts::PI
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// The difference between the brightest and darkest pixels of the hillshade.
+	fn contrast(data: &[i16], res: usize, z_factor: f32) -> u8 {
+		let shading = shade(data, res, true, Layers::NONE, (30.0, 30.0), z_factor);
+		shading.hillshade.iter().max().unwrap() - shading.hillshade.iter().min().unwrap()
+	}
+
+	#[test]
+	fn target_size_picks_the_finest_height_resolution_that_fits() {
+		let metadata = TileMetadata {
+			version: FORMAT_VERSION,
+			resolution: 16,
+			height_resolution: 2,
+			layers: Layers::NONE,
+			mini_tile_size: 0,
+		};
+		// Every doubling of the height resolution halves the size of the sampled tiles.
+		let estimate = |height_resolution: u16| DryRunEstimate {
+			tiles: 4,
+			sampled_tiles: 1,
+			sampled_bytes: (1 << 24) / height_resolution as u64,
+		};
+		let dry_run = |metadata: TileMetadata, compare: &[u16]| {
+			assert_eq!(metadata.height_resolution, 2);
+			assert_eq!(compare, [4, 8, 16, 32, 64, 128]);
+			Some([2].iter().chain(compare).map(|&x| (x, estimate(x))).collect())
+		};
+		let fit = |target| fit_height_resolution(target, metadata, dry_run);
+
+		assert_eq!(fit(estimate(8).file_size()), Some(8));
+		assert_eq!(fit(estimate(8).file_size() - 1), Some(16));
+		assert_eq!(fit(estimate(128).file_size() - 1), None);
+	}
+
+	#[test]
+	fn coverage_masks_no_data() {
+		let mut data = vec![100, -32768, 200, -32768];
+		assert_eq!(coverage(&mut data, Some(-32768.0)), [255, 0, 255, 0]);
+		assert_eq!(data, [100, 0, 200, 0]);
+
+		let mut data = vec![100, -32768];
+		assert_eq!(coverage(&mut data, None), [255, 255]);
+		assert_eq!(data, [100, -32768]);
+	}
+
+	#[test]
+	fn z_factor_increases_contrast() {
+		// A gentle ridge, rising 1 m every 30 m pixel on either side, so that it faces towards and away from the light.
+		let res = 16;
+		let data: Vec<_> = (0..res + 2)
+			.flat_map(|_| (0..res + 2).map(move |x: usize| x.min(res + 1 - x) as i16))
+			.collect();
+
+		let contrasts = [0.5, 1.0, 2.0].map(|z_factor| contrast(&data, res, z_factor));
+		assert!(
+			contrasts[0] < contrasts[1] && contrasts[1] < contrasts[2],
+			"{:?}",
+			contrasts
+		);
+	}
 }
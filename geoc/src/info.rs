@@ -1,29 +1,52 @@
-use std::{fmt::Display, path::PathBuf};
+use std::{collections::BTreeMap, path::PathBuf};
 
 use clap::Args;
-use geo::Dataset;
+use geo::{map_index_to_lat_lon, Codec, Dataset};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::common::Size;
 
 #[derive(Args)]
 /// Give information about the dataset.
 pub struct Info {
 	input: PathBuf,
+	/// Show the distribution of compressed tile sizes, and the largest tiles. Datasets from before format version 13
+	/// don't record the size of each tile, so any dead space left behind by replaced tiles is counted towards the tile
+	/// before it.
+	#[clap(long = "sizes")]
+	sizes: bool,
+	/// Show how the heights of the tiles were compressed, and how many distinct heights the hcomp tiles have. The
+	/// codec is read from the tag of each tile, but hcomp doesn't record which of its paths it took, so whether a
+	/// tile was paletted can't be shown. Every hcomp tile is decoded once to count its distinct heights instead.
+	#[clap(long = "encoding")]
+	encoding: bool,
+	/// Print the metadata and a summary of the tiles as a single JSON object, for scripts and dashboards, instead of
+	/// the tables.
+	#[clap(long = "json", conflicts_with_all = &["sizes", "encoding"])]
+	json: bool,
 }
 
-struct Size(usize);
-
-impl Display for Size {
-	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		let size = self.0;
-		if size < 1000 {
-			write!(f, "{} B", size)
-		} else if size < 1000 * 1000 {
-			write!(f, "{:.2} KB", size as f64 / 1000.0)
-		} else if size < 1000 * 1000 * 1000 {
-			write!(f, "{:.2} MiB", size as f64 / 1000.0 / 1000.0)
-		} else {
-			write!(f, "{:.2} GiB", size as f64 / 1000.0 / 1000.0 / 1000.0)
-		}
-	}
+/// What `--json` prints.
+#[derive(Serialize)]
+struct Summary {
+	version: u16,
+	resolution: u16,
+	height_resolution: u16,
+	tile_count: usize,
+	/// Of the 360 * 180 tiles of the globe.
+	coverage_percent: f64,
+	/// In meters, from the stored bounds of the tiles, which datasets before format version 12 don't have.
+	min_elev: Option<i16>,
+	max_elev: Option<i16>,
+	file_size: u64,
+}
+
+/// How the heights of a tile were compressed.
+enum Encoding {
+	Zstd,
+	Hcomp { distinct: usize, range: u16 },
+	Error,
 }
 
 pub fn info(info: Info) {
@@ -36,13 +59,172 @@ pub fn info(info: Info) {
 	};
 	let metadata = dataset.metadata();
 
+	if info.json {
+		print_json(&dataset);
+		return;
+	}
+
 	println!("Metadata");
 	println!("  Version: {}", metadata.version);
 	println!("  Resolution: {}", metadata.resolution);
 	println!("  Height resolution: {}", metadata.height_resolution);
+	if metadata.mini_tile_size != 0 {
+		println!("  Mini-tile size: {}", metadata.mini_tile_size);
+	}
+	if let Some(provenance) = dataset.provenance() {
+		println!("  Vertical datum: {:?}", provenance.vertical_datum);
+		if provenance.generated_at != 0 {
+			println!("  Generated at: {} (Unix time)", provenance.generated_at);
+		}
+	}
 
 	println!();
 
 	println!("Tiles");
 	println!("  Tile count: {}", dataset.tile_count());
+
+	if info.sizes {
+		println!();
+		print_sizes(&dataset);
+	}
+
+	if info.encoding {
+		println!();
+		print_encoding(&dataset);
+	}
+}
+
+fn print_json(dataset: &Dataset) {
+	let metadata = dataset.metadata();
+	let bounds: Vec<_> = (0..360 * 180)
+		.map(map_index_to_lat_lon)
+		.filter_map(|(lat, lon)| dataset.tile_bounds(lat, lon))
+		.collect();
+	let tile_count = dataset.tile_count();
+
+	let summary = Summary {
+		version: metadata.version,
+		resolution: metadata.resolution,
+		height_resolution: metadata.height_resolution,
+		tile_count,
+		coverage_percent: tile_count as f64 / (360.0 * 180.0) * 100.0,
+		min_elev: bounds.iter().map(|x| x.0).min(),
+		max_elev: bounds.iter().map(|x| x.1).max(),
+		file_size: dataset.file_size(),
+	};
+	println!("{}", serde_json::to_string(&summary).unwrap());
+}
+
+fn print_encoding(dataset: &Dataset) {
+	let encodings: Vec<_> = (0..360 * 180)
+		.into_par_iter()
+		.map(map_index_to_lat_lon)
+		.filter_map(|(lat, lon)| {
+			match dataset.tile_codec(lat, lon)? {
+				Ok(Codec::Zstd) => return Some(Encoding::Zstd),
+				Ok(Codec::Hcomp) => {},
+				Err(_) => return Some(Encoding::Error),
+			}
+
+			Some(match dataset.get_tile_raw(lat, lon)? {
+				Ok((heights, _)) => {
+					let mut seen = vec![0u64; 65536 / 64];
+					for &x in heights.iter() {
+						seen[x as usize / 64] |= 1 << (x % 64);
+					}
+					let min = heights.iter().copied().min().unwrap_or(0);
+					let max = heights.iter().copied().max().unwrap_or(0);
+					Encoding::Hcomp {
+						distinct: seen.iter().map(|x| x.count_ones() as usize).sum(),
+						range: max - min,
+					}
+				},
+				Err(_) => Encoding::Error,
+			})
+		})
+		.collect();
+
+	let (mut zstd, mut hcomp, mut few, mut narrow, mut errors) = (0, 0, 0, 0, 0);
+	let mut histogram = BTreeMap::new();
+	for encoding in encodings.iter() {
+		match *encoding {
+			Encoding::Zstd => zstd += 1,
+			Encoding::Hcomp { distinct, range } => {
+				hcomp += 1;
+				if distinct <= 256 {
+					few += 1;
+					*histogram.entry(usize::BITS - distinct.leading_zeros()).or_insert(0) += 1;
+				}
+				narrow += (range < 256) as usize;
+			},
+			Encoding::Error => errors += 1,
+		}
+	}
+
+	println!("Encoding");
+	println!("  zstd: {}", zstd);
+	println!("  hcomp: {}", hcomp);
+	println!("    At most 256 distinct heights: {}", few);
+	println!("    More than 256 distinct heights: {}", hcomp - few);
+	println!("    Heights spanning less than 256: {}", narrow);
+	if errors != 0 {
+		println!("  Failed to decode: {}", errors);
+	}
+
+	if histogram.is_empty() {
+		return;
+	}
+
+	let max = histogram.values().copied().max().unwrap_or(1);
+	println!();
+	println!("Distinct heights of tiles with at most 256");
+	for (&bits, &count) in histogram.iter() {
+		let lower = if bits == 0 { 0 } else { 1 << (bits - 1) };
+		let upper = (1 << bits) - 1;
+		println!(
+			"  {:>3} - {:<3} {:>6} {}",
+			lower,
+			upper,
+			count,
+			"#".repeat((count * 40 + max - 1) / max)
+		);
+	}
+}
+
+fn print_sizes(dataset: &Dataset) {
+	let mut sizes: Vec<_> = dataset
+		.tile_sizes()
+		.into_iter()
+		.map(|(lat, lon, size)| (size as usize, lat, lon))
+		.collect();
+	if sizes.is_empty() {
+		return;
+	}
+
+	let mut buckets = BTreeMap::new();
+	for &(size, ..) in sizes.iter() {
+		*buckets.entry(usize::BITS - size.leading_zeros()).or_insert(0) += 1;
+	}
+	let max = buckets.values().copied().max().unwrap_or(1);
+
+	println!("Compressed sizes");
+	for (&bits, &count) in buckets.iter() {
+		let lower = if bits == 0 { 0 } else { 1 << (bits - 1) };
+		let upper = 1 << bits;
+		println!(
+			"  {:>10} - {:<10} {:>6} {}",
+			Size(lower).to_string(),
+			Size(upper).to_string(),
+			count,
+			"#".repeat((count * 40 + max - 1) / max)
+		);
+	}
+
+	println!();
+
+	sizes.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+	println!("Largest tiles");
+	for &(size, lat, lon) in sizes.iter().take(10) {
+		println!("  {}, {}: {}", lat, lon, Size(size));
+	}
 }
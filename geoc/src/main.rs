@@ -2,15 +2,28 @@ use clap::{Parser, Subcommand};
 
 #[cfg(feature = "generate")]
 use crate::generate::Generate;
-use crate::{edit::Edit, info::Info};
+use crate::{bench::Bench, cat::Cat, compact::Compact, diff::Diff, edit::Edit, info::Info, verify::Verify};
 
+/// `tracy::zone!`, if the `profiling` feature is enabled.
+macro_rules! zone {
+	($name:literal) => {
+		#[cfg(feature = "profiling")]
+		tracy::zone!($name);
+	};
+}
+
+mod bench;
+mod cat;
 mod common;
+mod compact;
+mod diff;
 mod edit;
 #[cfg(feature = "generate")]
 mod generate;
 mod info;
 #[cfg(feature = "generate")]
 mod source;
+mod verify;
 
 #[derive(Parser)]
 struct Options {
@@ -24,6 +37,11 @@ enum Command {
 	Generate(Generate),
 	Info(Info),
 	Edit(Edit),
+	Compact(Compact),
+	Bench(Bench),
+	Diff(Diff),
+	Cat(Cat),
+	Verify(Verify),
 }
 
 fn main() {
@@ -33,5 +51,10 @@ fn main() {
 		Command::Generate(generate) => generate::generate(generate),
 		Command::Info(info) => info::info(info),
 		Command::Edit(edit) => edit::edit(edit),
+		Command::Compact(compact) => compact::compact(compact),
+		Command::Bench(bench) => bench::bench(bench),
+		Command::Diff(diff) => diff::diff(diff),
+		Command::Cat(cat) => cat::cat(cat),
+		Command::Verify(verify) => verify::verify(verify),
 	}
 }
@@ -1,18 +1,17 @@
-use std::path::{Path, PathBuf};
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+	sync::OnceLock,
+};
 
 use gdal::{
 	errors::GdalError,
 	raster::{GdalType, ResampleAlg},
 	Dataset,
 };
+use geo::LatLon;
 use thread_local::ThreadLocal;
 
-#[derive(Copy, Clone)]
-pub struct LatLon {
-	pub lat: f64,
-	pub lon: f64,
-}
-
 struct Transform([f64; 6]);
 
 impl Transform {
@@ -28,15 +27,197 @@ impl Transform {
 	}
 }
 
+/// A source raster, either a single GDAL dataset or a directory of 1° by 1° cells.
 pub struct Raster {
+	source: Source,
+}
+
+enum Source {
+	Single(RasterFile),
+	Tiled(HashMap<(i16, i16), Cell>),
+}
+
+/// A file in a tiled source, opened when it is first needed.
+struct Cell {
 	path: PathBuf,
-	set: ThreadLocal<Dataset>,
-	transform: Transform,
+	file: OnceLock<Option<RasterFile>>,
+}
+
+impl Cell {
+	fn get(&self) -> Option<&RasterFile> {
+		self.file
+			.get_or_init(|| match RasterFile::load(&self.path) {
+				Ok(x) => Some(x),
+				Err(e) => {
+					println!("\nError loading {}: {}", self.path.display(), e);
+					None
+				},
+			})
+			.as_ref()
+	}
 }
 
 impl Raster {
 	pub fn load(path: &Path) -> Result<Self, GdalError> {
-		tracy::zone!("Load raster");
+		Ok(Self {
+			source: Source::Single(RasterFile::load(path)?),
+		})
+	}
+
+	/// Load a directory of files that each cover a 1° by 1° cell, named after their bottom-left corner like SRTM
+	/// tiles (`N37W122.hgt`). Files are only opened when data inside them is requested.
+	pub fn load_tiled(dir: &Path) -> Result<Self, std::io::Error> {
+		zone!("Load tiled raster");
+
+		let mut cells = HashMap::new();
+		for entry in std::fs::read_dir(dir)? {
+			let path = entry?.path();
+			if !path.is_file() {
+				continue;
+			}
+
+			if let Some(pos) = path.file_stem().and_then(|x| x.to_str()).and_then(parse_cell_name) {
+				cells.insert(
+					pos,
+					Cell {
+						path,
+						file: OnceLock::new(),
+					},
+				);
+			}
+		}
+
+		if cells.is_empty() {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::NotFound,
+				"No tiles found in directory",
+			));
+		}
+
+		Ok(Self {
+			source: Source::Tiled(cells),
+		})
+	}
+
+	pub fn get_data<T: GdalType + Copy>(
+		&self, bottom_left: LatLon, top_right: LatLon, res: usize, resample: ResampleAlg,
+	) -> Option<Vec<T>> {
+		self.file_for(bottom_left)?
+			.get_data(bottom_left, top_right, res, resample)
+	}
+
+	pub fn get_data_for_hillshade<T: GdalType + Copy + Default>(
+		&self, bottom_left: LatLon, top_right: LatLon, res: usize, resample: ResampleAlg,
+	) -> Option<(Vec<T>, bool)> {
+		match &self.source {
+			Source::Single(file) => file.get_data_for_hillshade(bottom_left, top_right, res, resample),
+			Source::Tiled(cells) => match mosaic(cells, bottom_left, top_right, res, resample) {
+				Some(data) => Some((data, true)),
+				// Without every neighbouring cell, the edges are shaded from the cell alone.
+				None => self
+					.get_data(bottom_left, top_right, res, resample)
+					.map(|data| (data, false)),
+			},
+		}
+	}
+
+	/// The value that marks pixels without data, for the file that the tile at `bottom_left` is read from. `None` if
+	/// the file doesn't have one, in which case every pixel has data.
+	pub fn no_data(&self, bottom_left: LatLon) -> Option<f64> { self.file_for(bottom_left)?.no_data }
+
+	/// A tile covers a single cell of a tiled source, so the cell is picked by the bottom-left corner. Only the border
+	/// of the hillshade crosses into other cells, which `mosaic` reads.
+	fn file_for(&self, bottom_left: LatLon) -> Option<&RasterFile> {
+		match &self.source {
+			Source::Single(file) => Some(file),
+			Source::Tiled(cells) => cells
+				.get(&(bottom_left.lat.floor() as i16, bottom_left.lon.floor() as i16))?
+				.get(),
+		}
+	}
+}
+
+/// Read the window grown by one output pixel on every side from every cell that it overlaps, so that the border used
+/// for the hillshade comes from the neighbouring cells. Returns `None` if any of them is missing, or fails to read.
+fn mosaic<T: GdalType + Copy + Default>(
+	cells: &HashMap<(i16, i16), Cell>, bottom_left: LatLon, top_right: LatLon, res: usize, resample: ResampleAlg,
+) -> Option<Vec<T>> {
+	zone!("Mosaic raster data");
+
+	let size = res + 2;
+	let dx = (top_right.lon - bottom_left.lon) / res as f64;
+	let dy = (top_right.lat - bottom_left.lat) / res as f64;
+	let (left, right) = (bottom_left.lon - dx, top_right.lon + dx);
+	let (bottom, top) = (bottom_left.lat - dy, top_right.lat + dy);
+
+	let mut out = vec![T::default(); size * size];
+	for lat in bottom.floor() as i16..top.ceil() as i16 {
+		for lon in left.floor() as i16..right.ceil() as i16 {
+			// The pixels of the window inside this cell, with rows counted from the top.
+			let x0 = (((lon as f64).max(left) - left) / dx).round() as usize;
+			let x1 = (((lon as f64 + 1.0).min(right) - left) / dx).round() as usize;
+			let y0 = ((top - (lat as f64 + 1.0).min(top)) / dy).round() as usize;
+			let y1 = ((top - (lat as f64).max(bottom)) / dy).round() as usize;
+			if x0 >= x1 || y0 >= y1 {
+				continue;
+			}
+
+			let cell_bottom_left = LatLon {
+				lat: top - y1 as f64 * dy,
+				lon: left + x0 as f64 * dx,
+			};
+			let cell_top_right = LatLon {
+				lat: top - y0 as f64 * dy,
+				lon: left + x1 as f64 * dx,
+			};
+			let file = cells.get(&(lat, lon))?.get()?;
+			let data: Vec<T> = file.read(cell_bottom_left, cell_top_right, (x1 - x0, y1 - y0), resample)?;
+			for (y, row) in data.chunks_exact(x1 - x0).enumerate() {
+				let start = (y0 + y) * size + x0;
+				out[start..start + row.len()].copy_from_slice(row);
+			}
+		}
+	}
+
+	Some(out)
+}
+
+/// Parse the position of the bottom-left corner from names like `N37W122` or `s12_e045_1arc_v3`.
+fn parse_cell_name(name: &str) -> Option<(i16, i16)> {
+	fn parse_degrees(part: &str, positive: char, negative: char) -> Option<i16> {
+		let mut chars = part.chars();
+		let sign = match chars.next()? {
+			x if x == positive => 1,
+			x if x == negative => -1,
+			_ => return None,
+		};
+		let digits: String = chars.take_while(|x| x.is_ascii_digit()).collect();
+		digits.parse::<i16>().ok().map(|x| x * sign)
+	}
+
+	let name = name.to_ascii_uppercase();
+	let split = name.find(|x: char| x == 'E' || x == 'W')?;
+	let (lat, lon) = name.split_at(split);
+	let lat = parse_degrees(lat, 'N', 'S')?;
+	let lon = parse_degrees(lon, 'E', 'W')?;
+
+	if (-90..90).contains(&lat) && (-180..180).contains(&lon) {
+		Some((lat, lon))
+	} else {
+		None
+	}
+}
+
+struct RasterFile {
+	path: PathBuf,
+	set: ThreadLocal<Dataset>,
+	transform: Transform,
+	no_data: Option<f64>,
+}
+
+impl RasterFile {
+	fn load(path: &Path) -> Result<Self, GdalError> {
+		zone!("Load raster");
 
 		let dataset = Dataset::open(path)?;
 		let transform = dataset.geo_transform()?;
@@ -45,6 +226,8 @@ impl Raster {
 		assert_eq!(transform[4], 0.0, "column rotation must be 0");
 		assert!(transform[5] <= 0.0, "y scale must be negative");
 
+		let no_data = dataset.rasterband(1)?.no_data_value();
+
 		let set = ThreadLocal::new();
 		set.get_or(|| dataset);
 
@@ -52,11 +235,22 @@ impl Raster {
 			path: path.to_path_buf(),
 			set,
 			transform: Transform(transform),
+			no_data,
 		})
 	}
 
-	pub fn get_data<T: GdalType + Copy>(&self, bottom_left: LatLon, top_right: LatLon, res: usize) -> Option<Vec<T>> {
-		tracy::zone!("Get raster data");
+	fn get_data<T: GdalType + Copy>(
+		&self, bottom_left: LatLon, top_right: LatLon, res: usize, resample: ResampleAlg,
+	) -> Option<Vec<T>> {
+		self.read(bottom_left, top_right, (res, res), resample)
+	}
+
+	/// Read the window between `bottom_left` and `top_right`, resampled to `size` pixels. Windows narrower than a
+	/// pixel of the raster read a whole pixel.
+	fn read<T: GdalType + Copy>(
+		&self, bottom_left: LatLon, top_right: LatLon, size: (usize, usize), resample: ResampleAlg,
+	) -> Option<Vec<T>> {
+		zone!("Get raster data");
 
 		let set = self
 			.set
@@ -76,18 +270,18 @@ impl Raster {
 			.expect("Band with index 1 not present")
 			.read_as(
 				(xl, yt),
-				((xr - xl) as usize, (yb - yt) as usize),
-				(res, res),
-				Some(ResampleAlg::Lanczos),
+				(((xr - xl) as usize).max(1), ((yb - yt) as usize).max(1)),
+				size,
+				Some(resample),
 			)
 			.ok()
 			.map(|buf| buf.data)
 	}
 
-	pub fn get_data_for_hillshade<T: GdalType + Copy>(
-		&self, bottom_left: LatLon, top_right: LatLon, res: usize,
+	fn get_data_for_hillshade<T: GdalType + Copy>(
+		&self, bottom_left: LatLon, top_right: LatLon, res: usize, resample: ResampleAlg,
 	) -> Option<(Vec<T>, bool)> {
-		tracy::zone!("Get raster data");
+		zone!("Get raster data");
 
 		let set = self
 			.set
@@ -113,7 +307,7 @@ impl Raster {
 					(xl, yt),
 					((xr - xl) as usize, (yb - yt) as usize),
 					(res, res),
-					Some(ResampleAlg::Lanczos),
+					Some(resample),
 				)
 				.ok()
 				.map(|b| (b.data, false))
@@ -124,7 +318,7 @@ impl Raster {
 					(xl - 1, yt - 1),
 					((xr - xl) as usize + 2, (yb - yt) as usize + 2),
 					(res + 2, res + 2),
-					Some(ResampleAlg::Lanczos),
+					Some(resample),
 				)
 				.ok()
 				.map(|b| (b.data, true))
@@ -0,0 +1,44 @@
+use std::path::PathBuf;
+
+use clap::Args;
+use geo::{map_index_to_lat_lon, Access, Dataset};
+use rayon::prelude::*;
+
+#[derive(Args)]
+/// Check every tile against its checksum, to find tiles that were corrupted since the dataset was built.
+pub struct Verify {
+	input: PathBuf,
+}
+
+/// Exits with an error if any tile is corrupt, or if the dataset has no checksums to check.
+pub fn verify(verify: Verify) {
+	let dataset = match Dataset::load_with_advice(&verify.input, Access::Sequential) {
+		Ok(x) => x,
+		Err(err) => {
+			eprintln!("dataset could not be loaded: {}", err);
+			std::process::exit(1);
+		},
+	};
+	if !dataset.has_checksums() {
+		eprintln!(
+			"Dataset has no checksums, which are only stored since format version 13 (this is version {})",
+			dataset.format_version()
+		);
+		std::process::exit(1);
+	}
+
+	let corrupt: Vec<_> = (0..360 * 180)
+		.into_par_iter()
+		.map(map_index_to_lat_lon)
+		.filter_map(|(lat, lon)| dataset.verify_tile(lat, lon)?.err().map(|e| (lat, lon, e)))
+		.collect();
+
+	for (lat, lon, e) in corrupt.iter() {
+		println!("Tile {}, {}: {}", lat, lon, e);
+	}
+	println!("Checked {} tiles, {} corrupt", dataset.tile_count(), corrupt.len());
+
+	if !corrupt.is_empty() {
+		std::process::exit(1);
+	}
+}
@@ -1,37 +1,36 @@
 use std::{
 	error::Error,
+	fmt::{Debug, Display},
+	future::Future,
 	io::Write,
 	num::{NonZeroU32, NonZeroUsize},
 	path::PathBuf,
-	sync::Mutex,
+	sync::{Condvar, Mutex},
+	time::{Duration, Instant},
 };
 
-use dashmap::DashMap;
-use futures_lite::future::block_on;
+use dashmap::{mapref::one::RefMut, DashMap};
+use futures_lite::{
+	future::{block_on, poll_once},
+	pin,
+};
 use png::{BitDepth, ColorType, Encoder};
-use render::{FrameOptions, LatLon, Renderer, RendererOptions};
-use rouille::{try_or_400::ErrJson, Request, Response};
+use render::{FrameOptions, InvalidFrameOptions, LatLon, Overlays, Renderer, RendererOptions};
+use rouille::try_or_400::ErrJson;
+use serde::Deserialize;
 use tracy::wgpu::ProfileContext;
 use url::Url;
 
-struct RenderData {
-	renderer: Renderer,
-	res: (u32, u32),
+/// The texture a map is rendered to, and the buffer it is read back through. Every renderer renders maps of the same
+/// resolution to the same target, so a batch of maps at one resolution only allocates them once.
+struct RenderTarget {
 	texture: wgpu::Texture,
 	readback_buffer: wgpu::Buffer,
 	stride: NonZeroU32,
 }
 
-impl RenderData {
-	fn new(device: &wgpu::Device, path: PathBuf, width: u32, height: u32) -> Self {
-		let renderer = Renderer::new(
-			device,
-			&RendererOptions {
-				data_path: path,
-				output_format: wgpu::TextureFormat::Rgba8UnormSrgb,
-			},
-		)
-		.unwrap();
+impl RenderTarget {
+	fn new(device: &wgpu::Device, width: u32, height: u32) -> Self {
 		let texture = device.create_texture(&wgpu::TextureDescriptor {
 			label: None,
 			size: wgpu::Extent3d {
@@ -48,7 +47,7 @@ impl RenderData {
 
 		let stride = 4 * width;
 		let stride = NonZeroU32::new((stride + 256 - 1) & !255).unwrap();
-		let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+		let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
 			label: None,
 			size: (stride.get() * height) as _,
 			usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
@@ -56,23 +55,624 @@ impl RenderData {
 		});
 
 		Self {
-			renderer,
-			res: (width, height),
 			texture,
-			readback_buffer: buffer,
+			readback_buffer,
 			stride,
 		}
 	}
 }
 
+/// How long to wait for the GPU to finish a map before giving up on it.
+const GPU_DEADLINE: Duration = Duration::from_secs(10);
+
+/// The GPU didn't finish a map before [`GPU_DEADLINE`].
+struct GpuTimeout;
+
+impl Display for GpuTimeout {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "The GPU did not finish the map within {:?}", GPU_DEADLINE)
+	}
+}
+
+impl Debug for GpuTimeout {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { <Self as Display>::fmt(self, f) }
+}
+
+impl Error for GpuTimeout {}
+
+/// Poll `device` until `future` completes, instead of blocking on it, so that a hung GPU can't wedge the worker
+/// forever.
+fn wait<T>(device: &wgpu::Device, future: impl Future<Output = T>) -> Result<T, GpuTimeout> {
+	let deadline = Instant::now() + GPU_DEADLINE;
+	pin!(future);
+	loop {
+		device.poll(wgpu::Maintain::Poll);
+		if let Some(x) = block_on(poll_once(&mut future)) {
+			return Ok(x);
+		}
+		if Instant::now() >= deadline {
+			return Err(GpuTimeout);
+		}
+		std::thread::sleep(Duration::from_millis(1));
+	}
+}
+
+/// The address the server listens on.
+const ADDRESS: &str = "0.0.0.0:42069";
+
+/// The maps that are rendered at once unless `--max-renders` is given.
+const DEFAULT_MAX_RENDERS: usize = 2;
+
+/// The largest map, in pixels, unless `--max-pixels` is given. A 4096 by 4096 map needs 64 MiB for its render target,
+/// and as much again for its readback buffer.
+const DEFAULT_MAX_PIXELS: u64 = 4096 * 4096;
+
+/// The maps that can be rendered in a single `POST /maps`.
+const MAX_BATCH: usize = 64;
+const BOUNDARY: &str = "map-server-frame";
+
+/// How a map is encoded.
+#[derive(Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+	/// The rendered map, as an 8-bit RGBA PNG.
+	Png,
+	/// The terrain heights under each pixel as a 16-bit grayscale PNG, in meters plus 500. Pixels with no data are 0.
+	Png16,
+}
+
+impl Format {
+	fn parse(s: &str) -> Result<Self, Box<dyn Error>> {
+		match s {
+			"png" => Ok(Self::Png),
+			"png16" => Ok(Self::Png16),
+			_ => Err(From::from("unknown format")),
+		}
+	}
+}
+
+/// A single map, from the query of `GET /map.png` or an element of the array sent to `POST /maps`.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MapRequest {
+	id: u32,
+	res: (u32, u32),
+	pos: (f64, f64),
+	heading: f32,
+	range: f32,
+	alt: f32,
+	bg: [f32; 4],
+	transparent: bool,
+	legend: bool,
+	/// The radii of the range rings, in nautical miles.
+	rings: Vec<f32>,
+	fmt: Format,
+}
+
+impl Default for MapRequest {
+	fn default() -> Self {
+		Self {
+			id: 0,
+			res: (0, 0),
+			pos: (0.0, 0.0),
+			heading: 0.0,
+			range: 1.0,
+			alt: 0.0,
+			bg: [0.0, 0.0, 0.0, 1.0],
+			transparent: false,
+			legend: false,
+			rings: Vec::new(),
+			fmt: Format::Png,
+		}
+	}
+}
+
+impl MapRequest {
+	fn from_query(url: &Url) -> Result<Self, Box<dyn Error>> {
+		let mut map = Self::default();
+		for (key, val) in url.query_pairs() {
+			match key.as_ref() {
+				"id" => map.id = val.parse::<u32>()?,
+				"res" => {
+					let mut split = val.split(',');
+					map.res.0 = split.next().ok_or("missing res x")?.parse()?;
+					map.res.1 = split.next().ok_or("missing res y")?.parse()?;
+				},
+				"pos" => {
+					let mut split = val.split(',');
+					map.pos.0 = split.next().ok_or("missing pos lat")?.parse()?;
+					let lon = split.next().ok_or("missing pos lon")?.parse()?;
+					// Accept longitudes in [0, 360) too.
+					map.pos.1 = geo::normalize_lon(lon);
+				},
+				"heading" => map.heading = val.parse()?,
+				"range" => map.range = val.parse()?,
+				"alt" => map.alt = val.parse()?,
+				"transparent" => map.transparent = val.parse::<u8>()? != 0,
+				"legend" => map.legend = val.parse::<u8>()? != 0,
+				"rings" => {
+					map.rings = val.split(',').map(|x| x.parse::<f32>()).collect::<Result<_, _>>()?;
+				},
+				"fmt" => map.fmt = Format::parse(&val)?,
+				"bg" => {
+					let mut split = val.split(',');
+					for channel in map.bg.iter_mut() {
+						*channel = split.next().ok_or("missing bg channel")?.parse()?;
+					}
+				},
+				_ => return Err(From::from("unknown query param")),
+			}
+		}
+
+		Ok(map)
+	}
+
+	/// Build the frame options of the request, rejecting non-finite values.
+	fn frame_options(&self) -> Result<FrameOptions, InvalidFrameOptions> {
+		let mut opts = FrameOptions {
+			width: self.res.0,
+			height: self.res.1,
+			position: LatLon {
+				lat: self.pos.0,
+				lon: self.pos.1,
+			},
+			vertical_angle: self.range,
+			heading: self.heading,
+			altitude: self.alt,
+			background: self.bg,
+			transparent_missing: self.transparent,
+			overlays: Overlays {
+				legend: self.legend,
+				range_rings: self.rings.iter().map(|x| x * 1852.0).collect(),
+			},
+			..Default::default()
+		};
+		opts.validate()?;
+		Ok(opts)
+	}
+}
+
+/// A response, independent of the HTTP server that sends it.
+struct Reply {
+	status: u16,
+	content_type: String,
+	body: Vec<u8>,
+}
+
+impl Reply {
+	fn data(content_type: impl Into<String>, body: Vec<u8>) -> Self {
+		Self {
+			status: 200,
+			content_type: content_type.into(),
+			body,
+		}
+	}
+
+	fn error(status: u16, e: &(dyn Error + 'static)) -> Self {
+		Self {
+			status,
+			content_type: "application/json".into(),
+			body: serde_json::to_vec(&ErrJson::from_err(e)).unwrap(),
+		}
+	}
+
+	fn not_found() -> Self {
+		Self {
+			status: 404,
+			content_type: "text/plain".into(),
+			body: Vec::new(),
+		}
+	}
+}
+
+/// Bounds how many maps are rendered at once, so that a burst of requests waits for the GPU in turn, instead of all
+/// allocating their render targets and submitting work at the same time.
+struct RenderLimit {
+	max: usize,
+	in_flight: Mutex<usize>,
+	released: Condvar,
+}
+
+/// A render counted against a [`RenderLimit`], until it is dropped.
+struct RenderPermit<'a>(&'a RenderLimit);
+
+impl RenderLimit {
+	fn new(max: usize) -> Self {
+		Self {
+			max,
+			in_flight: Mutex::new(0),
+			released: Condvar::new(),
+		}
+	}
+
+	/// Wait until fewer than `max` maps are being rendered.
+	fn acquire(&self) -> RenderPermit<'_> {
+		let mut in_flight = self.in_flight.lock().unwrap();
+		while *in_flight >= self.max {
+			in_flight = self.released.wait(in_flight).unwrap();
+		}
+		*in_flight += 1;
+		RenderPermit(self)
+	}
+}
+
+impl Drop for RenderPermit<'_> {
+	fn drop(&mut self) {
+		*self.0.in_flight.lock().unwrap() -= 1;
+		self.0.released.notify_one();
+	}
+}
+
+struct Server {
+	path: PathBuf,
+	/// The level of detail every map is rendered with, if it doesn't depend on the range.
+	lod: Option<usize>,
+	device: wgpu::Device,
+	queue: wgpu::Queue,
+	profiler: Mutex<ProfileContext>,
+	id_to_renderer: DashMap<u32, Renderer>,
+	/// The render targets, by resolution.
+	targets: DashMap<(u32, u32), RenderTarget>,
+	renders: RenderLimit,
+	max_pixels: u64,
+}
+
+impl Server {
+	/// Respond to a request with `method`, for the path and query `url`, with the JSON `body` of `POST /maps`. Invalid
+	/// requests get a `400`.
+	fn respond(&self, method: &str, url: &str, body: &[u8]) -> Reply {
+		self.route(method, url, body).unwrap_or_else(|e| Reply::error(400, &*e))
+	}
+
+	fn route(&self, method: &str, url: &str, body: &[u8]) -> Result<Reply, Box<dyn Error>> {
+		let url = Url::parse(&format!("http://127.0.0.1{}", url))?;
+
+		match (method, url.path()) {
+			("GET", "/map.png") => {
+				let map = MapRequest::from_query(&url)?;
+				let opts = map.frame_options()?;
+				self.check_size(&opts)?;
+				Ok(self
+					.render(map.id, &opts, map.fmt)
+					.map_or_else(gpu_timeout, |png| Reply::data("image/png", png)))
+			},
+			("POST", "/maps") => {
+				let maps: Vec<MapRequest> = serde_json::from_slice(body)?;
+				if maps.len() > MAX_BATCH {
+					return Err(format!("at most {} maps can be rendered at once", MAX_BATCH).into());
+				}
+				// Validate every map before rendering any of them.
+				let maps = maps
+					.iter()
+					.map(|map| Ok((map.id, map.frame_options()?, map.fmt)))
+					.collect::<Result<Vec<_>, InvalidFrameOptions>>()?;
+				for (_, opts, _) in maps.iter() {
+					self.check_size(opts)?;
+				}
+				Ok(self.render_batch(&maps).unwrap_or_else(gpu_timeout))
+			},
+			_ => Ok(Reply::not_found()),
+		}
+	}
+
+	/// Reject maps that are too large to allocate the render targets for, before allocating them.
+	fn check_size(&self, opts: &FrameOptions) -> Result<(), Box<dyn Error>> {
+		let max_dimension = self.device.limits().max_texture_dimension_2d;
+		if opts.width as u64 * opts.height as u64 > self.max_pixels {
+			Err(format!("maps can have at most {} pixels", self.max_pixels).into())
+		} else if opts.width > max_dimension || opts.height > max_dimension {
+			Err(format!("maps can be at most {} pixels wide and high", max_dimension).into())
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Get the renderer for `id`.
+	fn renderer(&self, id: u32) -> RefMut<'_, u32, Renderer> {
+		self.id_to_renderer.entry(id).or_insert_with(|| {
+			Renderer::new(
+				&self.device,
+				&RendererOptions {
+					data_path: self.path.clone(),
+					output_format: wgpu::TextureFormat::Rgba8UnormSrgb,
+					water_color: RendererOptions::DEFAULT_WATER_COLOR,
+					use_hillshade: true,
+					egpws_bands: None,
+					forced_dataset: self.lod,
+				},
+			)
+			.unwrap()
+		})
+	}
+
+	/// Get the render target for maps of `res`.
+	fn target(&self, res: (u32, u32)) -> RefMut<'_, (u32, u32), RenderTarget> {
+		self.targets
+			.entry(res)
+			.or_insert_with(|| RenderTarget::new(&self.device, res.0, res.1))
+	}
+
+	/// Render a map with the renderer for `id` and encode it as a PNG of `format`.
+	fn render(&self, id: u32, opts: &FrameOptions, format: Format) -> Result<Vec<u8>, GpuTimeout> {
+		let _permit = self.renders.acquire();
+		match format {
+			Format::Png => self.render_color(id, opts).map_err(|e| {
+				// The readback buffer may still be waiting to be mapped, so start over with a new target.
+				self.targets.remove(&(opts.width, opts.height));
+				e
+			}),
+			Format::Png16 => Ok(self.render_heights(id, opts)),
+		}
+	}
+
+	fn render_color(&self, id: u32, opts: &FrameOptions) -> Result<Vec<u8>, GpuTimeout> {
+		let (device, queue) = (&self.device, &self.queue);
+		let res = (opts.width, opts.height);
+		let mut renderer = self.renderer(id);
+		let target = self.target(res);
+
+		{
+			let mut profiler = self.profiler.lock().unwrap();
+			let mut encoder = tracy::wgpu_command_encoder!(device, profiler, Default::default());
+
+			let view = target.texture.create_view(&Default::default());
+			renderer.render(opts, device, queue, &view, &mut encoder);
+
+			queue.submit([encoder.finish()]);
+			wait(device, queue.on_submitted_work_done())?;
+
+			let mut encoder = tracy::wgpu_command_encoder!(device, profiler, Default::default());
+			renderer.render(opts, device, queue, &view, &mut encoder);
+
+			encoder.copy_texture_to_buffer(
+				wgpu::ImageCopyTexture {
+					texture: &target.texture,
+					mip_level: 0,
+					origin: wgpu::Origin3d::ZERO,
+					aspect: wgpu::TextureAspect::All,
+				},
+				wgpu::ImageCopyBuffer {
+					buffer: &target.readback_buffer,
+					layout: wgpu::ImageDataLayout {
+						offset: 0,
+						bytes_per_row: Some(target.stride),
+						rows_per_image: Some(NonZeroU32::new(res.1).unwrap()),
+					},
+				},
+				wgpu::Extent3d {
+					width: res.0,
+					height: res.1,
+					depth_or_array_layers: 1,
+				},
+			);
+
+			queue.submit([encoder.finish()]);
+		}
+
+		let mut out: Vec<u8> = Vec::new();
+		{
+			let _ = wait(device, target.readback_buffer.slice(..).map_async(wgpu::MapMode::Read))?;
+			let view = target.readback_buffer.slice(..).get_mapped_range();
+
+			let mut encoder = Encoder::new(&mut out, res.0, res.1);
+			encoder.set_color(ColorType::Rgba);
+			encoder.set_depth(BitDepth::Eight);
+			let mut enc = encoder.write_header().unwrap();
+			let mut writer = enc.stream_writer().unwrap();
+			let stride = target.stride.get() as usize;
+
+			for i in 0..res.1 {
+				let i = i as usize;
+				writer.write(&view[i * stride..(i + 1) * stride]).unwrap();
+			}
+			writer.finish().unwrap();
+			enc.finish().unwrap();
+		}
+		target.readback_buffer.unmap();
+
+		Ok(out)
+	}
+
+	/// Read back the heights of a map with the renderer for `id`, so it reads the same level of detail as the color
+	/// output.
+	fn render_heights(&self, id: u32, opts: &FrameOptions) -> Vec<u8> {
+		let heights = self.renderer(id).readback_heights(opts);
+
+		let mut out: Vec<u8> = Vec::new();
+		{
+			let mut encoder = Encoder::new(&mut out, opts.width, opts.height);
+			encoder.set_color(ColorType::Grayscale);
+			encoder.set_depth(BitDepth::Sixteen);
+			let mut enc = encoder.write_header().unwrap();
+			// PNG samples are big-endian.
+			let data: Vec<_> = heights.iter().flat_map(|x| x.to_be_bytes()).collect();
+			enc.write_image_data(&data).unwrap();
+			enc.finish().unwrap();
+		}
+
+		out
+	}
+
+	/// Render several maps as a `multipart/mixed` response, with one PNG per part in the order they were requested.
+	fn render_batch(&self, maps: &[(u32, FrameOptions, Format)]) -> Result<Reply, GpuTimeout> {
+		// Render the maps of each resolution together, so they reuse the same render target one after another.
+		let mut order: Vec<_> = (0..maps.len()).collect();
+		order.sort_by_key(|&i| (maps[i].1.width, maps[i].1.height, maps[i].0));
+		let mut pngs = vec![Vec::new(); maps.len()];
+		for i in order {
+			pngs[i] = self.render(maps[i].0, &maps[i].1, maps[i].2)?;
+		}
+
+		let mut out = Vec::with_capacity(pngs.iter().map(|x| x.len() + 128).sum());
+		for png in pngs {
+			write!(
+				out,
+				"--{}\r\nContent-Type: image/png\r\nContent-Length: {}\r\n\r\n",
+				BOUNDARY,
+				png.len()
+			)
+			.unwrap();
+			out.extend_from_slice(&png);
+			out.extend_from_slice(b"\r\n");
+		}
+		write!(out, "--{}--\r\n", BOUNDARY).unwrap();
+
+		Ok(Reply::data(format!("multipart/mixed; boundary={}", BOUNDARY), out))
+	}
+}
+
+/// Pick the adapter matching `selector`, either as an index into the enumerated adapters or a case-insensitive
+/// substring of their name.
+fn select_adapter(instance: &wgpu::Instance, selector: &str) -> Option<wgpu::Adapter> {
+	let adapters: Vec<_> = instance.enumerate_adapters(wgpu::Backends::all()).collect();
+	if let Ok(index) = selector.parse::<usize>() {
+		return adapters.into_iter().nth(index);
+	}
+
+	let selector = selector.to_lowercase();
+	adapters
+		.into_iter()
+		.find(|adapter| adapter.get_info().name.to_lowercase().contains(&selector))
+}
+
+/// The GPU is broken rather than the request, so report a timeout as a server error.
+fn gpu_timeout(e: GpuTimeout) -> Reply { Reply::error(500, &e) }
+
+/// Serve with rouille, which renders every request on its own thread of a fixed pool.
+#[cfg(not(feature = "async"))]
+fn serve(server: Server) {
+	use std::io::Read;
+
+	use rouille::Response;
+
+	rouille::start_server_with_pool(
+		ADDRESS,
+		std::thread::available_parallelism().ok().map(NonZeroUsize::get),
+		move |req| {
+			let mut body = Vec::new();
+			if let Some(Err(e)) = req.data().map(|mut x| x.read_to_end(&mut body)) {
+				return Response::json(&ErrJson::from_err(&e)).with_status_code(400);
+			}
+
+			let reply = server.respond(req.method(), req.raw_url(), &body);
+			Response::from_data(reply.content_type, reply.body).with_status_code(reply.status)
+		},
+	);
+}
+
+/// Serve with axum, which handles connections asynchronously, and renders on a pool of blocking threads as large as
+/// the rouille pool. Requests that come in while every thread is rendering wait for one to be free, without holding
+/// a thread of their own.
+#[cfg(feature = "async")]
+fn serve(server: Server) {
+	use std::sync::Arc;
+
+	use axum::{
+		body::Bytes,
+		handler::Handler,
+		http::{header::CONTENT_TYPE, Method, StatusCode, Uri},
+		Router,
+	};
+
+	let server = Arc::new(server);
+	let handler = move |method: Method, uri: Uri, body: Bytes| {
+		let server = server.clone();
+		async move {
+			let reply = tokio::task::spawn_blocking(move || server.respond(method.as_str(), &uri.to_string(), &body))
+				.await
+				.unwrap_or_else(|e| Reply::error(500, &e));
+			let status = StatusCode::from_u16(reply.status).unwrap();
+			(status, [(CONTENT_TYPE, reply.content_type)], reply.body)
+		}
+	};
+
+	let threads = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+	let runtime = tokio::runtime::Builder::new_multi_thread()
+		.enable_all()
+		.max_blocking_threads(threads)
+		.build()
+		.unwrap();
+	runtime.block_on(async {
+		axum::Server::bind(&ADDRESS.parse().unwrap())
+			.serve(Router::new().fallback(handler.into_service()).into_make_service())
+			.await
+			.unwrap();
+	});
+}
+
 fn main() {
-	let path = PathBuf::from(std::env::args().nth(1).unwrap_or_else(|| {
-		println!("Usage: {} <path>", std::env::args().nth(0).unwrap());
+	let usage = || -> ! {
+		println!(
+			"Usage: {} [--gpu <name or index>] [--max-renders <count>] [--max-pixels <count>] [--max-decodes <count>] \
+			 [--lod <index>] <path>",
+			std::env::args().nth(0).unwrap()
+		);
 		std::process::exit(1);
-	}));
+	};
+
+	let mut path = None;
+	let mut lod = None;
+	let mut max_renders = DEFAULT_MAX_RENDERS;
+	let mut max_pixels = DEFAULT_MAX_PIXELS;
+	let mut gpu = std::env::var("MAP_SERVER_GPU").ok();
+	let mut args = std::env::args().skip(1);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--gpu" => gpu = Some(args.next().unwrap_or_else(|| usage())),
+			"--lod" => lod = Some(args.next().and_then(|x| x.parse().ok()).unwrap_or_else(|| usage())),
+			"--max-renders" => {
+				max_renders = args.next().and_then(|x| x.parse().ok()).unwrap_or_else(|| usage());
+				if max_renders == 0 {
+					usage();
+				}
+			},
+			"--max-pixels" => max_pixels = args.next().and_then(|x| x.parse().ok()).unwrap_or_else(|| usage()),
+			"--max-decodes" => {
+				let max = args.next().and_then(|x| x.parse().ok()).unwrap_or_else(|| usage());
+				geo::set_max_decodes_in_flight(Some(max));
+			},
+			_ if path.is_none() => path = Some(PathBuf::from(arg)),
+			_ => usage(),
+		}
+	}
+	let path = path.unwrap_or_else(|| usage());
+	if let Some(lod) = lod {
+		// Check it now, instead of failing on the first request.
+		let count = Renderer::lod_paths(&path).map_or(0, |x| x.len());
+		if lod >= count {
+			println!(
+				"--lod {} is out of range, the dataset has {} levels of detail",
+				lod, count
+			);
+			std::process::exit(1);
+		}
+	}
 
 	let instance = wgpu::Instance::new(wgpu::Backends::all());
-	let adapter = block_on(instance.request_adapter(&Default::default())).unwrap();
+	let adapter = match gpu {
+		Some(selector) => select_adapter(&instance, &selector).unwrap_or_else(|| {
+			println!("No adapter matches `{}`, the available adapters are:", selector);
+			for (i, adapter) in instance.enumerate_adapters(wgpu::Backends::all()).enumerate() {
+				let info = adapter.get_info();
+				println!("{}: {} ({:?})", i, info.name, info.backend);
+			}
+			std::process::exit(1);
+		}),
+		None => block_on(instance.request_adapter(&Default::default()))
+			.or_else(|| {
+				println!("Warning: no GPU found, falling back to a software adapter");
+				block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+					force_fallback_adapter: true,
+					..Default::default()
+				}))
+			})
+			.unwrap_or_else(|| {
+				println!("No adapter found");
+				std::process::exit(1);
+			}),
+	};
+	let info = adapter.get_info();
+	println!("Using adapter {} ({:?})", info.name, info.backend);
 
 	let timestamp_query = adapter.features().contains(wgpu::Features::TIMESTAMP_QUERY);
 
@@ -98,128 +698,17 @@ fn main() {
 		1,
 		timestamp_query,
 	));
-	let id_to_renderer: DashMap<u32, RenderData> = DashMap::new();
-
-	rouille::start_server_with_pool(
-		"0.0.0.0:42069",
-		std::thread::available_parallelism().ok().map(NonZeroUsize::get),
-		move |req| match (|req: &Request| -> Result<_, Box<dyn Error>> {
-			let url = Url::parse(&format!("http://127.0.0.1{}", req.raw_url()))?;
-
-			if url.path() != "/map.png" {
-				return Ok(Response::empty_404());
-			}
+	let server = Server {
+		path,
+		lod,
+		device,
+		queue,
+		profiler,
+		id_to_renderer: DashMap::new(),
+		targets: DashMap::new(),
+		renders: RenderLimit::new(max_renders),
+		max_pixels,
+	};
 
-			let mut id = 0;
-			let mut res = (0, 0);
-			let mut pos = (0.0, 0.0);
-			let mut heading = 0.0;
-			let mut altitude = 0.0;
-			let mut range = 1.0;
-			for (key, val) in url.query_pairs() {
-				match key.as_ref() {
-					"id" => id = val.parse::<u32>()?,
-					"res" => {
-						let mut split = val.split(',');
-						res.0 = split.next().ok_or("missing res x")?.parse()?;
-						res.1 = split.next().ok_or("missing res y")?.parse()?;
-					},
-					"pos" => {
-						let mut split = val.split(',');
-						pos.0 = split.next().ok_or("missing pos lat")?.parse()?;
-						pos.1 = split.next().ok_or("missing pos lon")?.parse()?;
-					},
-					"heading" => heading = val.parse()?,
-					"range" => range = val.parse()?,
-					"alt" => altitude = val.parse()?,
-					_ => return Err(From::from("unknown query param")),
-				}
-			}
-
-			let mut renderer = if let Some(mut renderer) = id_to_renderer.get_mut(&id) {
-				if renderer.res != res {
-					*renderer = RenderData::new(&device, path.clone(), res.0, res.1);
-				}
-				renderer
-			} else {
-				id_to_renderer.insert(id, RenderData::new(&device, path.clone(), res.0, res.1));
-				id_to_renderer.get_mut(&id).unwrap()
-			};
-
-			{
-				let mut profiler = profiler.lock().unwrap();
-				let mut encoder = tracy::wgpu_command_encoder!(device, profiler, Default::default());
-
-				let view = renderer.texture.create_view(&Default::default());
-				let opts = FrameOptions {
-					width: res.0,
-					height: res.1,
-					position: LatLon { lat: pos.0, lon: pos.1 },
-					vertical_angle: range,
-					heading,
-					altitude,
-				};
-				renderer.renderer.render(&opts, &device, &queue, &view, &mut encoder);
-
-				queue.submit([encoder.finish()]);
-				let _ = queue.on_submitted_work_done();
-				device.poll(wgpu::Maintain::Wait);
-
-				let mut encoder = tracy::wgpu_command_encoder!(device, profiler, Default::default());
-				renderer.renderer.render(&opts, &device, &queue, &view, &mut encoder);
-
-				encoder.copy_texture_to_buffer(
-					wgpu::ImageCopyTexture {
-						texture: &renderer.texture,
-						mip_level: 0,
-						origin: wgpu::Origin3d::ZERO,
-						aspect: wgpu::TextureAspect::All,
-					},
-					wgpu::ImageCopyBuffer {
-						buffer: &renderer.readback_buffer,
-						layout: wgpu::ImageDataLayout {
-							offset: 0,
-							bytes_per_row: Some(renderer.stride),
-							rows_per_image: Some(NonZeroU32::new(res.1).unwrap()),
-						},
-					},
-					wgpu::Extent3d {
-						width: res.0,
-						height: res.1,
-						depth_or_array_layers: 1,
-					},
-				);
-
-				queue.submit([encoder.finish()]);
-			}
-
-			let mut out: Vec<u8> = Vec::new();
-			{
-				let _ = renderer.readback_buffer.slice(..).map_async(wgpu::MapMode::Read);
-				device.poll(wgpu::Maintain::Wait);
-				let view = renderer.readback_buffer.slice(..).get_mapped_range();
-
-				let mut encoder = Encoder::new(&mut out, res.0, res.1);
-				encoder.set_color(ColorType::Rgba);
-				encoder.set_depth(BitDepth::Eight);
-				let mut enc = encoder.write_header().unwrap();
-				let mut writer = enc.stream_writer().unwrap();
-				let stride = renderer.stride.get() as usize;
-
-				for i in 0..res.1 {
-					let i = i as usize;
-					writer.write(&view[i * stride..(i + 1) * stride]).unwrap();
-				}
-				writer.finish().unwrap();
-				enc.finish().unwrap();
-			}
-			renderer.readback_buffer.unmap();
-
-			Ok(Response::from_data("image/png", out))
-		})(req)
-		{
-			Ok(x) => x,
-			Err(e) => Response::json(&ErrJson::from_err(&*e)).with_status_code(400),
-		},
-	);
+	serve(server);
 }
@@ -4,7 +4,7 @@ use egui::FontDefinitions;
 use egui_wgpu_backend::ScreenDescriptor;
 use egui_winit_platform::{Platform, PlatformDescriptor};
 use futures_lite::future::block_on;
-use tracing_subscriber::prelude::*;
+use tracing_subscriber::{filter::LevelFilter, prelude::*};
 use tracy::{tracing::TracyLayer, wgpu::ProfileContext};
 use wgpu::{
 	Backends,
@@ -41,7 +41,13 @@ mod ui;
 
 fn main() {
 	env_logger::init();
-	let _ = tracing::subscriber::set_global_default(tracing_subscriber::registry().with(TracyLayer)).unwrap();
+	// The renderer reports through `tracing`, so its events are printed as well as sent to tracy.
+	let _ = tracing::subscriber::set_global_default(
+		tracing_subscriber::registry()
+			.with(TracyLayer)
+			.with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::INFO)),
+	)
+	.unwrap();
 
 	let event_loop = EventLoop::new();
 	let window = WindowBuilder::new()
@@ -56,12 +62,22 @@ fn main() {
 
 	let instance = Instance::new(Backends::all());
 	let surface = unsafe { instance.create_surface(&window) };
-	let adapter = block_on(instance.request_adapter(&RequestAdapterOptions {
-		power_preference: PowerPreference::default(),
-		compatible_surface: Some(&surface),
-		force_fallback_adapter: false,
-	}))
-	.unwrap();
+	let request_adapter = |force_fallback_adapter| {
+		block_on(instance.request_adapter(&RequestAdapterOptions {
+			power_preference: PowerPreference::default(),
+			compatible_surface: Some(&surface),
+			force_fallback_adapter,
+		}))
+	};
+	let adapter = request_adapter(false)
+		.or_else(|| {
+			log::warn!("No GPU found, falling back to a software adapter");
+			request_adapter(true)
+		})
+		.unwrap_or_else(|| {
+			log::error!("No adapter found");
+			std::process::exit(1);
+		});
 
 	let timestamp_query = adapter.features().contains(Features::TIMESTAMP_QUERY);
 
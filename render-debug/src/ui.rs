@@ -1,4 +1,4 @@
-use egui::{Context, DragValue, Window};
+use egui::{CollapsingHeader, ComboBox, Context, DragValue, Grid, Window};
 use render::{FrameOptions, Renderer, RendererOptions};
 use tracy::wgpu::EncoderProfiler;
 use wgpu::{Device, Queue, TextureFormat, TextureView};
@@ -6,6 +6,9 @@ use wgpu::{Device, Queue, TextureFormat, TextureView};
 pub struct Ui {
 	data_path: String,
 	options: FrameOptions,
+	lod: Option<usize>,
+	/// In meters.
+	prefetch_radius: f32,
 	renderer: Option<Renderer>,
 }
 
@@ -14,6 +17,8 @@ impl Ui {
 		Self {
 			data_path: String::new(),
 			options: FrameOptions::default(),
+			lod: None,
+			prefetch_radius: 0.0,
 			renderer: None,
 		}
 	}
@@ -32,11 +37,32 @@ impl Ui {
 					if let Some(data) = rfd::FileDialog::new().pick_folder() {
 						if let Some(data_s) = data.to_str() {
 							self.data_path = data_s.into();
+							if let Some(renderer) = self.renderer.as_mut() {
+								// Keep the renderer, so that only the tiles are reloaded.
+								let swapped = match Renderer::lod_paths(&data) {
+									Ok(datasets) => renderer.set_datasets(datasets).map_err(|e| e.to_string()),
+									Err(e) => Err(e.to_string()),
+								};
+								match swapped {
+									Ok(()) => {
+										renderer.set_prefetch_radius(0.0);
+										self.lod = None;
+										self.prefetch_radius = 0.0;
+									},
+									Err(e) => log::error!("{}", e),
+								}
+								return;
+							}
+
 							let renderer = match Renderer::new(
 								device,
 								&RendererOptions {
 									data_path: data,
 									output_format: format,
+									water_color: RendererOptions::DEFAULT_WATER_COLOR,
+									use_hillshade: true,
+									egpws_bands: None,
+									forced_dataset: None,
 								},
 							) {
 								Ok(x) => x,
@@ -45,6 +71,8 @@ impl Ui {
 									return;
 								},
 							};
+							self.lod = None;
+							self.prefetch_radius = 0.0;
 							self.renderer = Some(renderer);
 						}
 					}
@@ -88,9 +116,93 @@ impl Ui {
 				ui.add(
 					DragValue::new(&mut self.options.altitude)
 						.clamp_range(0.0..=50000.0)
-						.speed(100.0),
+						.speed(100.0)
+						.suffix(" ft"),
 				);
 			});
+
+			ui.horizontal(|ui| {
+				ui.checkbox(&mut self.options.show_grid, "Grid");
+				ui.checkbox(&mut self.options.show_crosshair, "Crosshair");
+				ui.checkbox(&mut self.options.snap_to_texels, "Snap to texels");
+				ui.checkbox(&mut self.options.overlays.legend, "Legend");
+				let mut rings = !self.options.overlays.range_rings.is_empty();
+				if ui.checkbox(&mut rings, "Range rings").changed() {
+					// 10, 20, and 40 NM.
+					self.options.overlays.range_rings = if rings {
+						vec![18_520.0, 37_040.0, 74_080.0]
+					} else {
+						Vec::new()
+					};
+				}
+			});
+
+			if let Some(renderer) = self.renderer.as_mut() {
+				let lod = self.lod;
+				ComboBox::from_label("LOD")
+					.selected_text(lod.map_or("Auto".to_string(), |x| x.to_string()))
+					.show_ui(ui, |ui| {
+						ui.selectable_value(&mut self.lod, None, "Auto");
+						for i in 0..renderer.lod_count() {
+							ui.selectable_value(&mut self.lod, Some(i), i.to_string());
+						}
+					});
+				if self.lod != lod {
+					renderer.force_lod(self.lod);
+				}
+
+				CollapsingHeader::new("LODs").show(ui, |ui| {
+					Grid::new("lods").striped(true).show(ui, |ui| {
+						ui.label("");
+						ui.label("Path");
+						ui.label("Resolution");
+						ui.label("Rad/px");
+						ui.label("Loaded");
+						ui.end_row();
+
+						for (i, lod) in renderer.lod_info().into_iter().enumerate() {
+							ui.label(if lod.active { format!("> {}", i) } else { i.to_string() });
+							ui.label(lod.path.display().to_string());
+							ui.label(lod.resolution.to_string());
+							ui.label(format!("{:.2e}", lod.radians_per_pixel));
+							ui.label(lod.loaded_tiles.to_string());
+							ui.end_row();
+						}
+					});
+				});
+
+				CollapsingHeader::new("Frame").show(ui, |ui| {
+					let stats = renderer.frame_stats();
+					Grid::new("frame").striped(true).show(ui, |ui| {
+						ui.label("Uploaded");
+						ui.label(stats.tiles_uploaded.to_string());
+						ui.end_row();
+						ui.label("Collected");
+						ui.label(stats.tiles_collected.to_string());
+						ui.end_row();
+						ui.label("Atlas recreated");
+						ui.label(stats.atlas_recreated.to_string());
+						ui.end_row();
+						ui.label("Decode time");
+						ui.label(format!("{:.2} ms", stats.decode_time.as_secs_f64() * 1000.0));
+						ui.end_row();
+					});
+				});
+
+				ui.horizontal(|ui| {
+					ui.label("Prefetch Radius");
+					if ui
+						.add(
+							DragValue::new(&mut self.prefetch_radius)
+								.clamp_range(0.0..=500_000.0)
+								.speed(1000.0),
+						)
+						.changed()
+					{
+						renderer.set_prefetch_radius(self.prefetch_radius);
+					}
+				});
+			}
 		});
 
 		if let Some(renderer) = self.renderer.as_mut() {
@@ -0,0 +1,311 @@
+//! A CPU port of `render.wgsl`, so that the rendering geometry can be checked without a GPU.
+//!
+//! These work on a dataset directly, so unlike [`Renderer::render_cpu`](crate::Renderer::render_cpu) they don't need
+//! a renderer, or a wgpu device to create one with.
+
+use std::collections::HashMap;
+
+use geo::Dataset;
+
+use crate::{project::screen_to_latlon, EgpwsBands, FrameOptions, FEET_PER_METER};
+
+const L500: [f32; 3] = [0.00, 0.00, 0.00];
+const BELOW_SEA_LEVEL: [f32; 3] = [0.05, 0.05, 0.15];
+const UNKNOWN_TERRAIN: [f32; 3] = [0.41, 0.15, 0.42];
+const TAWS_MED_GREEN: [f32; 3] = [0.06, 0.36, 0.14];
+const TAWS_GREEN: [f32; 3] = [0.19, 0.64, 0.30];
+const TAWS_ORANGE: [f32; 3] = [0.76, 0.53, 0.10];
+const TAWS_YELLOW: [f32; 3] = [0.96, 0.98, 0.01];
+const TAWS_RED: [f32; 3] = [0.96, 0.00, 0.00];
+
+/// Set on samples from tiles that are missing from the dataset, above the water flag.
+const MISSING: u32 = 1 << 16;
+/// Where the coverage of a sample is stored, above the missing flag.
+const COVERAGE_SHIFT: u32 = 17;
+
+/// Decodes tiles from a dataset on demand, and samples them like `sample_globe` in the shader.
+struct Sampler<'a> {
+	dataset: &'a Dataset,
+	tile_size: usize,
+	tiles: HashMap<(u32, u32), Option<(Vec<u16>, Option<Vec<u8>>)>>,
+}
+
+/// The four samples around a pixel, with the weights to interpolate between them.
+struct Pixel {
+	samples: [u32; 4],
+	offset_x: f32,
+	offset_y: f32,
+}
+
+impl Pixel {
+	fn bilinear(&self, f: fn(u32) -> f32) -> f32 {
+		let [x, y, z, w] = self.samples;
+		let low = lerp(f(x), f(y), self.offset_x);
+		let high = lerp(f(z), f(w), self.offset_x);
+		lerp(low, high, self.offset_y)
+	}
+
+	fn height(&self) -> u32 { self.bilinear(|x| (x & 0x7fff) as f32) as u32 }
+
+	fn missing(&self) -> f32 { self.bilinear(|x| ((x >> 16) & 1) as f32) }
+}
+
+impl<'a> Sampler<'a> {
+	fn new(dataset: &'a Dataset) -> Self {
+		Self {
+			dataset,
+			tile_size: dataset.metadata().resolution as usize,
+			tiles: HashMap::new(),
+		}
+	}
+
+	fn sample(&mut self, lat: f32, lon: f32) -> u32 {
+		let (dataset, tile_size) = (self.dataset, self.tile_size);
+		let (tile_lon, tile_lat) = (lon as u32 % 360, (lat as u32).min(179));
+		let tile = self.tiles.entry((tile_lat, tile_lon)).or_insert_with(|| {
+			let (lat, lon) = (tile_lat as i16 - 90, tile_lon as i16 - 180);
+			let heights = dataset
+				.get_tile(lat, lon)
+				.and_then(|x| {
+					x.map_err(|e| tracing::error!(lat, lon, error = ?e, "Error loading tile"))
+						.ok()
+				})
+				.map(|x| x.0)?;
+			let coverage = dataset.get_coverage(lat, lon).and_then(|x| {
+				x.map_err(|e| tracing::error!(lat, lon, error = ?e, "Error loading coverage"))
+					.ok()
+			});
+			Some((heights, coverage))
+		});
+
+		match tile {
+			Some((heights, coverage)) => {
+				let x = ((lon - lon.floor()) * tile_size as f32) as usize;
+				let y = ((1.0 - (lat - lat.floor())) * tile_size as f32) as usize;
+				let index = y.min(tile_size - 1) * tile_size + x.min(tile_size - 1);
+				let coverage = coverage.as_ref().map_or(255, |x| x[index]) as u32;
+				heights[index] as u32 | coverage << COVERAGE_SHIFT
+			},
+			None => MISSING | 1 << 15 | 255 << COVERAGE_SHIFT,
+		}
+	}
+
+	/// Sample around the center of the pixel at `px`, `py`.
+	fn pixel(&mut self, options: &FrameOptions, px: u32, py: u32) -> Pixel {
+		let position = screen_to_latlon(options, px as f32 + 0.5, py as f32 + 0.5);
+		let lat = position.lat as f32 + 90.0;
+		let lon = (position.lon as f32 + 180.0).rem_euclid(360.0);
+
+		let tile_size = self.tile_size;
+		let pixel_offset = |x: f32| {
+			let pixel = x * tile_size as f32;
+			pixel - pixel.floor()
+		};
+		let mut offset_x = pixel_offset(lon - lon.floor());
+		let mut offset_y = pixel_offset(1.0 - (lat - lat.floor()));
+		if options.snap_to_texels {
+			offset_x = offset_x.round();
+			offset_y = offset_y.round();
+		}
+
+		let delta = 1.0 / tile_size as f32;
+		Pixel {
+			samples: [
+				self.sample(lat, lon),
+				self.sample(lat, lon + delta),
+				self.sample(lat - delta, lon),
+				self.sample(lat - delta, lon + delta),
+			],
+			offset_x,
+			offset_y,
+		}
+	}
+}
+
+/// Render a frame from `dataset` into tightly packed RGBA8 pixels, top row first.
+///
+/// The colors are written as they would appear on an sRGB target, with `water_color` and `egpws_bands` as in
+/// `RendererOptions`. Tiles are decoded on demand, so this is slow, and meant for tests rather than for display.
+pub fn render(
+	dataset: &Dataset, options: &FrameOptions, water_color: [f32; 4], egpws_bands: Option<EgpwsBands>,
+) -> Vec<u8> {
+	zone!("CPU Render");
+
+	let altitude = options.corrected_altitude();
+	let mut sampler = Sampler::new(dataset);
+	let mut out = Vec::with_capacity(options.width as usize * options.height as usize * 4);
+	for py in 0..options.height {
+		for px in 0..options.width {
+			let pixel = sampler.pixel(options, px, py);
+			let height = pixel.height();
+			let is_water = pixel.bilinear(|x| ((x >> 15) & 1) as f32);
+			let missing = pixel.missing();
+			let coverage = pixel.bilinear(|x| (x >> COVERAGE_SHIFT) as f32 / 255.0);
+
+			let color = if missing > 0.5 {
+				let [r, g, b, a] = options.background;
+				[r, g, b, if options.transparent_missing { 0.0 } else { a }]
+			} else if is_water > 0.5 {
+				water_color
+			} else {
+				let [r, g, b] = match egpws_bands {
+					Some(bands) => egpws_band(height, altitude, bands),
+					None => map_height(height, altitude),
+				};
+				[r, g, b, 1.0]
+			};
+			let color = if missing > 0.5 {
+				color
+			} else {
+				[0, 1, 2, 3].map(|i| lerp(options.background[i], color[i], coverage))
+			};
+			out.extend(color.iter().map(|&x| (x.clamp(0.0, 1.0) * 255.0).round() as u8));
+		}
+	}
+
+	out
+}
+
+/// Sample the heights under each pixel of a frame from `dataset`, top row first.
+///
+/// Each value is the interpolated height plus 500, in meters, like the raw tile data, with the water flag removed.
+/// Pixels on tiles missing from the dataset are 0.
+pub fn heights(dataset: &Dataset, options: &FrameOptions) -> Vec<u16> {
+	zone!("CPU Height Readback");
+
+	let mut sampler = Sampler::new(dataset);
+	let mut out = Vec::with_capacity(options.width as usize * options.height as usize);
+	for py in 0..options.height {
+		for px in 0..options.width {
+			let pixel = sampler.pixel(options, px, py);
+			out.push(if pixel.missing() > 0.5 {
+				0
+			} else {
+				pixel.height() as u16
+			});
+		}
+	}
+
+	out
+}
+
+fn map_height(height: u32, altitude: f32) -> [f32; 3] {
+	let feet = ((height as i32 - 500) as f32 * FEET_PER_METER) as i32;
+	let altitude_ft = altitude as i32;
+	if feet - 2000 > altitude_ft {
+		TAWS_RED
+	} else if feet - 1000 > altitude_ft {
+		TAWS_ORANGE
+	} else if feet > (altitude - 500.0) as i32 {
+		TAWS_YELLOW
+	} else if feet + 1000 > altitude_ft {
+		TAWS_MED_GREEN
+	} else if feet + 2000 > altitude_ft {
+		TAWS_GREEN
+	} else if height < 500 {
+		BELOW_SEA_LEVEL
+	} else if feet >= 500 && feet / 1000 > 32 {
+		UNKNOWN_TERRAIN
+	} else {
+		// Every elevation band below that is currently black.
+		L500
+	}
+}
+
+fn egpws_band(height: u32, altitude: f32, bands: EgpwsBands) -> [f32; 3] {
+	let clearance_ft = altitude - (height as f32 - 500.0) * FEET_PER_METER;
+	if clearance_ft < bands.warning_ft {
+		TAWS_RED
+	} else if clearance_ft < bands.caution_ft {
+		TAWS_YELLOW
+	} else {
+		TAWS_GREEN
+	}
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 { a + (b - a) * t }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{test_util::dataset, LatLon};
+
+	fn to_u8(color: [f32; 3]) -> [u8; 4] {
+		let [r, g, b] = color.map(|x| (x * 255.0).round() as u8);
+		[r, g, b, 255]
+	}
+
+	#[test]
+	fn renders_terrain_and_missing_tiles() {
+		// 3000 m is far above an aircraft at sea level, and the tile next to it is missing.
+		let dataset = dataset(&[(0, 0)], 32, |_, _| 3500);
+		let options = FrameOptions {
+			width: 8,
+			height: 8,
+			position: LatLon { lat: 0.5, lon: 0.5 },
+			vertical_angle: 0.001,
+			altitude: 0.0,
+			background: [0.0, 0.0, 1.0, 1.0],
+			..Default::default()
+		};
+
+		let pixels = render(&dataset, &options, [0.0; 4], None);
+		assert_eq!(pixels.len(), 8 * 8 * 4);
+		assert!(pixels.chunks_exact(4).all(|x| x == to_u8(TAWS_RED)));
+
+		let missing = FrameOptions {
+			position: LatLon { lat: 0.5, lon: 1.5 },
+			..options
+		};
+		let pixels = render(&dataset, &missing, [0.0; 4], None);
+		assert!(pixels.chunks_exact(4).all(|x| x == [0, 0, 255, 255]));
+	}
+
+	#[test]
+	fn altitude_offset_is_in_meters() {
+		// Terrain at 1000 m, with the aircraft 1050 ft above it, just outside the 1000 ft warning band.
+		let dataset = dataset(&[(0, 0)], 32, |_, _| 1500);
+		let options = |altitude_offset_m| FrameOptions {
+			width: 4,
+			height: 4,
+			position: LatLon { lat: 0.5, lon: 0.5 },
+			vertical_angle: 0.001,
+			altitude: 1000.0 * FEET_PER_METER + 1050.0,
+			altitude_offset_m,
+			..Default::default()
+		};
+		let band = |altitude_offset_m| -> [u8; 4] {
+			let pixels = render(
+				&dataset,
+				&options(altitude_offset_m),
+				[0.0; 4],
+				Some(EgpwsBands::default()),
+			);
+			pixels[..4].try_into().unwrap()
+		};
+
+		// 10 m is about 33 ft, which keeps the clearance above 1000 ft, while 30 m is about 98 ft, which doesn't.
+		assert_eq!(band(0.0), to_u8(TAWS_YELLOW));
+		assert_eq!(band(-10.0), to_u8(TAWS_YELLOW));
+		assert_eq!(band(-30.0), to_u8(TAWS_RED));
+	}
+
+	#[test]
+	fn heights_are_offset_and_zero_when_missing() {
+		let dataset = dataset(&[(0, 0)], 32, |_, _| 1234);
+		let options = FrameOptions {
+			width: 4,
+			height: 4,
+			position: LatLon { lat: 0.5, lon: 0.5 },
+			vertical_angle: 0.001,
+			..Default::default()
+		};
+		assert!(heights(&dataset, &options).iter().all(|&x| x == 1234));
+
+		let missing = FrameOptions {
+			position: LatLon { lat: -0.5, lon: 0.5 },
+			..options
+		};
+		assert!(heights(&dataset, &missing).iter().all(|&x| x == 0));
+	}
+}
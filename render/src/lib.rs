@@ -1,7 +1,11 @@
-use std::path::PathBuf;
+use std::{
+	error::Error,
+	fmt::{Debug, Display},
+	path::{Path, PathBuf},
+	time::Duration,
+};
 
 use geo::LoadError;
-use tracy::wgpu::EncoderProfiler;
 use wgpu::{
 	include_wgsl,
 	BindGroup,
@@ -12,12 +16,15 @@ use wgpu::{
 	BindGroupLayoutEntry,
 	BindingResource,
 	BindingType,
+	BlendState,
 	Buffer,
 	BufferBindingType,
 	BufferDescriptor,
 	BufferUsages,
 	Color,
 	ColorTargetState,
+	ColorWrites,
+	CommandEncoder,
 	Device,
 	FragmentState,
 	LoadOp,
@@ -28,6 +35,8 @@ use wgpu::{
 	RenderPassDescriptor,
 	RenderPipeline,
 	RenderPipelineDescriptor,
+	ShaderModuleDescriptor,
+	ShaderSource,
 	ShaderStages,
 	TextureFormat,
 	TextureSampleType,
@@ -38,21 +47,81 @@ use wgpu::{
 
 use crate::tile_cache::{TileCache, UploadStatus};
 
+/// `tracy::zone!`, if the `profiling` feature is enabled.
+macro_rules! zone {
+	($name:literal) => {
+		#[cfg(feature = "profiling")]
+		tracy::zone!($name);
+	};
+}
+
+pub mod cpu;
+pub mod project;
 pub mod range;
+#[cfg(test)]
+mod test_util;
 mod tile_cache;
 
-/// A polar coordinate, in degrees.
-#[derive(Copy, Clone, Debug)]
-pub struct LatLon {
-	pub lat: f32,
-	pub lon: f32,
-}
+pub use geo::LatLon;
 
 pub struct RendererOptions {
 	pub data_path: PathBuf,
+	/// One of `RendererOptions::OUTPUT_FORMATS`.
 	pub output_format: TextureFormat,
+	/// The RGBA color of water, in the same space as the terrain colors (sRGB).
+	///
+	/// Tiles are uploaded from `Dataset::get_tile`, which sets bit 15 of a height if the pixel is water. The atlas is
+	/// `R16Uint`, so the flag reaches the shader untouched, which masks it off the height and interpolates it on its
+	/// own. Pixels that are mostly water get this color, no matter their height. Missing tiles are drawn with
+	/// `FrameOptions::background` instead.
+	pub water_color: [f32; 4],
+	/// Allocate and bind a hillshade atlas. Without it, tiles are loaded without decoding their hillshade.
+	pub use_hillshade: bool,
+	/// Color terrain by its clearance below the aircraft instead of its elevation, like an EGPWS terrain display.
+	pub egpws_bands: Option<EgpwsBands>,
+	/// Always render with the level of detail dataset at this index, in the order they are listed in `_meta`, no
+	/// matter the range, so that the same view always renders the same. Unlike `Renderer::force_lod`, this can't be
+	/// changed after the renderer is created, and it is kept by `Renderer::set_datasets`.
+	pub forced_dataset: Option<usize>,
 }
 
+impl RendererOptions {
+	pub const DEFAULT_WATER_COLOR: [f32; 4] = [0.49, 0.65, 0.73, 1.0];
+	/// The formats that can be rendered to: color formats that the shaders can write floats to, and that the overlays
+	/// can be blended onto.
+	pub const OUTPUT_FORMATS: [TextureFormat; 6] = [
+		TextureFormat::Rgba8Unorm,
+		TextureFormat::Rgba8UnormSrgb,
+		TextureFormat::Bgra8Unorm,
+		TextureFormat::Bgra8UnormSrgb,
+		TextureFormat::Rgb10a2Unorm,
+		TextureFormat::Rgba16Float,
+	];
+}
+
+/// The clearances that terrain is colored by when it is compared against `FrameOptions::altitude`, in feet. Heights
+/// are stored in meters, and converted to feet in the shader.
+///
+/// Terrain less than `warning_ft` below the aircraft, or above it, is red. Terrain less than `caution_ft` below the
+/// aircraft is yellow, and everything lower is green.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct EgpwsBands {
+	pub warning_ft: f32,
+	pub caution_ft: f32,
+}
+
+impl Default for EgpwsBands {
+	fn default() -> Self {
+		Self {
+			warning_ft: 1000.0,
+			caution_ft: 2000.0,
+		}
+	}
+}
+
+/// Heights are stored in meters, while altitudes and the terrain bands are in feet.
+pub(crate) const FEET_PER_METER: f32 = 3.28084;
+
 pub struct FrameOptions {
 	/// The width of the output texture.
 	pub width: u32,
@@ -64,8 +133,75 @@ pub struct FrameOptions {
 	pub vertical_angle: f32,
 	/// Heading of the aircraft, in degrees.
 	pub heading: f32,
-	/// Altitude of the aircraft, in meters.
+	/// Altitude of the aircraft, in feet, above the same datum as the heights of the dataset.
 	pub altitude: f32,
+	/// Added to `altitude` before the terrain is compared against it, in meters, so it is converted to feet first.
+	/// Corrects for an altitude measured from another vertical datum than the heights of the dataset, such as a
+	/// GPS altitude above the WGS84 ellipsoid over a dataset referenced to the EGM96 geoid (see
+	/// `geo::Provenance`), which can be off by tens of meters.
+	pub altitude_offset_m: f32,
+	/// Draw lines at every integer latitude and longitude.
+	pub show_grid: bool,
+	/// Draw a crosshair at the position of the aircraft.
+	pub show_crosshair: bool,
+	/// Use the nearest texel of the tile instead of interpolating between the four around each pixel. The atlas is
+	/// read with `textureLoad`, so there is no sampler to configure, and the shader rounds the interpolation weights
+	/// instead. This stops the terrain from shimmering when panning slowly, at the cost of blockier zoomed in
+	/// terrain.
+	pub snap_to_texels: bool,
+	/// The RGBA color the output is cleared to, and that tiles missing from the dataset are drawn with, in the same
+	/// space as the terrain colors (sRGB). Pixels that are only partly covered by a `Layers::COVERAGE` fade into it.
+	pub background: [f32; 4],
+	/// Draw tiles missing from the dataset with an alpha of 0 instead of with `background`, for compositing the map
+	/// over another one. The rest of the output is still cleared to `background`. All of
+	/// `RendererOptions::OUTPUT_FORMATS` have an alpha channel, but `Rgb10a2Unorm` only has 2 bits of it.
+	pub transparent_missing: bool,
+	/// What to draw over the terrain.
+	pub overlays: Overlays,
+}
+
+/// Decorations drawn over the terrain in a second pass, so that they end up in the output image.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overlays {
+	/// Draw the colors of the terrain bands in a column at the right edge, with the highest terrain at the top. The
+	/// bottom edge of each band is labeled with the lowest height in it, in feet, which follows the altitude of the
+	/// aircraft.
+	pub legend: bool,
+	/// The radii of rings drawn around the aircraft, in meters. At most `MAX_RANGE_RINGS` can be drawn. The rings are
+	/// placed with the same projection as the terrain, so they stay aligned with it.
+	pub range_rings: Vec<f32>,
+}
+
+impl Overlays {
+	pub const MAX_RANGE_RINGS: usize = 8;
+}
+
+/// A level of detail dataset, as reported by [`Renderer::lod_info`].
+#[derive(Clone, Debug)]
+pub struct LodInfo {
+	pub path: PathBuf,
+	/// The width and height of its tiles.
+	pub resolution: u16,
+	/// The smallest radians per pixel of a frame that is rendered with this level of detail, unless it is forced.
+	pub radians_per_pixel: f32,
+	/// The tiles of this level of detail in the atlas. Only the active level has any, since the atlas is cleared when
+	/// the level changes.
+	pub loaded_tiles: usize,
+	/// If the last frame was rendered with this level of detail.
+	pub active: bool,
+}
+
+/// What the tile cache did while preparing the last frame, as reported by [`Renderer::frame_stats`].
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FrameStats {
+	/// The tiles written to the atlas, including prefetched tiles.
+	pub tiles_uploaded: usize,
+	/// The tiles removed from the atlas, because they weren't used anymore or to make room for others.
+	pub tiles_collected: usize,
+	/// If the atlas was full and grew, in which case every tile is uploaded again on the next frame.
+	pub atlas_recreated: bool,
+	/// The time spent decoding tiles.
+	pub decode_time: Duration,
 }
 
 impl Default for FrameOptions {
@@ -77,25 +213,118 @@ impl Default for FrameOptions {
 			vertical_angle: 0.297,
 			heading: 0.,
 			altitude: 10000.,
+			altitude_offset_m: 0.0,
+			show_grid: false,
+			show_crosshair: false,
+			snap_to_texels: false,
+			background: [0.0, 0.0, 0.0, 1.0],
+			transparent_missing: false,
+			overlays: Overlays::default(),
 		}
 	}
 }
 
+impl FrameOptions {
+	/// Check that the options describe a frame that can be rendered, clamping the latitude to `[-90, 90]` and wrapping
+	/// the longitude to `[-180, 180)`.
+	///
+	/// Non-finite values are rejected instead of clamped, since they would otherwise reach the cbuffer and render
+	/// garbage.
+	pub fn validate(&mut self) -> Result<(), InvalidFrameOptions> {
+		if self.width == 0 || self.height == 0 {
+			return Err(InvalidFrameOptions::EmptyOutput);
+		}
+
+		let fields = [
+			("lat", self.position.lat),
+			("lon", self.position.lon),
+			("vertical_angle", self.vertical_angle as f64),
+			("heading", self.heading as f64),
+			("altitude", self.altitude as f64),
+			("altitude_offset_m", self.altitude_offset_m as f64),
+		];
+		if let Some(&(field, _)) = fields.iter().find(|(_, x)| !x.is_finite()) {
+			return Err(InvalidFrameOptions::NonFinite(field));
+		}
+		if self.overlays.range_rings.iter().any(|x| !x.is_finite()) {
+			return Err(InvalidFrameOptions::NonFinite("range_rings"));
+		}
+		if self.overlays.range_rings.len() > Overlays::MAX_RANGE_RINGS {
+			return Err(InvalidFrameOptions::TooManyRangeRings);
+		}
+		if self.vertical_angle <= 0.0 {
+			return Err(InvalidFrameOptions::NonPositiveAngle);
+		}
+
+		self.position.lat = self.position.lat.clamp(-90.0, 90.0);
+		self.position.lon = geo::normalize_lon(self.position.lon);
+		Ok(())
+	}
+
+	/// `altitude` corrected by `altitude_offset_m`, in feet.
+	pub(crate) fn corrected_altitude(&self) -> f32 { self.altitude + self.altitude_offset_m * FEET_PER_METER }
+}
+
+pub enum InvalidFrameOptions {
+	/// The width or height of the output is zero.
+	EmptyOutput,
+	/// The named field is NaN or infinite.
+	NonFinite(&'static str),
+	/// The vertical angle is zero or negative.
+	NonPositiveAngle,
+	/// There are more than `Overlays::MAX_RANGE_RINGS` range rings.
+	TooManyRangeRings,
+}
+
+impl Display for InvalidFrameOptions {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Self::EmptyOutput => write!(f, "Output size must be nonzero"),
+			Self::NonFinite(field) => write!(f, "`{}` must be finite", field),
+			Self::NonPositiveAngle => write!(f, "Vertical angle must be positive"),
+			Self::TooManyRangeRings => write!(f, "At most {} range rings can be drawn", Overlays::MAX_RANGE_RINGS),
+		}
+	}
+}
+
+impl Debug for InvalidFrameOptions {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { Display::fmt(self, f) }
+}
+
+impl Error for InvalidFrameOptions {}
+
 pub struct Renderer {
 	cache: TileCache,
+	water_color: [f32; 4],
+	egpws_bands: Option<EgpwsBands>,
 	cbuffer: Buffer,
 	layout: BindGroupLayout,
 	pipeline: RenderPipeline,
 	group: BindGroup,
+	overlay_pipeline: RenderPipeline,
+	overlay_group: BindGroup,
 }
 
 impl Renderer {
-	const CBUFFER_SIZE: u64 = 48;
+	const CBUFFER_SIZE: u64 = 112;
+	const FLAG_EGPWS: u32 = 1 << 2;
+	const FLAG_LEGEND: u32 = 1 << 4;
+	const FLAG_RANGE_RINGS: u32 = 1 << 5;
+	const FLAG_SHOW_CROSSHAIR: u32 = 1 << 1;
+	const FLAG_SHOW_GRID: u32 = 1 << 0;
+	const FLAG_SNAP_TO_TEXELS: u32 = 1 << 3;
+	const FLAG_TRANSPARENT_MISSING: u32 = 1 << 6;
 
 	pub fn new(device: &Device, options: &RendererOptions) -> Result<Self, LoadError> {
-		let sets = std::fs::read_to_string(options.data_path.join("_meta"))?;
-		let datasets = sets.lines().map(|line| options.data_path.join(line)).collect();
-		let cache = TileCache::new(device, datasets)?;
+		if !RendererOptions::OUTPUT_FORMATS.contains(&options.output_format) {
+			return Err(LoadError::UnsupportedOutputFormat(format!(
+				"{:?}",
+				options.output_format
+			)));
+		}
+
+		let datasets = Self::lod_paths(&options.data_path)?;
+		let cache = TileCache::new(device, datasets, options.use_hillshade, options.forced_dataset)?;
 
 		let cbuffer = device.create_buffer(&BufferDescriptor {
 			label: Some("Map Render Constant Buffer"),
@@ -104,61 +333,102 @@ impl Renderer {
 			mapped_at_creation: false,
 		});
 
-		let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-			label: Some("Map Render Bind Group"),
-			entries: &[
-				BindGroupLayoutEntry {
-					binding: 0,
-					visibility: ShaderStages::FRAGMENT,
-					ty: BindingType::Buffer {
-						ty: BufferBindingType::Uniform,
-						has_dynamic_offset: false,
-						min_binding_size: None,
-					},
-					count: None,
+		let entries = [
+			BindGroupLayoutEntry {
+				binding: 0,
+				visibility: ShaderStages::FRAGMENT,
+				ty: BindingType::Buffer {
+					ty: BufferBindingType::Uniform,
+					has_dynamic_offset: false,
+					min_binding_size: None,
 				},
-				BindGroupLayoutEntry {
-					binding: 1,
-					visibility: ShaderStages::FRAGMENT,
-					ty: BindingType::Texture {
-						sample_type: TextureSampleType::Uint,
-						view_dimension: TextureViewDimension::D2,
-						multisampled: false,
-					},
-					count: None,
+				count: None,
+			},
+			BindGroupLayoutEntry {
+				binding: 1,
+				visibility: ShaderStages::FRAGMENT,
+				ty: BindingType::Texture {
+					sample_type: TextureSampleType::Uint,
+					view_dimension: TextureViewDimension::D2,
+					multisampled: false,
 				},
-				BindGroupLayoutEntry {
-					binding: 2,
-					visibility: ShaderStages::FRAGMENT,
-					ty: BindingType::Buffer {
-						ty: BufferBindingType::Storage { read_only: false },
-						has_dynamic_offset: false,
-						min_binding_size: None,
-					},
-					count: None,
+				count: None,
+			},
+			BindGroupLayoutEntry {
+				binding: 2,
+				visibility: ShaderStages::FRAGMENT,
+				ty: BindingType::Buffer {
+					ty: BufferBindingType::Storage { read_only: false },
+					has_dynamic_offset: false,
+					min_binding_size: None,
 				},
-				BindGroupLayoutEntry {
-					binding: 3,
-					visibility: ShaderStages::FRAGMENT,
-					ty: BindingType::Texture {
-						sample_type: TextureSampleType::Uint,
-						view_dimension: TextureViewDimension::D2,
-						multisampled: false,
-					},
-					count: None,
+				count: None,
+			},
+			BindGroupLayoutEntry {
+				binding: 3,
+				visibility: ShaderStages::FRAGMENT,
+				ty: BindingType::Texture {
+					sample_type: TextureSampleType::Uint,
+					view_dimension: TextureViewDimension::D2,
+					multisampled: false,
 				},
-				BindGroupLayoutEntry {
-					binding: 4,
-					visibility: ShaderStages::FRAGMENT,
-					ty: BindingType::Texture {
-						sample_type: TextureSampleType::Float { filterable: true },
-						view_dimension: TextureViewDimension::D2,
-						multisampled: false,
-					},
-					count: None,
+				count: None,
+			},
+			BindGroupLayoutEntry {
+				binding: 4,
+				visibility: ShaderStages::FRAGMENT,
+				ty: BindingType::Texture {
+					sample_type: TextureSampleType::Float { filterable: true },
+					view_dimension: TextureViewDimension::D2,
+					multisampled: false,
 				},
-			],
+				count: None,
+			},
+			BindGroupLayoutEntry {
+				binding: 5,
+				visibility: ShaderStages::FRAGMENT,
+				ty: BindingType::Texture {
+					sample_type: TextureSampleType::Float { filterable: true },
+					view_dimension: TextureViewDimension::D2,
+					multisampled: false,
+				},
+				count: None,
+			},
+		];
+		// The hillshade and coverage are the last bindings, and are left out if they aren't used.
+		let use_coverage = cache.coverage().is_some();
+		let mut layout_entries = entries[..4].to_vec();
+		if options.use_hillshade {
+			layout_entries.push(entries[4]);
+		}
+		if use_coverage {
+			layout_entries.push(entries[5]);
+		}
+		let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			label: Some("Map Render Bind Group"),
+			entries: &layout_entries,
+		});
+
+		let hillshade = if options.use_hillshade {
+			include_str!("shaders/hillshade.wgsl")
+		} else {
+			include_str!("shaders/no_hillshade.wgsl")
+		};
+		let coverage = if use_coverage {
+			include_str!("shaders/coverage.wgsl")
+		} else {
+			include_str!("shaders/no_coverage.wgsl")
+		};
+		let common = include_str!("shaders/common.wgsl");
+		let render_shader = device.create_shader_module(&ShaderModuleDescriptor {
+			label: Some("shaders/render.wgsl"),
+			source: ShaderSource::Wgsl(
+				[hillshade, coverage, common, include_str!("shaders/render.wgsl")]
+					.concat()
+					.into(),
+			),
 		});
+		let fullscreen = device.create_shader_module(&include_wgsl!("shaders/fullscreen.wgsl"));
 
 		let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
 			label: Some("Map Render Pipeline"),
@@ -168,7 +438,7 @@ impl Renderer {
 				push_constant_ranges: &[],
 			})),
 			vertex: VertexState {
-				module: &device.create_shader_module(&include_wgsl!("shaders/fullscreen.wgsl")),
+				module: &fullscreen,
 				entry_point: "main",
 				buffers: &[],
 			},
@@ -176,7 +446,7 @@ impl Renderer {
 			depth_stencil: None,
 			multisample: Default::default(),
 			fragment: Some(FragmentState {
-				module: &device.create_shader_module(&include_wgsl!("shaders/render.wgsl")),
+				module: &render_shader,
 				entry_point: "main",
 				targets: &[ColorTargetState::from(options.output_format)],
 			}),
@@ -185,100 +455,291 @@ impl Renderer {
 
 		let group = Self::make_bind_group(device, &layout, &cbuffer, &cache);
 
+		// The overlays only need the constants.
+		let overlay_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+			label: Some("Map Overlay Bind Group"),
+			entries: &entries[..1],
+		});
+		let overlay_shader = device.create_shader_module(&ShaderModuleDescriptor {
+			label: Some("shaders/overlay.wgsl"),
+			source: ShaderSource::Wgsl([common, include_str!("shaders/overlay.wgsl")].concat().into()),
+		});
+		let overlay_pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+			label: Some("Map Overlay Pipeline"),
+			layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
+				label: Some("Map Overlay Pipeline Layout"),
+				bind_group_layouts: &[&overlay_layout],
+				push_constant_ranges: &[],
+			})),
+			vertex: VertexState {
+				module: &fullscreen,
+				entry_point: "main",
+				buffers: &[],
+			},
+			primitive: Default::default(),
+			depth_stencil: None,
+			multisample: Default::default(),
+			fragment: Some(FragmentState {
+				module: &overlay_shader,
+				entry_point: "main",
+				targets: &[ColorTargetState {
+					format: options.output_format,
+					blend: Some(BlendState::ALPHA_BLENDING),
+					write_mask: ColorWrites::ALL,
+				}],
+			}),
+			multiview: None,
+		});
+		let overlay_group = device.create_bind_group(&BindGroupDescriptor {
+			label: Some("Map Overlay Bind Group"),
+			layout: &overlay_layout,
+			entries: &[BindGroupEntry {
+				binding: 0,
+				resource: cbuffer.as_entire_binding(),
+			}],
+		});
+
 		Ok(Self {
 			cache,
+			water_color: options.water_color,
+			egpws_bands: options.egpws_bands,
 			cbuffer,
 			pipeline,
 			group,
 			layout,
+			overlay_pipeline,
+			overlay_group,
 		})
 	}
 
+	/// Record a frame into `encoder`. A `tracy::wgpu::EncoderProfiler` can be passed too, as it derefs to a
+	/// `CommandEncoder`.
 	pub fn render(
 		&mut self, options: &FrameOptions, device: &Device, queue: &Queue, view: &TextureView,
-		encoder: &mut EncoderProfiler,
+		encoder: &mut CommandEncoder,
 	) {
-		tracy::zone!("Map Render");
+		zone!("Map Render");
 
-		if let UploadStatus::Resized = self
-			.cache
-			.populate_tiles(device, queue, options.height, options.vertical_angle)
-		{
+		if let UploadStatus::Resized = self.cache.populate_tiles(device, queue, options) {
 			self.group = Self::make_bind_group(device, &self.layout, &self.cbuffer, &self.cache);
 		}
 
 		{
-			tracy::zone!("Tile Status Clear");
+			zone!("Tile Status Clear");
 
 			encoder.clear_buffer(self.cache.tile_status(), 0, None);
-			queue.write_buffer(&self.cbuffer, 0, &Self::get_cbuffer_data(&self.cache, options));
+			queue.write_buffer(&self.cbuffer, 0, &self.get_cbuffer_data(options));
 		}
 
 		{
-			tracy::zone!("Render");
-
-			let mut pass = tracy::wgpu_render_pass!(
-				encoder,
-				RenderPassDescriptor {
-					label: Some("Map Render Pass"),
-					color_attachments: &[RenderPassColorAttachment {
-						view,
-						resolve_target: None,
-						ops: Operations {
-							load: LoadOp::Clear(Color::BLACK),
-							store: true,
-						},
-					}],
-					depth_stencil_attachment: None,
-				}
-			);
+			zone!("Render");
+
+			let mut pass = encoder.begin_render_pass(&RenderPassDescriptor {
+				label: Some("Map Render Pass"),
+				color_attachments: &[RenderPassColorAttachment {
+					view,
+					resolve_target: None,
+					ops: Operations {
+						load: LoadOp::Clear(Self::clear_color(options.background)),
+						store: true,
+					},
+				}],
+				depth_stencil_attachment: None,
+			});
 			pass.set_pipeline(&self.pipeline);
 			pass.set_bind_group(0, &self.group, &[]);
 			pass.draw(0..3, 0..1);
 		}
+
+		if options.overlays != Overlays::default() {
+			zone!("Overlays");
+
+			let mut pass = encoder.begin_render_pass(&RenderPassDescriptor {
+				label: Some("Map Overlay Pass"),
+				color_attachments: &[RenderPassColorAttachment {
+					view,
+					resolve_target: None,
+					ops: Operations {
+						load: LoadOp::Load,
+						store: true,
+					},
+				}],
+				depth_stencil_attachment: None,
+			});
+			pass.set_pipeline(&self.overlay_pipeline);
+			pass.set_bind_group(0, &self.overlay_group, &[]);
+			pass.draw(0..3, 0..1);
+		}
+	}
+
+	/// The level of detail datasets listed in the `_meta` file of `data_path`.
+	pub fn lod_paths(data_path: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+		let sets = std::fs::read_to_string(data_path.join("_meta"))?;
+		Ok(sets.lines().map(|line| data_path.join(line)).collect())
+	}
+
+	/// Render from a different set of level of detail datasets, keeping the pipelines and atlases. The tiles are
+	/// reloaded on the next frame, and a forced level of detail is reset.
+	///
+	/// The bindings of the renderer don't change, so if it was created without a coverage atlas, the coverage of the
+	/// new datasets is ignored. If the datasets fail to load, don't fit the atlas, or don't have the dataset of
+	/// `RendererOptions::forced_dataset`, the current ones are kept.
+	pub fn set_datasets(&mut self, datasets: Vec<PathBuf>) -> Result<(), LoadError> {
+		self.cache.set_datasets(datasets)
+	}
+
+	/// Force the level of detail to the dataset at `index`, in the order they are listed in `_meta`. `None` goes back
+	/// to choosing the level of detail from the range. The tiles are reloaded on the next frame. This is ignored if
+	/// `RendererOptions::forced_dataset` is set.
+	pub fn force_lod(&mut self, index: Option<usize>) { self.cache.force_lod(index); }
+
+	/// Load the tiles within `meters` around the view ahead of time, favoring the tiles ahead of the aircraft, so that
+	/// they are already loaded when they become visible. Zero, the default, only loads the tiles that were visible in
+	/// the last frame.
+	pub fn set_prefetch_radius(&mut self, meters: f32) { self.cache.set_prefetch_radius(meters); }
+
+	/// The number of level of detail datasets.
+	pub fn lod_count(&self) -> usize { self.cache.lod_count() }
+
+	/// Every level of detail dataset, in the order they are listed in `_meta`.
+	pub fn lod_info(&self) -> Vec<LodInfo> { self.cache.lod_info() }
+
+	/// What the tile cache did while preparing the last frame, to diagnose frame hitches.
+	pub fn frame_stats(&self) -> FrameStats { self.cache.frame_stats() }
+
+	/// The tiles of the current level of detail that failed to load, as latitude and longitude.
+	pub fn failed_tiles(&self) -> Vec<(i16, i16)> { self.cache.failed_tiles() }
+
+	/// Retry loading the tiles that failed to load.
+	pub fn clear_failed_tiles(&mut self) { self.cache.clear_failed(); }
+
+	/// Render a frame on the CPU, returning tightly packed RGBA8 pixels with the top row first.
+	///
+	/// This follows the same projection and coloring as the shader, and reads the same level of detail, but it decodes
+	/// tiles directly from the dataset instead of going through the atlas, and doesn't draw the overlays. It is meant
+	/// for testing the geometry without a GPU, and isn't expected to match the GPU output pixel-for-pixel. To render
+	/// without creating a renderer, and so without a GPU at all, use [`cpu::render`].
+	pub fn render_cpu(&self, options: &FrameOptions) -> Vec<u8> {
+		cpu::render(
+			self.cache.dataset_for_frame(options.height, options.vertical_angle),
+			options,
+			self.water_color,
+			self.egpws_bands,
+		)
+	}
+
+	/// Read back the terrain height under each pixel of a frame, top row first.
+	///
+	/// The frame is sampled like [`Self::render_cpu`], from the same level of detail the GPU would use. Each value is
+	/// the height plus 500, in meters, so that the heights below sea level fit. Pixels with no data are 0.
+	pub fn readback_heights(&self, options: &FrameOptions) -> Vec<u16> {
+		cpu::heights(
+			self.cache.dataset_for_frame(options.height, options.vertical_angle),
+			options,
+		)
 	}
 
 	fn make_bind_group(device: &Device, layout: &BindGroupLayout, cbuffer: &Buffer, cache: &TileCache) -> BindGroup {
+		let mut entries = vec![
+			BindGroupEntry {
+				binding: 0,
+				resource: cbuffer.as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 1,
+				resource: BindingResource::TextureView(cache.tile_map()),
+			},
+			BindGroupEntry {
+				binding: 2,
+				resource: cache.tile_status().as_entire_binding(),
+			},
+			BindGroupEntry {
+				binding: 3,
+				resource: BindingResource::TextureView(&cache.atlas()),
+			},
+		];
+		if let Some(hillshade) = cache.hillshade() {
+			entries.push(BindGroupEntry {
+				binding: 4,
+				resource: BindingResource::TextureView(hillshade),
+			});
+		}
+		if let Some(coverage) = cache.coverage() {
+			entries.push(BindGroupEntry {
+				binding: 5,
+				resource: BindingResource::TextureView(coverage),
+			});
+		}
+
 		device.create_bind_group(&BindGroupDescriptor {
 			label: Some("Map Render Bind Group"),
 			layout,
-			entries: &[
-				BindGroupEntry {
-					binding: 0,
-					resource: cbuffer.as_entire_binding(),
-				},
-				BindGroupEntry {
-					binding: 1,
-					resource: BindingResource::TextureView(cache.tile_map()),
-				},
-				BindGroupEntry {
-					binding: 2,
-					resource: cache.tile_status().as_entire_binding(),
-				},
-				BindGroupEntry {
-					binding: 3,
-					resource: BindingResource::TextureView(&cache.atlas()),
-				},
-				BindGroupEntry {
-					binding: 4,
-					resource: BindingResource::TextureView(&cache.hillshade()),
-				},
-			],
+			entries: &entries,
 		})
 	}
 
-	fn get_cbuffer_data(cache: &TileCache, options: &FrameOptions) -> [u8; Self::CBUFFER_SIZE as _] {
+	/// Clear colors are linear, while the shader converts its colors with a gamma of 2.2, so match that.
+	fn clear_color([r, g, b, a]: [f32; 4]) -> Color {
+		let linear = |x: f32| x.powf(2.2) as f64;
+		Color {
+			r: linear(r),
+			g: linear(g),
+			b: linear(b),
+			a: a as f64,
+		}
+	}
+
+	fn get_cbuffer_data(&self, options: &FrameOptions) -> [u8; Self::CBUFFER_SIZE as _] {
 		let mut data = [0; Self::CBUFFER_SIZE as _];
 
-		data[0..4].copy_from_slice(&options.position.lat.to_radians().to_le_bytes());
-		data[4..8].copy_from_slice(&options.position.lon.to_radians().to_le_bytes());
+		data[0..4].copy_from_slice(&(options.position.lat.to_radians() as f32).to_le_bytes());
+		data[4..8].copy_from_slice(&(options.position.lon.to_radians() as f32).to_le_bytes());
 
 		data[16..20].copy_from_slice(&options.vertical_angle.to_le_bytes());
 		let aspect_ratio = options.width as f32 / options.height as f32;
 		data[20..24].copy_from_slice(&aspect_ratio.to_le_bytes());
-		data[24..28].copy_from_slice(&cache.tile_size().to_le_bytes());
+		data[24..28].copy_from_slice(&self.cache.tile_size().to_le_bytes());
 		data[28..32].copy_from_slice(&(360. - options.heading).to_radians().to_le_bytes());
-		data[32..36].copy_from_slice(&options.altitude.to_le_bytes());
+		data[32..36].copy_from_slice(&options.corrected_altitude().to_le_bytes());
+
+		let mut flags = 0;
+		if options.show_grid {
+			flags |= Self::FLAG_SHOW_GRID;
+		}
+		if options.show_crosshair {
+			flags |= Self::FLAG_SHOW_CROSSHAIR;
+		}
+		if options.snap_to_texels {
+			flags |= Self::FLAG_SNAP_TO_TEXELS;
+		}
+		if options.overlays.legend {
+			flags |= Self::FLAG_LEGEND;
+		}
+		if !options.overlays.range_rings.is_empty() {
+			flags |= Self::FLAG_RANGE_RINGS;
+		}
+		if options.transparent_missing {
+			flags |= Self::FLAG_TRANSPARENT_MISSING;
+		}
+		if let Some(bands) = self.egpws_bands {
+			flags |= Self::FLAG_EGPWS;
+			data[40..44].copy_from_slice(&bands.warning_ft.to_le_bytes());
+			data[44..48].copy_from_slice(&bands.caution_ft.to_le_bytes());
+		}
+		data[36..40].copy_from_slice(&flags.to_le_bytes());
+
+		for (i, channel) in self.water_color.iter().enumerate() {
+			data[48 + i * 4..52 + i * 4].copy_from_slice(&channel.to_le_bytes());
+		}
+		for (i, channel) in options.background.iter().enumerate() {
+			data[64 + i * 4..68 + i * 4].copy_from_slice(&channel.to_le_bytes());
+		}
+		// Unused radii are left at 0, which the shader skips.
+		let rings = options.overlays.range_rings.iter().take(Overlays::MAX_RANGE_RINGS);
+		for (i, radius) in rings.enumerate() {
+			let angle = radius / project::EARTH_RADIUS;
+			data[80 + i * 4..84 + i * 4].copy_from_slice(&angle.to_le_bytes());
+		}
 
 		data
 	}
@@ -0,0 +1,173 @@
+//! The projection done by `render.wgsl`, for mapping points on the screen to the globe on the CPU.
+
+use crate::{FrameOptions, LatLon};
+
+/// The mean radius of the Earth in meters, for converting distances on the ground to the angles of the projection.
+pub const EARTH_RADIUS: f32 = geo::EARTH_RADIUS as f32;
+
+/// Get the position under a point of a frame, in pixels from the top-left corner of the frame. Pixel centers are at
+/// half-pixel offsets. The longitude is wrapped to `[-180, 180)`.
+pub fn screen_to_latlon(options: &FrameOptions, px: f32, py: f32) -> LatLon {
+	// The fullscreen triangle has v pointing up, while the rows of the frame go down.
+	let u = px / options.width as f32;
+	let v = 1.0 - py / options.height as f32;
+	let (lat, lon) = project(options, u, v);
+
+	LatLon {
+		lat: lat.to_degrees() as f64,
+		lon: ((lon.to_degrees() + 180.0).rem_euclid(360.0) - 180.0) as f64,
+	}
+}
+
+/// Project a point on the screen, with `v` pointing up, to a latitude and longitude in radians.
+fn project(options: &FrameOptions, u: f32, v: f32) -> (f32, f32) {
+	let aspect_ratio = options.width as f32 / options.height as f32;
+	project_view(
+		options.position,
+		options.heading,
+		aspect_ratio,
+		options.vertical_angle,
+		u,
+		v,
+	)
+}
+
+/// Like `project`, but for a view that isn't described by a whole frame, such as the grown view of the prefetch.
+pub(crate) fn project_view(
+	position: LatLon, heading: f32, aspect_ratio: f32, vertical_angle: f32, u: f32, v: f32,
+) -> (f32, f32) {
+	let heading = (360. - heading).to_radians();
+
+	let (headsin, headcos) = heading.sin_cos();
+	let x = (u - 0.5) * aspect_ratio;
+	let y = v - 0.5;
+	let x_rot = x * headcos - y * headsin;
+	let y_rot = x * headsin + y * headcos;
+	let x = x_rot * vertical_angle;
+	let y = y_rot * vertical_angle;
+
+	// The projection is done in `f32`, like in the shader.
+	let center_lat = position.lat.to_radians() as f32;
+	let center_lon = position.lon.to_radians() as f32;
+	let c = (x * x + y * y).sqrt();
+	if c == 0.0 {
+		return (center_lat, center_lon);
+	}
+
+	let (latsin, latcos) = center_lat.sin_cos();
+	let (csin, ccos) = c.sin_cos();
+	let lat = (ccos * latsin + y * csin * latcos / c).asin();
+	let lon = center_lon + (x * csin).atan2(c * latcos * ccos - y * latsin * csin);
+
+	(lat, lon)
+}
+
+/// The position `distance` meters away from `position`, starting out at `bearing` degrees clockwise from north.
+pub(crate) fn destination(position: LatLon, bearing: f32, distance: f32) -> LatLon {
+	let angle = distance / EARTH_RADIUS;
+	let bearing = bearing.to_radians();
+	let (latsin, latcos) = (position.lat.to_radians() as f32).sin_cos();
+	let (asin, acos) = angle.sin_cos();
+
+	let lat = (latsin * acos + latcos * asin * bearing.cos()).asin();
+	let lon = position.lon.to_radians() as f32 + (bearing.sin() * asin * latcos).atan2(acos - latsin * lat.sin());
+
+	LatLon {
+		lat: lat.to_degrees() as f64,
+		lon: lon.to_degrees() as f64,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{cpu, test_util::dataset};
+
+	const HEADINGS: [f32; 4] = [0.0, 45.0, 90.0, 200.0];
+	const RANGES: [f32; 2] = [0.01, 0.03];
+
+	fn options(heading: f32, vertical_angle: f32) -> FrameOptions {
+		FrameOptions {
+			width: 9,
+			height: 7,
+			position: LatLon { lat: 0.3, lon: 0.6 },
+			heading,
+			vertical_angle,
+			..Default::default()
+		}
+	}
+
+	/// The center and the corners of a frame, in pixels.
+	fn points(options: &FrameOptions) -> [(u32, u32); 5] {
+		let (w, h) = (options.width - 1, options.height - 1);
+		[(w / 2, h / 2), (0, 0), (w, 0), (0, h), (w, h)]
+	}
+
+	#[test]
+	fn matches_azimuthal_equidistant() {
+		for heading in HEADINGS {
+			for range in RANGES {
+				let options = options(heading, range);
+				let center = screen_to_latlon(&options, 4.5, 3.5);
+				assert!(center.distance_m(&options.position) < 1.0);
+
+				let aspect_ratio = options.width as f32 / options.height as f32;
+				for (px, py) in points(&options).into_iter().skip(1) {
+					let (px, py) = (px as f32 + 0.5, py as f32 + 0.5);
+					let x = (px / options.width as f32 - 0.5) * aspect_ratio;
+					let y = 0.5 - py / options.height as f32;
+					let expected_distance = (EARTH_RADIUS * range * x.hypot(y)) as f64;
+					let expected_bearing = (heading + x.atan2(y).to_degrees()) as f64;
+
+					let point = screen_to_latlon(&options, px, py);
+					let distance = options.position.distance_m(&point);
+					let bearing = options.position.bearing_deg(&point);
+					assert!(
+						(distance - expected_distance).abs() < expected_distance * 1e-3,
+						"heading {heading}, range {range}: {distance} m, expected {expected_distance} m"
+					);
+					let error = (bearing - expected_bearing).rem_euclid(360.0);
+					assert!(
+						error.min(360.0 - error) < 0.1,
+						"heading {heading}, range {range}: {bearing}°, expected {expected_bearing}°"
+					);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn matches_cpu_render() {
+		// Every tile has its own height, so the height under a point tells which tile it landed in.
+		let tiles: Vec<_> = (-2..=2).flat_map(|lat| (-2..=2).map(move |lon| (lat, lon))).collect();
+		let height = |lat: i16, lon: i16| (1000 + (lat + 2) * 5 + lon + 2) as u16;
+		let resolution = 16;
+		let dataset = dataset(&tiles, resolution, height);
+
+		let mut checked = 0;
+		for heading in HEADINGS {
+			for range in RANGES {
+				let options = options(heading, range);
+				let heights = cpu::heights(&dataset, &options);
+				for (px, py) in points(&options) {
+					let point = screen_to_latlon(&options, px as f32 + 0.5, py as f32 + 0.5);
+					// The pixel is interpolated with the texels after it, which may be in the next tile.
+					let edge = 2.0 / resolution as f64;
+					let near_edge = |x: f64| x - x.floor() < edge || x.ceil() - x < edge;
+					if near_edge(point.lat) || near_edge(point.lon) {
+						continue;
+					}
+
+					let expected = height(point.lat.floor() as i16, point.lon.floor() as i16);
+					let actual = heights[(py * options.width + px) as usize];
+					assert_eq!(
+						actual, expected,
+						"heading {heading}, range {range}, pixel ({px}, {py}) at {point:?}"
+					);
+					checked += 1;
+				}
+			}
+		}
+		assert!(checked >= 20, "only {checked} points were away from tile edges");
+	}
+}
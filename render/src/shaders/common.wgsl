@@ -0,0 +1,70 @@
+// Shared by `render.wgsl` and `overlay.wgsl`, which it is prepended to.
+
+struct LatLon {
+    lat: f32;
+    lon: f32;
+};
+
+struct Uniform {
+    map_center: LatLon;
+    [[align(16)]] vertical_diameter: f32;
+    aspect_ratio: f32;
+    tile_size: u32;
+    heading: f32;
+    altitude: f32;
+    flags: u32;
+    egpws_warning_ft: f32;
+    egpws_caution_ft: f32;
+    water_color: vec4<f32>;
+    background: vec4<f32>;
+    // The radii of the range rings in radians of arc, or 0 for unused rings.
+    ring_radii: array<vec4<f32>, 2>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> uniforms: Uniform;
+
+var<private> taws_med_green: vec3<f32> = vec3<f32>(0.06,0.36,0.14);
+var<private> taws_green: vec3<f32> = vec3<f32>(0.19,0.64,0.30);
+var<private> taws_orange: vec3<f32> = vec3<f32>(0.76,0.53,0.10);
+var<private> taws_yellow: vec3<f32> = vec3<f32>(0.96, 0.98, 0.01);
+var<private> taws_red: vec3<f32> = vec3<f32>(0.96, 0.00, 0.00);
+
+let FLAG_SHOW_GRID: u32 = 1u;
+let FLAG_SHOW_CROSSHAIR: u32 = 2u;
+let FLAG_EGPWS: u32 = 4u;
+let FLAG_SNAP_TO_TEXELS: u32 = 8u;
+let FLAG_LEGEND: u32 = 16u;
+let FLAG_RANGE_RINGS: u32 = 32u;
+let FLAG_TRANSPARENT_MISSING: u32 = 64u;
+
+fn degrees(radians: f32) -> f32 {
+    return radians * 57.295779513082322865;
+}
+
+// The offset of a point on the screen from the aircraft, rotated by the heading, in radians of arc. Its length is the
+// angular distance from the aircraft.
+fn screen_offset(uv: vec2<f32>) -> vec2<f32> {
+    let headsin = sin(uniforms.heading);
+    let headcos = cos(uniforms.heading);
+    let offset_uv = vec2<f32>(uv.x - 0.5, uv.y - 0.5);
+    let scaled_uv = vec2<f32>(offset_uv.x * uniforms.aspect_ratio, offset_uv.y);
+    let rotated_uv = vec2<f32>(scaled_uv.x * headcos - scaled_uv.y * headsin, scaled_uv.x * headsin + scaled_uv.y * headcos);
+    let uv = vec2<f32>(rotated_uv.x + 0.5, rotated_uv.y + 0.5);
+    return (uv - vec2<f32>(0.5, 0.5)) * uniforms.vertical_diameter;
+}
+
+fn project(uv: vec2<f32>) -> LatLon {
+    let xy = screen_offset(uv);
+
+    let latsin = sin(uniforms.map_center.lat);
+    let latcos = cos(uniforms.map_center.lat);
+    let c = sqrt(xy.x * xy.x + xy.y * xy.y);
+    let csin = sin(c);
+    let ccos = cos(c);
+
+    let lat = asin(ccos * latsin + xy.y * csin * latcos / c);
+    let lon = uniforms.map_center.lon + atan2(xy.x * csin, c * latcos * ccos - xy.y * latsin * csin);
+
+    return LatLon(lat, lon);
+}
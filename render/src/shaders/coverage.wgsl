@@ -0,0 +1,6 @@
+[[group(0), binding(5)]]
+var coverage_atlas: texture_2d<f32>;
+
+fn load_coverage(pixel: vec2<i32>) -> f32 {
+    return textureLoad(coverage_atlas, pixel, 0).x;
+}
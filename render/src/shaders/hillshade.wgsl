@@ -0,0 +1,6 @@
+[[group(0), binding(4)]]
+var hillshade_atlas: texture_2d<f32>;
+
+fn load_hillshade(pixel: vec2<i32>) -> f32 {
+    return textureLoad(hillshade_atlas, pixel, 0).x;
+}
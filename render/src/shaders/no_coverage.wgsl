@@ -0,0 +1,4 @@
+// Used instead of `coverage.wgsl` when no level of detail has a coverage layer.
+fn load_coverage(pixel: vec2<i32>) -> f32 {
+    return 1.0;
+}
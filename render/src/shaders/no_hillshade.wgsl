@@ -0,0 +1,4 @@
+// Used instead of `hillshade.wgsl` when the renderer has no hillshade atlas.
+fn load_hillshade(pixel: vec2<i32>) -> f32 {
+    return 1.0;
+}
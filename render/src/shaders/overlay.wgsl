@@ -0,0 +1,167 @@
+// Drawn over the terrain in a second pass, blended by alpha. `common.wgsl` is prepended to this file.
+
+var<private> ring: vec3<f32> = vec3<f32>(1.00, 1.00, 1.00);
+var<private> legend_border: vec3<f32> = vec3<f32>(1.00, 1.00, 1.00);
+var<private> label_text: vec3<f32> = vec3<f32>(1.00, 1.00, 1.00);
+var<private> label_backdrop: vec4<f32> = vec4<f32>(0.00, 0.00, 0.00, 0.60);
+
+// The width of the legend and its distance from the right edge of the screen, in pixels.
+let LEGEND_WIDTH: f32 = 16.0;
+let LEGEND_MARGIN: f32 = 8.0;
+// The size of a pixel of the label font, and the gap between the labels and the legend, in pixels.
+let LABEL_SCALE: f32 = 2.0;
+let LABEL_GAP: f32 = 4.0;
+
+// The colors of the legend from top to bottom, in the same order as the bands of `map_height` or `egpws_band`.
+fn legend_color(band: i32) -> vec3<f32> {
+    if ((uniforms.flags & FLAG_EGPWS) != 0u) {
+        switch (band) {
+            case 0: { return taws_red; }
+            case 1: { return taws_yellow; }
+            default: { return taws_green; }
+        }
+    } else {
+        switch (band) {
+            case 0: { return taws_red; }
+            case 1: { return taws_orange; }
+            case 2: { return taws_yellow; }
+            case 3: { return taws_med_green; }
+            default: { return taws_green; }
+        }
+    }
+}
+
+// The lowest height of a band of the legend, in feet.
+fn legend_threshold(band: i32) -> f32 {
+    let altitude = uniforms.altitude;
+    if ((uniforms.flags & FLAG_EGPWS) != 0u) {
+        switch (band) {
+            case 0: { return altitude - uniforms.egpws_warning_ft; }
+            default: { return altitude - uniforms.egpws_caution_ft; }
+        }
+    } else {
+        switch (band) {
+            case 0: { return altitude + 2000.0; }
+            case 1: { return altitude + 1000.0; }
+            case 2: { return altitude - 500.0; }
+            case 3: { return altitude - 1000.0; }
+            default: { return altitude - 2000.0; }
+        }
+    }
+}
+
+// A digit, or a minus sign for 10, as 5 rows of 3 pixels with the top row in the highest bits.
+fn glyph(c: i32) -> u32 {
+    switch (c) {
+        case 0: { return 31599u; }
+        case 1: { return 11415u; }
+        case 2: { return 29671u; }
+        case 3: { return 29647u; }
+        case 4: { return 23497u; }
+        case 5: { return 31183u; }
+        case 6: { return 31215u; }
+        case 7: { return 29257u; }
+        case 8: { return 31727u; }
+        case 9: { return 31695u; }
+        default: { return 448u; }
+    }
+}
+
+// Draw `value` right-aligned, with `pixel` in font pixels left of the right edge of the label and down from its top.
+// Returns the color of the pixel, which is transparent outside of the label.
+fn label(value: i32, pixel: vec2<f32>) -> vec4<f32> {
+    var digits = 1;
+    var power = 10;
+    for (var i = 0; i < 8; i = i + 1) {
+        if (abs(value) >= power) {
+            digits = digits + 1;
+            power = power * 10;
+        }
+    }
+    var chars = digits;
+    if (value < 0) {
+        chars = chars + 1;
+    }
+
+    // Every glyph is 3 pixels wide, with a gap of 1 pixel on its left, and the backdrop has a border of 1 pixel.
+    let cell = vec2<i32>(floor(pixel));
+    if (cell.x < -1 || cell.x >= chars * 4 || cell.y < -1 || cell.y > 5) {
+        return vec4<f32>(0.0);
+    }
+    let index = cell.x / 4;
+    let column = cell.x % 4;
+    if (cell.x < 0 || cell.y < 0 || cell.y > 4 || column == 3) {
+        return label_backdrop;
+    }
+
+    var c = 10;
+    if (index < digits) {
+        var divisor = 1;
+        for (var i = 0; i < index; i = i + 1) {
+            divisor = divisor * 10;
+        }
+        c = (abs(value) / divisor) % 10;
+    }
+    let bit = u32((4 - cell.y) * 3 + column);
+    if (((glyph(c) >> bit) & 1u) != 0u) {
+        return vec4<f32>(label_text, 1.0);
+    }
+    return label_backdrop;
+}
+
+[[stage(fragment)]]
+fn main([[location(0)]] uv: vec2<f32>) -> [[location(0)]] vec4<f32> {
+    // Derivatives have to be taken in uniform control flow, so do it before branching on the flags.
+    let arc = length(screen_offset(uv));
+    let arc_width = fwidth(arc);
+    let screen_size = 1.0 / fwidth(uv);
+
+    if ((uniforms.flags & FLAG_RANGE_RINGS) != 0u) {
+        for (var i = 0; i < 8; i = i + 1) {
+            let radius = uniforms.ring_radii[i / 4][i % 4];
+            if (radius > 0.0 && abs(arc - radius) / arc_width < 1.0) {
+                return vec4<f32>(pow(ring, vec3<f32>(2.2)), 1.0);
+            }
+        }
+    }
+
+    if ((uniforms.flags & FLAG_LEGEND) != 0u) {
+        // A column of the bands in the middle half of the right edge, with the highest terrain at the top.
+        let pixel = vec2<f32>((1.0 - uv.x) * screen_size.x, (1.0 - uv.y) * screen_size.y);
+        let top = screen_size.y * 0.25;
+        let height = screen_size.y * 0.5;
+        var bands = 5.0;
+        if ((uniforms.flags & FLAG_EGPWS) != 0u) {
+            bands = 3.0;
+        }
+        let inside = pixel.x >= LEGEND_MARGIN && pixel.x < LEGEND_MARGIN + LEGEND_WIDTH
+            && pixel.y >= top && pixel.y < top + height;
+        if (inside) {
+            let on_border = pixel.x < LEGEND_MARGIN + 1.0 || pixel.x >= LEGEND_MARGIN + LEGEND_WIDTH - 1.0
+                || pixel.y < top + 1.0 || pixel.y >= top + height - 1.0;
+            var color = legend_color(i32((pixel.y - top) / height * bands));
+            if (on_border) {
+                color = legend_border;
+            }
+            return vec4<f32>(pow(color, vec3<f32>(2.2)), 1.0);
+        }
+
+        // Label the bottom edge of every band with its lowest height, left of the column. The lowest band of the
+        // EGPWS bands has no lower bound, so it isn't labeled.
+        var labels = 5;
+        if ((uniforms.flags & FLAG_EGPWS) != 0u) {
+            labels = 2;
+        }
+        let right = LEGEND_MARGIN + LEGEND_WIDTH + LABEL_GAP;
+        for (var i = 0; i < labels; i = i + 1) {
+            let edge = top + height * f32(i + 1) / bands;
+            let local = vec2<f32>(pixel.x - right, pixel.y - edge) / LABEL_SCALE + vec2<f32>(0.0, 2.5);
+            let color = label(i32(round(legend_threshold(i))), local);
+            if (color.a > 0.0) {
+                return vec4<f32>(pow(color.rgb, vec3<f32>(2.2)), color.a);
+            }
+        }
+    }
+
+    return vec4<f32>(0.0);
+}
@@ -1,31 +1,16 @@
-struct LatLon {
-    lat: f32;
-    lon: f32;
-};
-
-struct Uniform {
-    map_center: LatLon;
-    [[align(16)]] vertical_diameter: f32;
-    aspect_ratio: f32;
-    tile_size: u32;
-    heading: f32;
-    altitude: f32;
-};
-
 struct TileStatus {
     values: array<u32>;
 };
 
-[[group(0), binding(0)]]
-var<uniform> uniforms: Uniform;
 [[group(0), binding(1)]]
 var tile_map: texture_2d<u32>;
 [[group(0), binding(2)]]
 var<storage, read_write> tile_status: TileStatus;
 [[group(0), binding(3)]]
 var tile_atlas: texture_2d<u32>;
-[[group(0), binding(4)]]
-var hillshade_atlas: texture_2d<f32>;
+// `load_hillshade` and binding 4 come from `hillshade.wgsl` or `no_hillshade.wgsl`, `load_coverage` and binding 5 from
+// `coverage.wgsl` or `no_coverage.wgsl`, and the uniforms and projection from `common.wgsl`, which are prepended to
+// this file.
 
 var<private> l500: vec3<f32> = vec3<f32>(0.00, 0.00, 0.00);
 var<private> l1000: vec3<f32> = vec3<f32>(0.00, 0.00, 0.00);
@@ -47,40 +32,10 @@ var<private> l19000: vec3<f32> = vec3<f32>(0.00, 0.00, 0.00);
 var<private> l21000: vec3<f32> = vec3<f32>(0.00, 0.00, 0.00);
 var<private> l33000: vec3<f32> = vec3<f32>(0.00, 0.00, 0.00);
 var<private> unknown_terrain: vec3<f32> = vec3<f32>(0.41, 0.15, 0.42);
-var<private> water: vec3<f32> = vec3<f32>(0.49,0.65,0.73);
-var<private> taws_med_green: vec3<f32> = vec3<f32>(0.06,0.36,0.14);
-var<private> taws_green: vec3<f32> = vec3<f32>(0.19,0.64,0.30);
-var<private> taws_orange: vec3<f32> = vec3<f32>(0.76,0.53,0.10);
-var<private> taws_yellow: vec3<f32> = vec3<f32>(0.96, 0.98, 0.01);
-var<private> taws_red: vec3<f32> = vec3<f32>(0.96, 0.00, 0.00);
+var<private> below_sea_level: vec3<f32> = vec3<f32>(0.05, 0.05, 0.15);
 var<private> rand_seed : vec2<f32>;
-
-
-
-fn degrees(radians: f32) -> f32 {
-    return radians * 57.295779513082322865;
-}
-
-fn project(uv: vec2<f32>) -> LatLon {
-    let headsin = sin(uniforms.heading);
-    let headcos = cos(uniforms.heading);
-    let offset_uv = vec2<f32>(uv.x - 0.5, uv.y - 0.5);
-    let scaled_uv = vec2<f32>(offset_uv.x * uniforms.aspect_ratio, offset_uv.y);
-    let rotated_uv = vec2<f32>(scaled_uv.x * headcos - scaled_uv.y * headsin, scaled_uv.x * headsin + scaled_uv.y * headcos);
-    let uv = vec2<f32>(rotated_uv.x + 0.5, rotated_uv.y + 0.5);
-    let xy = (uv - vec2<f32>(0.5, 0.5)) * uniforms.vertical_diameter;
-
-    let latsin = sin(uniforms.map_center.lat);
-    let latcos = cos(uniforms.map_center.lat);
-    let c = sqrt(xy.x * xy.x + xy.y * xy.y);
-    let csin = sin(c);
-    let ccos = cos(c);
-
-    let lat = asin(ccos * latsin + xy.y * csin * latcos / c);
-    let lon = uniforms.map_center.lon + atan2(xy.x * csin, c * latcos * ccos - xy.y * latsin * csin);
-
-    return LatLon(lat, lon);
-}
+var<private> grid: vec3<f32> = vec3<f32>(0.80, 0.80, 0.80);
+var<private> crosshair: vec3<f32> = vec3<f32>(1.00, 1.00, 1.00);
 
 fn map_height(height: u32) -> vec3<f32> {
     let feet = i32(f32(i32(height) - 500) * 3.28084);
@@ -94,7 +49,10 @@ fn map_height(height: u32) -> vec3<f32> {
         return taws_med_green;
     }else if(feet + 2000 > i32(uniforms.altitude)) {
         return taws_green;
-    }else if (feet < 500) {
+    } else if (height < 500u) {
+        // Dry land below sea level, like the Dead Sea or Death Valley.
+        return below_sea_level;
+    } else if (feet < 500) {
         return l500;
     } else {
         switch (feet / 1000) {
@@ -136,9 +94,23 @@ fn map_height(height: u32) -> vec3<f32> {
     }
 }
 
+fn egpws_band(height: u32) -> vec3<f32> {
+    // The altitude is already in feet, like the thresholds.
+    let clearance_ft = uniforms.altitude - (f32(height) - 500.0) * 3.28084;
+    if (clearance_ft < uniforms.egpws_warning_ft) {
+        return taws_red;
+    } else if (clearance_ft < uniforms.egpws_caution_ft) {
+        return taws_yellow;
+    } else {
+        return taws_green;
+    }
+}
+
 struct SampleResult {
     height: u32;
     hillshade: f32;
+    missing: f32;
+    coverage: f32;
 };
 
 fn sample_globe(lat: f32, lon: f32) -> SampleResult {
@@ -152,16 +124,18 @@ fn sample_globe(lat: f32, lon: f32) -> SampleResult {
     let unloaded = tile_offset.y == i32(atlas_dimensions.y);
 
     if (not_found) {
-        return SampleResult(1u << 15u, 1.0);
+        // Missing tiles are drawn with the background already, so they don't fade the tiles next to them.
+        return SampleResult(1u << 15u, 1.0, 1.0, 1.0);
     } else if (unloaded) {
-        return SampleResult(1u << 15u, 0.0);
+        return SampleResult(1u << 15u, 0.0, 0.0, 1.0);
     } else {
         let tile_uv = vec2<f32>(lon - floor(lon), 1.0 - (lat - floor(lat)));
         let pixel = vec2<f32>(tile_offset) + tile_uv * f32(uniforms.tile_size);
 
         let height = textureLoad(tile_atlas, vec2<i32>(pixel), 0).x;
-        let hillshade = textureLoad(hillshade_atlas, vec2<i32>(pixel), 0).x;
-        return SampleResult(height, mix(0.4, 1.0, hillshade));
+        let hillshade = load_hillshade(vec2<i32>(pixel));
+        let coverage = load_coverage(vec2<i32>(pixel));
+        return SampleResult(height, mix(0.4, 1.0, hillshade), 0.0, coverage);
     }
 }
 
@@ -176,7 +150,11 @@ fn main([[location(0)]] uv: vec2<f32>) -> [[location(0)]] vec4<f32> {
 
     let tile_uv = vec2<f32>(lon - floor(lon), 1.0 - (lat - floor(lat)));
     let pixel = tile_uv * f32(uniforms.tile_size);
-    let pixel_offset = pixel - floor(pixel);
+    var pixel_offset = pixel - floor(pixel);
+    if ((uniforms.flags & FLAG_SNAP_TO_TEXELS) != 0u) {
+        // Picks whichever of the four samples is closest.
+        pixel_offset = round(pixel_offset);
+    }
 
     let delta = 1.0 / f32(uniforms.tile_size);
     let x = sample_globe(lat, lon);
@@ -207,11 +185,50 @@ fn main([[location(0)]] uv: vec2<f32>) -> [[location(0)]] vec4<f32> {
     let xh_lerp = mix(z.hillshade, w.hillshade, pixel_offset.x);
     let hillshade = mix(xl_lerp, xh_lerp, pixel_offset.y);
 
+    let xl_lerp = mix(x.missing, y.missing, pixel_offset.x);
+    let xh_lerp = mix(z.missing, w.missing, pixel_offset.x);
+    let missing = mix(xl_lerp, xh_lerp, pixel_offset.y);
+
+    let xl_lerp = mix(x.coverage, y.coverage, pixel_offset.x);
+    let xh_lerp = mix(z.coverage, w.coverage, pixel_offset.x);
+    let coverage = mix(xl_lerp, xh_lerp, pixel_offset.y);
+
+    // Derivatives have to be taken in uniform control flow, so do it before branching on the flags.
+    let grid_pos = vec2<f32>(degrees(rad_position.lon), degrees(rad_position.lat));
+    let grid_distance = abs(fract(grid_pos + 0.5) - 0.5) / fwidth(grid_pos);
+    let center_distance = abs(uv - 0.5) / fwidth(uv);
+
+    // Bit 15 of each sample is the water flag from `Dataset::get_tile`, interpolated like the height.
     var ret: vec3<f32>;
-    if (is_water > 0.5) {
-        ret = water;
+    var alpha = 1.0;
+    if (missing > 0.5) {
+        ret = uniforms.background.rgb;
+        alpha = uniforms.background.a;
+        if ((uniforms.flags & FLAG_TRANSPARENT_MISSING) != 0u) {
+            alpha = 0.0;
+        }
+    } else if (is_water > 0.5) {
+        ret = uniforms.water_color.rgb;
+        alpha = uniforms.water_color.a;
+    } else if ((uniforms.flags & FLAG_EGPWS) != 0u) {
+        ret = egpws_band(height);
     } else {
         ret = map_height(height);
     }
-    return vec4<f32>(pow(ret, vec3<f32>(2.2)), 1.0);
+    // Partly covered pixels fade into the background, like the missing tiles they border.
+    ret = mix(uniforms.background.rgb, ret, coverage);
+    alpha = mix(uniforms.background.a, alpha, coverage);
+
+    if ((uniforms.flags & FLAG_SHOW_GRID) != 0u && min(grid_distance.x, grid_distance.y) < 1.0) {
+        ret = grid;
+    }
+    if ((uniforms.flags & FLAG_SHOW_CROSSHAIR) != 0u) {
+        let horizontal = center_distance.y < 1.0 && center_distance.x < 10.0;
+        let vertical = center_distance.x < 1.0 && center_distance.y < 10.0;
+        if (horizontal || vertical) {
+            ret = crosshair;
+        }
+    }
+
+    return vec4<f32>(pow(ret, vec3<f32>(2.2)), alpha);
 }
@@ -0,0 +1,36 @@
+//! Small in-memory datasets for tests.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use geo::{Dataset, DatasetBuilder, Layers, TileMetadata, FORMAT_VERSION};
+
+/// Build a dataset with the tiles at `tiles`, as `(lat, lon)`, where every pixel of a tile is `height(lat, lon)`,
+/// as a raw `height + 500`.
+pub fn dataset(tiles: &[(i16, i16)], resolution: u16, height: impl Fn(i16, i16) -> u16) -> Dataset {
+	static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+	let path = std::env::temp_dir().join(format!(
+		"render-test-{}-{}.geo",
+		std::process::id(),
+		NEXT.fetch_add(1, Ordering::Relaxed)
+	));
+	let metadata = TileMetadata {
+		version: FORMAT_VERSION,
+		resolution,
+		height_resolution: 1,
+		layers: Layers::NONE,
+		mini_tile_size: 0,
+	};
+	let builder = DatasetBuilder::new(&path, metadata).unwrap();
+	let len = resolution as usize * resolution as usize;
+	for &(lat, lon) in tiles {
+		builder
+			.add_tile(lat, lon, vec![height(lat, lon); len], vec![0; len], vec![0; len])
+			.unwrap();
+	}
+	builder.finish().unwrap();
+
+	let bytes = std::fs::read(&path).unwrap();
+	std::fs::remove_file(&path).unwrap();
+	Dataset::from_bytes(bytes.into()).unwrap()
+}
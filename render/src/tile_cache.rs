@@ -1,6 +1,6 @@
-use std::{num::NonZeroU32, path::PathBuf};
+use std::{num::NonZeroU32, path::PathBuf, time::Instant};
 
-use geo::{Dataset, LoadError};
+use geo::{map_lat_lon_to_index, Access, Dataset, Layers, LoadError};
 use wgpu::{
 	Buffer,
 	BufferDescriptor,
@@ -23,7 +23,13 @@ use wgpu::{
 	TextureViewDescriptor,
 };
 
-use crate::range::radians_per_pixel;
+use crate::{
+	project::{self, EARTH_RADIUS},
+	range::radians_per_pixel,
+	FrameOptions,
+	FrameStats,
+	LodInfo,
+};
 
 pub enum UploadStatus {
 	Uploads,
@@ -45,10 +51,19 @@ pub struct TileCache {
 	tile_status: Buffer,
 	atlas: Atlas,
 	tiles: Vec<TileOffset>,
+	/// How far around the view tiles are loaded before they become visible, in meters.
+	prefetch_radius: f32,
+	/// The tiles around the view of the last frame, which are kept loaded even if the GPU didn't use them.
+	prefetch: Vec<bool>,
 }
 
+/// The most prefetched tiles decoded in a frame, so that prefetching doesn't stall a single frame for long.
+const PREFETCH_PER_FRAME: usize = 4;
+
 impl TileCache {
-	pub fn new(device: &Device, datasets: Vec<PathBuf>) -> Result<Self, LoadError> {
+	pub fn new(
+		device: &Device, datasets: Vec<PathBuf>, use_hillshade: bool, pinned_dataset: Option<usize>,
+	) -> Result<Self, LoadError> {
 		let tile_map = device.create_texture(&TextureDescriptor {
 			label: Some("Tile Map"),
 			size: Extent3d {
@@ -74,7 +89,7 @@ impl TileCache {
 			mapped_at_creation: false,
 		});
 
-		let atlas = Atlas::new(device, datasets)?;
+		let atlas = Atlas::new(device, datasets, use_hillshade, pinned_dataset)?;
 
 		Ok(Self {
 			tile_map,
@@ -82,13 +97,20 @@ impl TileCache {
 			tile_status,
 			tiles: vec![atlas.unloaded(); 360 * 180],
 			atlas,
+			prefetch_radius: 0.0,
+			prefetch: vec![false; 360 * 180],
 		})
 	}
 
-	pub fn populate_tiles(&mut self, device: &Device, queue: &Queue, height: u32, vertical_angle: f32) -> UploadStatus {
-		tracy::zone!("Tile Population");
+	/// The tiles the GPU used last frame are loaded first. Tiles that are only prefetched are loaded afterwards, at
+	/// most `PREFETCH_PER_FRAME` every frame, and only into free space in the atlas, so they never evict used tiles
+	/// or grow the atlas.
+	pub fn populate_tiles(&mut self, device: &Device, queue: &Queue, options: &FrameOptions) -> UploadStatus {
+		zone!("Tile Population");
 
-		let radians_per_pixel = radians_per_pixel(height as _, vertical_angle);
+		let radians_per_pixel = radians_per_pixel(options.height as _, options.vertical_angle);
+		self.atlas.stats = FrameStats::default();
+		self.mark_prefetch(options);
 
 		if self.atlas.needs_clear(radians_per_pixel) {
 			self.clear(radians_per_pixel);
@@ -99,7 +121,7 @@ impl TileCache {
 			let _ = self.tile_status.slice(..).map_async(MapMode::Read);
 
 			{
-				tracy::zone!("GPU Readback Sync");
+				zone!("GPU Readback Sync");
 				device.poll(Maintain::Wait);
 			}
 
@@ -111,7 +133,7 @@ impl TileCache {
 					let index = (lat * 360 + lon) as usize;
 					let offset = &mut self.tiles[index];
 					if used[index] == 0 {
-						if *offset != self.atlas.unloaded() && *offset != self.atlas.not_found() {
+						if self.atlas.is_resident(*offset) && !self.prefetch[index] {
 							self.atlas.return_tile(*offset);
 							*offset = self.atlas.unloaded();
 						}
@@ -123,48 +145,45 @@ impl TileCache {
 					ret = UploadStatus::Uploads;
 					let lon = lon as i16 - 180;
 					let lat = lat as i16 - 90;
-					let tile = {
-						tracy::zone!("Load Tile");
-
-						let dataset = &self.atlas.datasets[self.atlas.curr_dataset];
-						if let Some(data) = dataset.get_tile(lat, lon) {
-							match data {
-								Ok(x) => x,
-								Err(e) => {
-									log::error!("Error loading tile: {:?}", e);
-									continue;
-								},
-							}
-						} else {
-							*offset = self.atlas.not_found();
+					let tile = match self.atlas.load_tile(lat, lon) {
+						Ok(x) => x,
+						Err(x) => {
+							*offset = x;
 							continue;
-						}
+						},
 					};
 
-					self.tiles[index] = if let Some(offset) = self.atlas.upload_tile(queue, &tile.0, &tile.1) {
+					self.tiles[index] = if let Some(offset) = self.atlas.upload_tile(queue, &tile) {
 						offset
 					} else if self.atlas.collect_tiles(used, &mut self.tiles, index) {
 						self.atlas
-							.upload_tile(queue, &tile.0, &tile.1)
+							.upload_tile(queue, &tile)
 							.expect("Tile GC returned None when it had to be Some")
 					} else {
 						if self.atlas.recreate_atlas(device) {
 							self.tiles.fill(self.atlas.unloaded());
 							ret = UploadStatus::Resized;
 						} else {
+							tracing::warn!("The atlas is full, so some tiles were not loaded");
 							ret = UploadStatus::AtlasFull;
 						}
 						break 'outer;
 					};
 				}
 			}
+
+			if let UploadStatus::Uploads | UploadStatus::NoUploads = ret {
+				if self.atlas.prefetch_tiles(queue, used, &self.prefetch, &mut self.tiles) {
+					ret = UploadStatus::Uploads;
+				}
+			}
 		}
 
 		self.tile_status.unmap();
 
 		{
 			if let UploadStatus::Uploads | UploadStatus::Resized = ret {
-				tracy::zone!("Tile Map Upload");
+				zone!("Tile Map Upload");
 
 				queue.write_texture(
 					self.tile_map.as_image_copy(),
@@ -191,6 +210,50 @@ impl TileCache {
 		ret
 	}
 
+	/// Load the tiles within `meters` of the view ahead of time, so that they don't pop in when they become visible.
+	/// Zero disables prefetching.
+	pub fn set_prefetch_radius(&mut self, meters: f32) { self.prefetch_radius = meters.max(0.0); }
+
+	/// Mark the tiles under the view grown by the prefetch radius on every side, and moved ahead by it along the
+	/// heading, since the tiles ahead of the aircraft are the ones most likely to become visible.
+	fn mark_prefetch(&mut self, options: &FrameOptions) {
+		zone!("Prefetch Marking");
+
+		self.prefetch.fill(false);
+		if self.prefetch_radius <= 0.0 {
+			return;
+		}
+
+		let margin = 2.0 * self.prefetch_radius / EARTH_RADIUS;
+		let vertical_angle = options.vertical_angle + margin;
+		let width = options.vertical_angle * options.width as f32 / options.height as f32 + margin;
+		let aspect_ratio = width / vertical_angle;
+		let center = project::destination(options.position, options.heading, self.prefetch_radius);
+
+		// Sample the view a few times per degree, so that no tile is skipped between samples.
+		let steps = ((width.max(vertical_angle).to_degrees() * 4.0).ceil() as usize + 1).clamp(2, 256);
+		for y in 0..steps {
+			for x in 0..steps {
+				let u = x as f32 / (steps - 1) as f32;
+				let v = y as f32 / (steps - 1) as f32;
+				let (lat, lon) = project::project_view(center, options.heading, aspect_ratio, vertical_angle, u, v);
+				let lat = lat.to_degrees().floor().clamp(-90.0, 89.0) as i16;
+				let lon = ((lon.to_degrees() + 180.0).rem_euclid(360.0) - 180.0)
+					.floor()
+					.clamp(-180.0, 179.0) as i16;
+				self.prefetch[map_lat_lon_to_index(lat, lon)] = true;
+			}
+		}
+	}
+
+	/// Replace the level of detail datasets. If the new datasets are invalid, the old ones are kept.
+	pub fn set_datasets(&mut self, datasets: Vec<PathBuf>) -> Result<(), LoadError> {
+		self.atlas.set_datasets(datasets)?;
+		self.tiles.fill(self.atlas.unloaded());
+		self.prefetch.fill(false);
+		Ok(())
+	}
+
 	pub fn clear(&mut self, radians_per_pixel: f32) {
 		for offset in self.tiles.iter_mut() {
 			*offset = self.atlas.unloaded();
@@ -198,63 +261,208 @@ impl TileCache {
 		self.atlas.clear(radians_per_pixel);
 	}
 
+	/// The tiles that failed to load from the current dataset, as latitude and longitude. They aren't retried until
+	/// `clear_failed` is called, or the level of detail changes.
+	pub fn failed_tiles(&self) -> Vec<(i16, i16)> {
+		self.tiles
+			.iter()
+			.enumerate()
+			.filter(|&(_, &x)| x == self.atlas.failed())
+			.map(|(i, _)| ((i / 360) as i16 - 90, (i % 360) as i16 - 180))
+			.collect()
+	}
+
+	/// Retry loading the tiles that failed to load, the next time they are used.
+	pub fn clear_failed(&mut self) {
+		let (failed, unloaded) = (self.atlas.failed(), self.atlas.unloaded());
+		for offset in self.tiles.iter_mut().filter(|x| **x == failed) {
+			*offset = unloaded;
+		}
+	}
+
 	pub fn tile_map(&self) -> &TextureView { &self.tile_map_view }
 
 	pub fn tile_status(&self) -> &Buffer { &self.tile_status }
 
 	pub fn atlas(&self) -> &TextureView { &self.atlas.view }
 
-	pub fn hillshade(&self) -> &TextureView { &self.atlas.hillshade_view }
+	/// The hillshade atlas, laid out like the height atlas, if the hillshade is used.
+	pub fn hillshade(&self) -> Option<&TextureView> { self.atlas.hillshade.as_ref().map(|x| &x.1) }
+
+	/// The coverage atlas, laid out like the height atlas, if any level of detail has a coverage layer.
+	pub fn coverage(&self) -> Option<&TextureView> { self.atlas.coverage.as_ref().map(|x| &x.1) }
+
+	/// The dataset that a frame with the given height and vertical angle would be rendered with.
+	pub fn dataset_for_frame(&self, height: u32, vertical_angle: f32) -> &Dataset {
+		let index = self
+			.atlas
+			.get_dataset_for_angle(radians_per_pixel(height as _, vertical_angle));
+		&self.atlas.datasets[index]
+	}
+
+	/// Always use the dataset at `index`, instead of choosing one based on the range. Out of range indices are ignored.
+	pub fn force_lod(&mut self, index: Option<usize>) {
+		self.atlas.forced_dataset = index.filter(|&x| x < self.atlas.datasets.len());
+	}
+
+	pub fn lod_count(&self) -> usize { self.atlas.datasets.len() }
+
+	/// What the last call to `populate_tiles` did.
+	pub fn frame_stats(&self) -> FrameStats { self.atlas.stats }
+
+	pub fn lod_info(&self) -> Vec<LodInfo> {
+		let loaded = self.tiles.iter().filter(|&&x| self.atlas.is_resident(x)).count();
+		self.atlas
+			.paths
+			.iter()
+			.zip(self.atlas.datasets.iter())
+			.zip(self.atlas.lod_densities.iter())
+			.enumerate()
+			.map(|(i, ((path, dataset), &density))| {
+				let active = i == self.atlas.curr_dataset;
+				LodInfo {
+					path: path.clone(),
+					resolution: dataset.metadata().resolution,
+					radians_per_pixel: density,
+					loaded_tiles: if active { loaded } else { 0 },
+					active,
+				}
+			})
+			.collect()
+	}
 
 	pub fn tile_size(&self) -> u32 { self.atlas.datasets[self.atlas.curr_dataset].metadata().resolution as _ }
 }
 
+/// A texture laid out like the height atlas, and its view.
+type Layer = (Texture, TextureView);
+
 struct Atlas {
+	paths: Vec<PathBuf>,
 	datasets: Vec<Dataset>,
 	lod_densities: Vec<f32>,
 	atlas: Texture,
 	view: TextureView,
-	hillshade: Texture,
-	hillshade_view: TextureView,
+	hillshade: Option<Layer>,
+	/// Allocated if any level of detail has a coverage layer.
+	coverage: Option<Layer>,
 	width: u32,
 	height: u32,
 	curr_dataset: usize,
 	curr_offset: TileOffset,
 	collected_tiles: Vec<TileOffset>,
+	forced_dataset: Option<usize>,
+	/// Takes precedence over `forced_dataset`, and is kept when the datasets change.
+	pinned_dataset: Option<usize>,
+	/// Reset at the start of every frame.
+	stats: FrameStats,
 }
 
-impl Atlas {
-	fn new(device: &Device, datasets: Vec<PathBuf>) -> Result<Self, LoadError> {
-		let datasets: Result<Vec<_>, LoadError> = datasets.into_iter().map(|dir| Dataset::load(&dir)).collect();
-		let datasets = datasets?;
+/// The atlas textures that a tile is uploaded to.
+#[derive(Copy, Clone)]
+enum Target {
+	Heights,
+	Hillshade,
+	Coverage,
+}
 
-		let lod_densities = datasets
-			.iter()
-			.map(|x| radians_per_pixel(x.metadata().resolution as _, 1.0f32.to_radians()))
-			.collect();
+/// The decoded data of a tile, ready to be uploaded.
+struct LoadedTile {
+	heights: Vec<u16>,
+	hillshade: Option<Vec<u8>>,
+	/// `None` if there is no coverage atlas, or if the level of detail has no coverage layer, in which case the tile
+	/// is fully covered.
+	coverage: Option<Vec<u8>>,
+}
+
+impl Atlas {
+	fn new(
+		device: &Device, datasets: Vec<PathBuf>, use_hillshade: bool, pinned_dataset: Option<usize>,
+	) -> Result<Self, LoadError> {
+		let paths = datasets;
+		let (datasets, lod_densities) = Self::load_lods(&paths)?;
 
 		let (width, height) = (4096, 4096);
 		let limits = device.limits();
 		let width = width.min(limits.max_texture_dimension_2d);
 		let height = height.min(limits.max_texture_dimension_2d);
-		let (atlas, view, hillshade, hillshade_view) = Self::make_atlas(device, width, height);
+
+		Self::validate_lods(&datasets, width.min(height), pinned_dataset)?;
+
+		let use_coverage = datasets.iter().any(|x| x.metadata().layers.contains(Layers::COVERAGE));
+		let (atlas, view, hillshade, coverage) = Self::make_atlas(device, width, height, use_hillshade, use_coverage);
 
 		Ok(Self {
 			curr_dataset: datasets.len(),
+			paths,
 			datasets,
 			lod_densities,
 			atlas,
 			view,
 			hillshade,
-			hillshade_view,
+			coverage,
 			width,
 			height,
 			curr_offset: TileOffset::default(),
 			collected_tiles: Vec::new(),
+			forced_dataset: None,
+			pinned_dataset,
+			stats: FrameStats::default(),
 		})
 	}
 
+	fn load_lods(paths: &[PathBuf]) -> Result<(Vec<Dataset>, Vec<f32>), LoadError> {
+		let datasets: Result<Vec<_>, LoadError> = paths
+			.iter()
+			.map(|dir| Dataset::load_with_advice(dir, Access::Random))
+			.collect();
+		let datasets = datasets?;
+
+		let lod_densities = datasets
+			.iter()
+			.map(|x| radians_per_pixel(x.metadata().resolution as _, 1.0f32.to_radians()))
+			.collect();
+
+		Ok((datasets, lod_densities))
+	}
+
+	/// Swap the datasets, keeping the atlas textures. The atlas is cleared on the next frame, since no level of detail
+	/// is current.
+	fn set_datasets(&mut self, paths: Vec<PathBuf>) -> Result<(), LoadError> {
+		let (datasets, lod_densities) = Self::load_lods(&paths)?;
+		Self::validate_lods(&datasets, self.width.min(self.height), self.pinned_dataset)?;
+
+		self.curr_dataset = datasets.len();
+		self.paths = paths;
+		self.datasets = datasets;
+		self.lod_densities = lod_densities;
+		self.forced_dataset = None;
+		self.curr_offset = TileOffset::default();
+		self.collected_tiles.clear();
+
+		Ok(())
+	}
+
+	/// Level of detail selection assumes that each dataset is coarser than the one before it, and uploads assume that
+	/// a tile of any level fits in the atlas.
+	fn validate_lods(datasets: &[Dataset], atlas_size: u32, pinned: Option<usize>) -> Result<(), LoadError> {
+		if datasets.is_empty() || pinned.map_or(false, |x| x >= datasets.len()) {
+			return Err(LoadError::InconsistentLod);
+		}
+
+		let resolutions: Vec<_> = datasets.iter().map(|x| x.metadata().resolution as u32).collect();
+		if resolutions.windows(2).any(|x| x[0] <= x[1]) || resolutions.iter().any(|&x| x >= atlas_size) {
+			return Err(LoadError::InconsistentLod);
+		}
+
+		Ok(())
+	}
+
 	fn get_dataset_for_angle(&self, radians_per_pixel: f32) -> usize {
+		if let Some(index) = self.pinned_dataset.or(self.forced_dataset) {
+			return index;
+		}
+
 		let mut index = 0;
 		for (i, &density) in self.lod_densities.iter().enumerate().rev() {
 			if radians_per_pixel >= density {
@@ -273,13 +481,94 @@ impl Atlas {
 	fn clear(&mut self, radians_per_pixel: f32) {
 		self.curr_offset = TileOffset::default();
 		self.collected_tiles.clear();
-		self.curr_dataset = self.get_dataset_for_angle(radians_per_pixel)
+		self.curr_dataset = self.get_dataset_for_angle(radians_per_pixel);
+		tracing::info!(
+			lod = self.curr_dataset,
+			resolution = self.datasets[self.curr_dataset].metadata().resolution,
+			"Switching level of detail"
+		);
+	}
+
+	fn return_tile(&mut self, tile: TileOffset) {
+		self.collected_tiles.push(tile);
+		self.stats.tiles_collected += 1;
 	}
 
-	fn return_tile(&mut self, tile: TileOffset) { self.collected_tiles.push(tile); }
+	/// Load prefetched tiles that aren't loaded yet into free space in the atlas. Returns if any were uploaded.
+	fn prefetch_tiles(&mut self, queue: &Queue, used: &[u32], prefetch: &[bool], tiles: &mut [TileOffset]) -> bool {
+		zone!("Tile Prefetch");
+
+		let mut loaded = 0;
+		for (index, offset) in tiles.iter_mut().enumerate() {
+			if loaded == PREFETCH_PER_FRAME {
+				break;
+			}
+			if !prefetch[index] || used[index] != 0 || *offset != self.unloaded() {
+				continue;
+			}
+
+			let lat = (index / 360) as i16 - 90;
+			let lon = (index % 360) as i16 - 180;
+			let tile = match self.load_tile(lat, lon) {
+				Ok(x) => x,
+				Err(x) => {
+					*offset = x;
+					continue;
+				},
+			};
+			match self.upload_tile(queue, &tile) {
+				Some(x) => *offset = x,
+				// The atlas is full, which is left for the tiles that are actually used to deal with.
+				None => break,
+			}
+			loaded += 1;
+		}
 
-	fn upload_tile(&mut self, queue: &Queue, tile: &[u16], hillshade: &[u8]) -> Option<TileOffset> {
-		tracy::zone!("Tile Upload");
+		loaded != 0
+	}
+
+	/// Decode a tile of the current dataset, and its hillshade and coverage if they are used. If the tile is missing or
+	/// fails to decode, returns the offset to mark it with instead.
+	fn load_tile(&mut self, lat: i16, lon: i16) -> Result<LoadedTile, TileOffset> {
+		zone!("Load Tile");
+
+		let start = Instant::now();
+		let ret = self.decode_tile(lat, lon);
+		self.stats.decode_time += start.elapsed();
+		ret
+	}
+
+	fn decode_tile(&self, lat: i16, lon: i16) -> Result<LoadedTile, TileOffset> {
+		let dataset = &self.datasets[self.curr_dataset];
+		let tile = if self.hillshade.is_some() {
+			dataset
+				.get_tile(lat, lon)
+				.map(|x| x.map(|(data, hillshade)| (data, Some(hillshade))))
+		} else {
+			dataset.get_heights(lat, lon).map(|x| x.map(|data| (data, None)))
+		};
+		let coverage = if self.coverage.is_some() {
+			dataset.get_coverage(lat, lon).transpose()
+		} else {
+			Ok(None)
+		};
+		match (tile, coverage) {
+			(Some(Ok((heights, hillshade))), Ok(coverage)) => Ok(LoadedTile {
+				heights,
+				hillshade,
+				coverage,
+			}),
+			(Some(Err(e)), _) | (Some(_), Err(e)) => {
+				// Retrying every frame would only fail again, so wait for `clear_failed`.
+				tracing::error!(lat, lon, error = ?e, "Error loading tile");
+				Err(self.failed())
+			},
+			(None, _) => Err(self.not_found()),
+		}
+	}
+
+	fn upload_tile(&mut self, queue: &Queue, tile: &LoadedTile) -> Option<TileOffset> {
+		zone!("Tile Upload");
 
 		let res = self.datasets[self.curr_dataset].metadata().resolution as u32;
 
@@ -294,44 +583,64 @@ impl Atlas {
 			}
 		};
 
-		queue.write_texture(
-			ImageCopyTexture {
-				texture: &self.atlas,
-				mip_level: 0,
-				origin: Origin3d {
-					x: ret.x as _,
-					y: ret.y as _,
-					z: 0,
+		let heights = unsafe { std::slice::from_raw_parts(tile.heights.as_ptr() as _, tile.heights.len() * 2) };
+		let mut uploads = vec![(heights, 2 * res, Target::Heights)];
+		if let (Some(_), Some(hillshade)) = (&self.hillshade, &tile.hillshade) {
+			uploads.push((hillshade.as_slice(), res, Target::Hillshade));
+		}
+		// Levels of detail without coverage are fully covered, which still has to overwrite what was there before.
+		let full;
+		if self.coverage.is_some() {
+			let coverage = match &tile.coverage {
+				Some(x) => x.as_slice(),
+				None => {
+					full = vec![255; (res * res) as usize];
+					full.as_slice()
 				},
-				aspect: TextureAspect::All,
-			},
-			unsafe { std::slice::from_raw_parts(tile.as_ptr() as _, tile.len() * 2) },
-			ImageDataLayout {
-				offset: 0,
-				bytes_per_row: Some(NonZeroU32::new(2 * res).unwrap()),
-				rows_per_image: Some(NonZeroU32::new(res).unwrap()),
-			},
-			Extent3d {
-				width: res,
-				height: res,
-				depth_or_array_layers: 1,
-			},
-		);
+			};
+			uploads.push((coverage, res, Target::Coverage));
+		}
+
+		for (data, row, target) in uploads {
+			self.write_layer(queue, target, ret, data, row);
+		}
+
+		self.stats.tiles_uploaded += 1;
+		self.curr_offset.x += res;
+		if self.curr_offset.x + res >= self.width {
+			self.curr_offset.x = 0;
+			self.curr_offset.y += res;
+		}
+
+		Some(ret)
+	}
+
+	fn texture(&self, target: Target) -> &Texture {
+		match target {
+			Target::Heights => &self.atlas,
+			Target::Hillshade => &self.hillshade.as_ref().unwrap().0,
+			Target::Coverage => &self.coverage.as_ref().unwrap().0,
+		}
+	}
+
+	fn write_layer(&self, queue: &Queue, target: Target, origin: TileOffset, data: &[u8], bytes_per_row: u32) {
+		let res = self.datasets[self.curr_dataset].metadata().resolution as u32;
+
 		queue.write_texture(
 			ImageCopyTexture {
-				texture: &self.hillshade,
+				texture: self.texture(target),
 				mip_level: 0,
 				origin: Origin3d {
-					x: ret.x as _,
-					y: ret.y as _,
+					x: origin.x as _,
+					y: origin.y as _,
 					z: 0,
 				},
 				aspect: TextureAspect::All,
 			},
-			unsafe { std::slice::from_raw_parts(hillshade.as_ptr() as _, hillshade.len()) },
+			data,
 			ImageDataLayout {
 				offset: 0,
-				bytes_per_row: Some(NonZeroU32::new(res).unwrap()),
+				bytes_per_row: Some(NonZeroU32::new(bytes_per_row).unwrap()),
 				rows_per_image: Some(NonZeroU32::new(res).unwrap()),
 			},
 			Extent3d {
@@ -340,18 +649,10 @@ impl Atlas {
 				depth_or_array_layers: 1,
 			},
 		);
-
-		self.curr_offset.x += res;
-		if self.curr_offset.x + res >= self.width {
-			self.curr_offset.x = 0;
-			self.curr_offset.y += res;
-		}
-
-		Some(ret)
 	}
 
 	fn collect_tiles(&mut self, used: &[u32], tiles: &mut [TileOffset], start: usize) -> bool {
-		tracy::zone!("Tile GC");
+		zone!("Tile GC");
 
 		let mut needed = 1;
 		let mut collected = 0;
@@ -359,7 +660,7 @@ impl Atlas {
 			if used == 1 && *offset == self.unloaded() {
 				needed += 1;
 			} else {
-				if *offset != self.unloaded() && *offset != self.not_found() {
+				if self.is_resident(*offset) {
 					self.collected_tiles.push(*offset);
 					*offset = self.unloaded();
 					collected += 1;
@@ -367,31 +668,42 @@ impl Atlas {
 			}
 		}
 
+		self.stats.tiles_collected += collected;
+		tracing::debug!(collected, needed, "Collected unused tiles");
 		collected >= needed
 	}
 
 	fn recreate_atlas(&mut self, device: &Device) -> bool {
 		let limits = device.limits();
 		if self.width == limits.max_texture_dimension_2d && self.height == limits.max_texture_dimension_2d {
-			log::error!("Atlas is too large to fit in device limits");
+			tracing::error!(
+				width = self.width,
+				height = self.height,
+				"Atlas is too large to fit in device limits"
+			);
 			return false;
 		}
 
 		let width = (self.width * 2).min(limits.max_texture_dimension_2d);
 		let height = (self.height * 2).min(limits.max_texture_dimension_2d);
-		let (atlas, view, hillshade, hillshade_view) = Self::make_atlas(device, width, height);
+		tracing::info!(width, height, "Growing the atlas");
+		let (atlas, view, hillshade, coverage) =
+			Self::make_atlas(device, width, height, self.hillshade.is_some(), self.coverage.is_some());
 
 		self.atlas = atlas;
 		self.view = view;
 		self.hillshade = hillshade;
-		self.hillshade_view = hillshade_view;
+		self.coverage = coverage;
 		self.width = width;
 		self.height = height;
+		self.stats.atlas_recreated = true;
 
 		true
 	}
 
-	fn make_atlas(device: &Device, width: u32, height: u32) -> (Texture, TextureView, Texture, TextureView) {
+	fn make_atlas(
+		device: &Device, width: u32, height: u32, use_hillshade: bool, use_coverage: bool,
+	) -> (Texture, TextureView, Option<Layer>, Option<Layer>) {
 		let descriptor = TextureDescriptor {
 			label: Some("Heightmap Atlas"),
 			size: Extent3d {
@@ -412,20 +724,49 @@ impl Atlas {
 			..Default::default()
 		});
 
-		let hillshade = device.create_texture(&TextureDescriptor {
-			label: Some("Hillshade"),
-			format: TextureFormat::R8Unorm,
-			..descriptor
+		let hillshade = use_hillshade.then(|| {
+			let hillshade = device.create_texture(&TextureDescriptor {
+				label: Some("Hillshade"),
+				format: TextureFormat::R8Unorm,
+				..descriptor
+			});
+			let hillshade_view = hillshade.create_view(&TextureViewDescriptor {
+				label: Some("Hillshade View"),
+				..Default::default()
+			});
+			(hillshade, hillshade_view)
 		});
-		let hillshade_view = hillshade.create_view(&TextureViewDescriptor {
-			label: Some("Hillshade View"),
-			..Default::default()
+
+		let coverage = use_coverage.then(|| {
+			let coverage = device.create_texture(&TextureDescriptor {
+				label: Some("Coverage"),
+				format: TextureFormat::R8Unorm,
+				..descriptor
+			});
+			let coverage_view = coverage.create_view(&TextureViewDescriptor {
+				label: Some("Coverage View"),
+				..Default::default()
+			});
+			(coverage, coverage_view)
 		});
 
-		(atlas, view, hillshade, hillshade_view)
+		(atlas, view, hillshade, coverage)
 	}
 
 	fn unloaded(&self) -> TileOffset { TileOffset { x: 0, y: self.height } }
 
 	fn not_found(&self) -> TileOffset { TileOffset { x: self.width, y: 0 } }
+
+	/// The shader checks for `not_found` first, so tiles that failed to load are drawn as missing.
+	fn failed(&self) -> TileOffset {
+		TileOffset {
+			x: self.width,
+			y: self.height,
+		}
+	}
+
+	/// Whether the tile occupies space in the atlas.
+	fn is_resident(&self, offset: TileOffset) -> bool {
+		offset != self.unloaded() && offset != self.not_found() && offset != self.failed()
+	}
 }